@@ -8,7 +8,7 @@ extern crate trackable;
 use clap::{App, Arg};
 use fibers::{Executor, InPlaceExecutor, Spawn};
 use fibers_inotify::{Error, InotifyService, WatchMask};
-use futures::{Future, Stream};
+use futures::Stream;
 
 fn main() {
     let matches = App::new("watch")
@@ -18,11 +18,8 @@ fn main() {
     let mask = WatchMask::CREATE | WatchMask::MODIFY | WatchMask::DELETE_SELF | WatchMask::DELETE
         | WatchMask::MOVE | WatchMask::MOVE_SELF;
 
-    let inotify_service = InotifyService::new();
-    let inotify_handle = inotify_service.handle();
-
     let mut executor = InPlaceExecutor::new().unwrap();
-    executor.spawn(inotify_service.map_err(|e| panic!("{}", e)));
+    let inotify_handle = InotifyService::new().start(&executor.handle());
 
     let fiber = executor.spawn_monitor(inotify_handle.watch(path, mask).for_each(|event| {
         println!("{:?}", event);
@@ -0,0 +1,121 @@
+use fibers::executor::InPlaceExecutorHandle;
+use fibers::{Executor, InPlaceExecutor, Spawn};
+use futures::Future;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use {Error, InotifyService, InotifyServiceHandle, Result};
+
+/// An `InotifyService` running on a dedicated thread.
+///
+/// This is useful for applications which do not manage `fibers` executors by themselves.
+/// The thread runs an executor which is dedicated to the service,
+/// and terminates when the service terminates (e.g., `InotifyServiceHandle::shutdown` is called).
+///
+/// Because `Watcher`s must be polled in a fiber, they should be consumed on the executor
+/// (see `BackgroundService::spawner`), e.g., by `Watcher::into_std_receiver`.
+///
+/// When this is dropped, the service is shut down and the thread is joined.
+///
+/// This is created by calling `InotifyService::spawn_background` method.
+#[derive(Debug)]
+pub struct BackgroundService {
+    handle: InotifyServiceHandle,
+    spawner: InPlaceExecutorHandle,
+    thread: Option<JoinHandle<()>>,
+}
+impl BackgroundService {
+    pub(crate) fn spawn(service: InotifyService) -> Result<Self> {
+        let handle = service.handle();
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("fibers_inotify".to_owned())
+            .spawn(move || {
+                let mut executor = match InPlaceExecutor::new() {
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+                    Ok(executor) => executor,
+                };
+                let _ = tx.send(Ok(executor.handle()));
+                let monitor = executor.spawn_monitor(service.then(|_| Ok::<_, ()>(())));
+                let _ = executor.run_fiber(monitor); // The error is kept by the service
+            });
+        let thread = track!(thread.map_err(Error::from))?;
+        let spawner = track!(rx.recv().map_err(Error::from))?;
+        let spawner = track!(spawner.map_err(Error::from))?;
+        Ok(BackgroundService {
+            handle,
+            spawner,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the handle of the service.
+    pub fn handle(&self) -> &InotifyServiceHandle {
+        &self.handle
+    }
+
+    /// Returns the spawner of the executor running the service.
+    ///
+    /// The fibers spawned by this run on the thread of the service.
+    pub fn spawner(&self) -> &InPlaceExecutorHandle {
+        &self.spawner
+    }
+
+    /// Shuts down the service, and waits until the thread terminates.
+    ///
+    /// If the service has terminated due to an error, the error is returned.
+    pub fn join(mut self) -> Result<()> {
+        self.shutdown_and_join();
+        match self.handle.error() {
+            None => Ok(()),
+            Some(e) => Err(track!(e)),
+        }
+    }
+
+    fn shutdown_and_join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.handle.shutdown();
+            let _ = thread.join();
+        }
+    }
+}
+impl Drop for BackgroundService {
+    fn drop(&mut self) {
+        self.shutdown_and_join();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use test_util::TempDir;
+    use {EventMask, InotifyService, WatchMask, WatcherEvent};
+
+    #[test]
+    fn spawn_background_works() {
+        let dir = TempDir::new();
+        let service = InotifyService::new().spawn_background().unwrap();
+
+        let watcher = service.handle().watch(dir.path(), WatchMask::CREATE);
+        let rx = watcher.into_std_receiver(service.spawner());
+        let timeout = Duration::from_secs(5);
+        let event = rx.recv_timeout(timeout).unwrap().unwrap();
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        match rx.recv_timeout(timeout).unwrap().unwrap() {
+            WatcherEvent::Notified(e) => assert_eq!(e.mask, EventMask::CREATE),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+
+        let handle = service.handle().clone();
+        service.join().unwrap();
+        assert!(!handle.is_alive());
+        assert!(rx.recv_timeout(timeout).is_err());
+    }
+}
@@ -0,0 +1,41 @@
+use fibers::time::timer::{self, Timeout};
+use futures::{Future, Poll};
+use std::time::Duration;
+
+use Error;
+
+/// Source of the timers used by the timer-based combinators (e.g., `WatcherExt::idle_timeout`).
+///
+/// The default implementation is `FibersClock`.
+/// A custom implementation can be injected via the `*_with_clock` methods of `WatcherExt`,
+/// e.g., to advance time manually in tests.
+pub trait Clock {
+    /// The future which completes when the duration passed to `Clock::timer` has elapsed.
+    type Timer: Future<Item = (), Error = Error>;
+
+    /// Makes a timer which expires after `duration`.
+    fn timer(&self, duration: Duration) -> Self::Timer;
+}
+
+/// The clock based on the timers of `fibers` crate.
+///
+/// Note that the timers work only if they are polled in a fiber.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FibersClock;
+impl Clock for FibersClock {
+    type Timer = FibersTimer;
+    fn timer(&self, duration: Duration) -> Self::Timer {
+        FibersTimer(timer::timeout(duration))
+    }
+}
+
+/// A timer made by `FibersClock`.
+#[derive(Debug)]
+pub struct FibersTimer(Timeout);
+impl Future for FibersTimer {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        track!(self.0.poll().map_err(Error::from))
+    }
+}
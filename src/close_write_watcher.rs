@@ -0,0 +1,103 @@
+use futures::{Async, Poll, Stream};
+use std::ffi::OsString;
+
+use {Error, ErrorKind, EventMask, Watcher, WatcherEvent};
+
+/// A watcher which notifies the completion of writes to a file.
+///
+/// This is a `Stream` that produces an `EventMask::CLOSE_WRITE` event each time the file is
+/// closed after being opened for writing, in addition to the control events
+/// (e.g., `WatcherEvent::StartWatching`) of the underlying `Watcher`.
+///
+/// Because the parent directory of the file is watched instead of the file itself,
+/// the watcher keeps working even if the file is removed and re-created
+/// (or does not exist yet).
+/// The stream terminates when the parent directory is removed.
+///
+/// This is created by calling `InotifyServiceHandle::watch_on_close` method.
+#[derive(Debug)]
+pub struct CloseWriteWatcher {
+    watcher: Watcher,
+    file_name: Option<OsString>,
+}
+impl CloseWriteWatcher {
+    pub(crate) fn new(watcher: Watcher, file_name: Option<OsString>) -> Self {
+        CloseWriteWatcher { watcher, file_name }
+    }
+}
+impl Stream for CloseWriteWatcher {
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let file_name = track_assert_some!(
+            self.file_name.as_ref(),
+            ErrorKind::InvalidInput,
+            "The path has no file name: {:?}",
+            self.watcher.path()
+        );
+        loop {
+            let polled = track!(self.watcher.poll())?;
+            if let Async::Ready(Some(WatcherEvent::Notified(ref e))) = polled {
+                let is_target = e.name.as_ref().map(|n| n.as_os_str()) == Some(file_name);
+                if !(is_target || e.mask.contains(EventMask::IGNORED)) {
+                    continue;
+                }
+            }
+            return Ok(polled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Stream;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use test_util::{TempDir, TestExecutor};
+    use {EventMask, WatcherEvent};
+
+    #[test]
+    fn watch_on_close_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor.handle.watch_on_close(dir.join("foo"));
+        let (event, watcher) = executor.wait(watcher.into_future()).ok().unwrap();
+        assert_eq!(event, Some(WatcherEvent::StartWatching));
+
+        fs::write(dir.join("bar"), b"bar").unwrap();
+        fs::write(dir.join("foo"), b"foo").unwrap();
+        let (event, watcher) = executor.wait(watcher.into_future()).ok().unwrap();
+        match event {
+            Some(WatcherEvent::Notified(e)) => {
+                assert_eq!(e.mask, EventMask::CLOSE_WRITE);
+                assert_eq!(e.name, Some(PathBuf::from("foo")));
+            }
+            _ => panic!("Unexpected event: {:?}", event),
+        }
+
+        // The kernel merges identical events which are queued successively,
+        // so the next write is made after the previous event is read.
+        fs::write(dir.join("foo"), b"foofoo").unwrap();
+        let (event, watcher) = executor.wait(watcher.into_future()).ok().unwrap();
+        assert!(event.is_some_and(|e| is_close_write(&e)));
+
+        fs::remove_file(dir.join("foo")).unwrap();
+        fs::remove_file(dir.join("bar")).unwrap();
+        fs::remove_dir(dir.path()).unwrap();
+        let events = executor.wait(watcher.collect()).unwrap();
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            WatcherEvent::Notified(ref e) => assert!(e.mask.contains(EventMask::IGNORED)),
+            _ => panic!("Unexpected event: {:?}", events[0]),
+        }
+    }
+
+    fn is_close_write(event: &WatcherEvent) -> bool {
+        match *event {
+            WatcherEvent::Notified(ref e) => e.mask.contains(EventMask::CLOSE_WRITE),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use futures03::compat::Compat01As03;
+
+use Watcher;
+
+impl Watcher {
+    /// Converts this watcher into a `futures` 0.3 stream (via the `futures::compat` bridge).
+    ///
+    /// The resulting stream produces `Result<WatcherEvent, Error>` items,
+    /// and can be consumed by `async`/`await` code (e.g., `stream.next().await`)
+    /// running on any executor.
+    ///
+    /// Note that the service itself still needs to run on fibers.
+    ///
+    /// This method is available only if the `compat` feature is enabled.
+    pub fn compat(self) -> Compat01As03<Watcher> {
+        Compat01As03::new(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+    use futures::Future;
+    use futures03::executor::block_on;
+    use futures03::StreamExt;
+    use std::fs;
+    use std::thread;
+
+    use test_util::TempDir;
+    use {EventMask, InotifyService, WatchMask, WatcherEvent};
+
+    #[test]
+    fn compat_works() {
+        let dir = TempDir::new();
+        let service = InotifyService::new();
+        let handle = service.handle();
+        thread::spawn(move || {
+            // Runs until the test process exits
+            let executor = InPlaceExecutor::new().unwrap();
+            executor.spawn(service.map_err(|e| panic!("{}", e)));
+            executor.run().unwrap();
+        });
+
+        let mut stream = handle.watch(dir.path(), WatchMask::CREATE).compat();
+        let event = block_on(stream.next()).unwrap().unwrap();
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        match block_on(stream.next()).unwrap().unwrap() {
+            WatcherEvent::Notified(e) => assert_eq!(e.mask, EventMask::CREATE),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+}
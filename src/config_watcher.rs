@@ -0,0 +1,198 @@
+use futures::{Async, Future, Poll, Stream};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use {Clock, Error, EventMask, FibersClock, Watcher, WatcherEvent};
+
+/// A watcher which produces the contents of a file each time the file is changed.
+///
+/// This is a `Stream` that produces the contents of the file once the watch has started,
+/// and then each time the file is closed after being written or is replaced by a rename
+/// (e.g., an atomic save by an editor).
+/// The changes are debounced, thus a burst of writes results in a single delivery
+/// after no changes have been notified for the debounce duration.
+///
+/// Because the parent directory of the file is watched instead of the file itself,
+/// the watcher keeps working even if the file is replaced, removed or re-created.
+/// If the file does not exist when it is about to be read, nothing is produced.
+/// The stream terminates when the parent directory is removed.
+///
+/// Note that the timer of `FibersClock` works only if this is polled in a fiber.
+///
+/// This is created by calling `InotifyServiceHandle::watch_config` method
+/// (or `InotifyServiceHandle::watch_config_with_clock`).
+#[derive(Debug)]
+pub struct ConfigWatcher<C: Clock = FibersClock> {
+    watcher: Watcher,
+    path: PathBuf,
+    debounce: Duration,
+    clock: C,
+    timer: Option<C::Timer>,
+}
+impl<C: Clock> ConfigWatcher<C> {
+    pub(crate) fn new(watcher: Watcher, path: PathBuf, debounce: Duration, clock: C) -> Self {
+        ConfigWatcher {
+            watcher,
+            path,
+            debounce,
+            clock,
+            timer: None,
+        }
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<Option<Vec<u8>>, Error> {
+        match fs::read(&self.path) {
+            Ok(content) => Ok(Some(content)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(track!(Error::from(e), "path={:?}", self.path)),
+        }
+    }
+}
+impl<C: Clock> Stream for ConfigWatcher<C> {
+    type Item = Vec<u8>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match track!(self.watcher.poll())? {
+                Async::NotReady => break,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::Ready(Some(WatcherEvent::StartWatching))
+                | Async::Ready(Some(WatcherEvent::RestartWatching)) => {
+                    // The initial contents (or the ones possibly missed while restarting)
+                    if let Some(content) = track!(self.read())? {
+                        return Ok(Async::Ready(Some(content)));
+                    }
+                }
+                Async::Ready(Some(WatcherEvent::Notified(ref e)))
+                    if e.mask
+                        .intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO) =>
+                {
+                    self.timer = Some(self.clock.timer(self.debounce));
+                }
+                Async::Ready(Some(_)) => {}
+            }
+        }
+
+        let is_expired = if let Some(ref mut timer) = self.timer {
+            track!(timer.poll())?.is_ready()
+        } else {
+            false
+        };
+        if is_expired {
+            self.timer = None;
+            if let Some(content) = track!(self.read())? {
+                return Ok(Async::Ready(Some(content)));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Stream;
+    use std::fs;
+
+    use super::*;
+    use event_channel;
+    use test_util::{event, FakeClock, TempDir, TestExecutor};
+    use {OverflowPolicy, WatchMask, WatchOptions};
+
+    #[test]
+    fn config_watcher_works() {
+        let dir = TempDir::new();
+        let path = dir.join("app.conf");
+        fs::write(&path, b"a").unwrap();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor
+            .handle
+            .watch_config(&path, Duration::from_millis(10));
+        let (content, watcher) = next_content(&mut executor, watcher);
+        assert_eq!(content, b"a");
+
+        fs::write(&path, b"b").unwrap();
+        let (content, watcher) = next_content(&mut executor, watcher);
+        assert_eq!(content, b"b");
+
+        // Atomic replacement
+        fs::write(dir.join("app.conf.tmp"), b"c").unwrap();
+        fs::rename(dir.join("app.conf.tmp"), &path).unwrap();
+        let (content, _) = next_content(&mut executor, watcher);
+        assert_eq!(content, b"c");
+    }
+
+    #[test]
+    fn debounce_works() {
+        let dir = TempDir::new();
+        let path = dir.join("app.conf");
+        fs::write(&path, b"a").unwrap();
+        let executor = TestExecutor::new();
+
+        // The events are fed manually
+        let (event_tx, event_rx) = event_channel::channel(None, OverflowPolicy::default());
+        let watcher = Watcher::new(
+            0,
+            executor.handle.clone(),
+            dir.path().to_path_buf(),
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO,
+            WatchOptions::default(),
+            event_rx,
+        );
+        let clock = FakeClock::new();
+        let debounce = Duration::from_secs(1);
+        let mut watcher = ConfigWatcher::new(watcher, path.clone(), debounce, clock.clone());
+        let notify = |mask| {
+            let e = event(mask, Some("app.conf"), dir.path().to_str().unwrap());
+            event_tx.send(Ok(WatcherEvent::Notified(e))).unwrap();
+        };
+
+        event_tx.send(Ok(WatcherEvent::StartWatching)).unwrap();
+        assert_eq!(watcher.poll().unwrap(), Async::Ready(Some(b"a".to_vec())));
+
+        // A burst of writes is delivered once
+        fs::write(&path, b"b").unwrap();
+        notify(EventMask::CLOSE_WRITE);
+        assert_eq!(watcher.poll().unwrap(), Async::NotReady);
+        clock.advance(Duration::from_millis(500));
+        fs::write(&path, b"c").unwrap();
+        notify(EventMask::CLOSE_WRITE);
+        assert_eq!(watcher.poll().unwrap(), Async::NotReady);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(watcher.poll().unwrap(), Async::NotReady);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(watcher.poll().unwrap(), Async::Ready(Some(b"c".to_vec())));
+        assert_eq!(watcher.poll().unwrap(), Async::NotReady);
+
+        // Atomic replacement
+        fs::write(&path, b"d").unwrap();
+        notify(EventMask::MOVED_TO);
+        assert_eq!(watcher.poll().unwrap(), Async::NotReady);
+        clock.advance(debounce);
+        assert_eq!(watcher.poll().unwrap(), Async::Ready(Some(b"d".to_vec())));
+
+        // Nothing is produced if the file has gone
+        notify(EventMask::CLOSE_WRITE);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(watcher.poll().unwrap(), Async::NotReady);
+        clock.advance(debounce);
+        assert_eq!(watcher.poll().unwrap(), Async::NotReady);
+
+        watcher.watcher.detach();
+    }
+
+    fn next_content(
+        executor: &mut TestExecutor,
+        watcher: ConfigWatcher,
+    ) -> (Vec<u8>, ConfigWatcher) {
+        let (content, watcher) = executor.wait(watcher.into_future()).ok().unwrap();
+        (content.expect("Unexpected end of stream"), watcher)
+    }
+}
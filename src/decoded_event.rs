@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+
+use {EventMask, EventType, InodeId, InotifyEvent};
+
+/// The names of the event bits in the order of `inotify(7)`.
+const EVENT_NAMES: &[(EventMask, &str)] = &[
+    (EventMask::ACCESS, "ACCESS"),
+    (EventMask::ATTRIB, "ATTRIB"),
+    (EventMask::CLOSE_WRITE, "CLOSE_WRITE"),
+    (EventMask::CLOSE_NOWRITE, "CLOSE_NOWRITE"),
+    (EventMask::CREATE, "CREATE"),
+    (EventMask::DELETE, "DELETE"),
+    (EventMask::DELETE_SELF, "DELETE_SELF"),
+    (EventMask::MODIFY, "MODIFY"),
+    (EventMask::MOVE_SELF, "MOVE_SELF"),
+    (EventMask::MOVED_FROM, "MOVED_FROM"),
+    (EventMask::MOVED_TO, "MOVED_TO"),
+    (EventMask::OPEN, "OPEN"),
+    (EventMask::IGNORED, "IGNORED"),
+    (EventMask::Q_OVERFLOW, "Q_OVERFLOW"),
+    (EventMask::UNMOUNT, "UNMOUNT"),
+];
+
+/// A decoded representation of an `InotifyEvent`.
+///
+/// This is intended for tools which dump events in a human readable structured form
+/// (e.g., JSON lines), so that each of them does not need to decode the mask by itself.
+///
+/// This is created by calling `InotifyEvent::decode` or `WatcherEvent::decode` method.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedEvent {
+    /// The names of the event bits contained in the mask (e.g., `["CREATE"]`).
+    ///
+    /// The names are the ones of the `EventMask` constants.
+    /// `ISDIR` is not included (see `is_dir` field).
+    pub kinds: Vec<&'static str>,
+
+    /// The semantic classification of the event.
+    pub event_type: EventType,
+
+    /// The full path of the file/directory that the event refers to.
+    pub path: PathBuf,
+
+    /// Whether the subject of the event is a directory.
+    pub is_dir: bool,
+
+    /// The cookie of the event (non-zero only for rename events).
+    pub cookie: u32,
+
+    /// The identity of the file/directory (see `WatchOptions::stat_inode`).
+    pub inode: Option<InodeId>,
+}
+impl DecodedEvent {
+    pub(crate) fn new(event: &InotifyEvent) -> Self {
+        DecodedEvent {
+            kinds: EVENT_NAMES
+                .iter()
+                .filter(|&&(mask, _)| event.mask.contains(mask))
+                .map(|&(_, name)| name)
+                .collect(),
+            event_type: event.event_type(),
+            path: event.path(),
+            is_dir: event.is_dir(),
+            cookie: event.cookie,
+            inode: event.inode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use test_util::event;
+    use {EventType, WatcherEvent};
+
+    use super::*;
+
+    #[test]
+    fn decode_works() {
+        let mut e = event(EventMask::MOVED_TO | EventMask::ISDIR, Some("foo"), "/tmp");
+        e.cookie = 10;
+        let decoded = WatcherEvent::Notified(e).decode().unwrap();
+        assert_eq!(decoded.kinds, ["MOVED_TO"]);
+        assert_eq!(decoded.event_type, EventType::MovedTo { is_dir: true });
+        assert_eq!(decoded.path, PathBuf::from("/tmp/foo"));
+        assert!(decoded.is_dir);
+        assert_eq!(decoded.cookie, 10);
+
+        let e = event(EventMask::DELETE_SELF | EventMask::IGNORED, None, "/tmp");
+        assert_eq!(e.decode().kinds, ["DELETE_SELF", "IGNORED"]);
+
+        assert_eq!(WatcherEvent::StartWatching.decode(), None);
+    }
+}
@@ -0,0 +1,126 @@
+use futures::{Async, Poll, Stream};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use {Error, EventMask, Result, Watcher, WatcherEvent};
+
+/// A watcher which terminates when the watched directory becomes empty.
+///
+/// This is a `Stream` that produces the same events as the underlying `Watcher`,
+/// and additionally produces `WatcherEvent::DirectoryEmptied` as the final event
+/// when the directory has no entries.
+///
+/// The emptiness of the directory is checked when the watcher (re)starts watching and
+/// each time when an entry is removed from the directory (i.e., `DELETE` or `MOVED_FROM`).
+/// If the directory itself is removed, this stream terminates without producing
+/// `WatcherEvent::DirectoryEmptied`.
+///
+/// This is created by calling `InotifyServiceHandle::watch_until_empty` method.
+#[derive(Debug)]
+pub struct EmptyDirWatcher {
+    watcher: Watcher,
+    path: PathBuf,
+    is_empty: bool,
+    eos: bool,
+}
+impl EmptyDirWatcher {
+    pub(crate) fn new(watcher: Watcher, path: PathBuf) -> Self {
+        EmptyDirWatcher {
+            watcher,
+            path,
+            is_empty: false,
+            eos: false,
+        }
+    }
+}
+impl Stream for EmptyDirWatcher {
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.eos {
+            return Ok(Async::Ready(None));
+        }
+        if self.is_empty {
+            self.eos = true;
+            return Ok(Async::Ready(Some(WatcherEvent::DirectoryEmptied)));
+        }
+
+        let polled = track!(self.watcher.poll())?;
+        if let Async::Ready(Some(ref event)) = polled {
+            let needs_check = match *event {
+                WatcherEvent::StartWatching | WatcherEvent::RestartWatching => true,
+                WatcherEvent::Notified(ref e) => {
+                    e.mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM)
+                }
+                _ => false,
+            };
+            if needs_check {
+                self.is_empty = track!(is_empty_dir(&self.path))?;
+            }
+        }
+        Ok(polled)
+    }
+}
+
+fn is_empty_dir(path: &Path) -> Result<bool> {
+    match fs::read_dir(path) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            // The directory has been removed (`IGNORED` event will terminate the stream)
+            Ok(false)
+        }
+        Err(e) => Err(track!(Error::from(e))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Stream;
+    use std::fs;
+
+    use test_util::{TempDir, TestExecutor};
+    use {EventMask, WatchMask, WatcherEvent};
+
+    #[test]
+    fn watch_until_empty_works() {
+        let dir = TempDir::new();
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::write(dir.join("bar"), b"").unwrap();
+
+        let mut executor = TestExecutor::new();
+        let watcher = executor
+            .handle
+            .watch_until_empty(dir.path(), WatchMask::empty());
+        let (event, watcher) = executor
+            .wait(watcher.into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(event, Some(WatcherEvent::StartWatching));
+
+        fs::remove_file(dir.join("foo")).unwrap();
+        fs::remove_file(dir.join("bar")).unwrap();
+        // The directory may become empty before the second `DELETE` event is read
+        let mut events = executor.wait(watcher.collect()).unwrap();
+        assert_eq!(events.pop(), Some(WatcherEvent::DirectoryEmptied));
+        assert!(!events.is_empty());
+        assert!(events.iter().all(|e| match *e {
+            WatcherEvent::Notified(ref e) => e.mask == EventMask::DELETE,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn already_empty_directory() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+        let watcher = executor
+            .handle
+            .watch_until_empty(dir.path(), WatchMask::empty());
+        let events = executor.wait(watcher.collect()).unwrap();
+        assert_eq!(
+            events,
+            [WatcherEvent::StartWatching, WatcherEvent::DirectoryEmptied]
+        );
+    }
+}
@@ -9,11 +9,6 @@ use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt};
 /// This crate specific `Error` type.
 #[derive(Debug, Clone, trackable::TrackableError)]
 pub struct Error(TrackableError<ErrorKind>);
-impl Error {
-    pub(crate) fn last_os_error() -> Self {
-        Error::from(io::Error::last_os_error())
-    }
-}
 impl From<ffi::NulError> for Error {
     fn from(f: ffi::NulError) -> Self {
         ErrorKind::InvalidInput.cause(f).into()
@@ -40,10 +35,21 @@ impl From<io::Error> for Error {
                 | libc::EFAULT
                 | libc::ENAMETOOLONG
                 | libc::ENOENT => ErrorKind::InvalidInput,
-                libc::EMFILE | libc::ENOMEM | libc::ENOSPC => ErrorKind::ResourceShortage,
+                libc::EMFILE | libc::ENOSPC => ErrorKind::WatchLimitExceeded,
+                libc::ENOMEM => ErrorKind::ResourceShortage,
+                libc::EEXIST => ErrorKind::AlreadyExists,
                 _ => ErrorKind::Other,
             });
-        kind.cause(f).into()
+        let e = Error::from(kind.cause(f));
+        if kind == ErrorKind::WatchLimitExceeded {
+            track!(
+                e,
+                "The limits can be raised via `/proc/sys/fs/inotify/max_user_watches` \
+                 and `/proc/sys/fs/inotify/max_user_instances`"
+            )
+        } else {
+            e
+        }
     }
 }
 impl<E: Into<Error>> From<MonitorError<E>> for Error {
@@ -66,10 +72,47 @@ pub enum ErrorKind {
 
     /// System resource shortage.
     ///
-    /// E.g., EMFILE, ENOMEM, ENOSPC
+    /// E.g., ENOMEM
     ResourceShortage,
 
+    /// The limit on the number of inotify watches or instances has been reached.
+    ///
+    /// E.g., ENOSPC (the limit of watches), EMFILE (the limit of instances or file descriptors)
+    ///
+    /// The limits can be raised via `/proc/sys/fs/inotify/max_user_watches` and
+    /// `/proc/sys/fs/inotify/max_user_instances` respectively.
+    WatchLimitExceeded,
+
+    /// The watch to be created already exists.
+    ///
+    /// E.g., EEXIST (see `WatchOptions::mask_create`)
+    AlreadyExists,
+
+    /// The service is too busy to accept the request.
+    ///
+    /// E.g., too many requests are pending in the service.
+    Busy,
+
     /// Other error.
     Other,
 }
 impl TrackableErrorKind for ErrorKind {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_io_error_works() {
+        let kind = |errno| *Error::from(io::Error::from_raw_os_error(errno)).kind();
+        assert_eq!(kind(libc::ENOENT), ErrorKind::InvalidInput);
+        assert_eq!(kind(libc::ENOSPC), ErrorKind::WatchLimitExceeded);
+        assert_eq!(kind(libc::EMFILE), ErrorKind::WatchLimitExceeded);
+        assert_eq!(kind(libc::ENOMEM), ErrorKind::ResourceShortage);
+        assert_eq!(kind(libc::EEXIST), ErrorKind::AlreadyExists);
+        assert_eq!(kind(libc::EIO), ErrorKind::Other);
+
+        let e = Error::from(io::Error::from_raw_os_error(libc::ENOSPC));
+        assert!(e.to_string().contains("max_user_watches"));
+    }
+}
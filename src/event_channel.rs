@@ -0,0 +1,345 @@
+use fibers::fiber::{self, Unpark};
+use futures::task::{self, Task};
+use futures::{Async, Poll, Stream};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use {OverflowPolicy, Result, WatcherEvent};
+
+/// Makes a channel used to deliver events from the service to a watcher.
+///
+/// If `capacity` is `Some(_)`, the notified events exceeding the capacity are handled
+/// according to `policy`.
+pub fn channel(capacity: Option<usize>, policy: OverflowPolicy) -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            sender_count: 1,
+            is_receiver_dropped: false,
+            sender_unpark: None,
+            receiver_unpark: None,
+        }),
+        capacity,
+        policy,
+    });
+    let tx = EventSender {
+        shared: Arc::clone(&shared),
+    };
+    let rx = EventReceiver { shared };
+    (tx, rx)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// Inotify events have been dropped due to the overflow.
+    ///
+    /// The value is the number of the dropped events (a batch counts as its all events).
+    Full(u64),
+    Disconnected,
+}
+
+#[derive(Debug)]
+pub struct EventSender {
+    shared: Arc<Shared>,
+}
+impl EventSender {
+    /// Sends `item` regardless of the capacity.
+    ///
+    /// Control events (e.g., `WatcherEvent::StartWatching`) and errors are sent by this method,
+    /// because dropping them would confuse the watcher.
+    pub fn send(&self, item: Result<WatcherEvent>) -> ::std::result::Result<(), SendError> {
+        let mut state = self.shared.lock();
+        if state.is_receiver_dropped {
+            return Err(SendError::Disconnected);
+        }
+        state.queue.push_back((item, Instant::now()));
+        state.receiver_unpark = None;
+        Ok(())
+    }
+
+    /// Sends `event` if the number of the queued items is less than the capacity.
+    ///
+    /// If the channel is full, `event` is handled according to the overflow policy:
+    /// - `DropNewest`: `event` is rejected
+    /// - `DropOldest`: the oldest queued inotify event is dropped to make room for `event`
+    /// - `Block`: `event` is accepted (the service stops reading events by `poll_ready` instead)
+    ///
+    /// `SendError::Full` is returned if an event (or a batch) has been dropped.
+    pub fn try_send(&self, event: WatcherEvent) -> ::std::result::Result<(), SendError> {
+        let mut state = self.shared.lock();
+        if state.is_receiver_dropped {
+            return Err(SendError::Disconnected);
+        }
+        let mut result = Ok(());
+        if self.shared.is_full(&state) {
+            match self.shared.policy {
+                OverflowPolicy::DropNewest => return Err(SendError::Full(event_count(&event))),
+                OverflowPolicy::DropOldest => {
+                    // Control events and errors are never dropped
+                    let oldest = state.queue.iter().position(|(item, _)| {
+                        matches!(
+                            *item,
+                            Ok(WatcherEvent::Notified(_)) | Ok(WatcherEvent::Batch(_))
+                        )
+                    });
+                    if let Some(i) = oldest {
+                        let (item, _) = state.queue.remove(i).expect("Never fails");
+                        let evicted = item.as_ref().map_or(0, event_count);
+                        result = Err(SendError::Full(evicted));
+                    } else {
+                        return Err(SendError::Full(event_count(&event)));
+                    }
+                }
+                OverflowPolicy::Block => {}
+            }
+        }
+        state.queue.push_back((Ok(event), Instant::now()));
+        state.receiver_unpark = None;
+        result
+    }
+
+    /// Returns `Async::NotReady` if the channel is full.
+    ///
+    /// In that case, the current fiber will be unparked when the receiver consumes an item
+    /// (or is dropped).
+    pub fn poll_ready(&self) -> Async<()> {
+        let mut state = self.shared.lock();
+        if self.shared.is_full(&state) && !state.is_receiver_dropped {
+            park(&mut state.sender_unpark);
+            Async::NotReady
+        } else {
+            Async::Ready(())
+        }
+    }
+
+    /// Returns the overflow policy of the channel.
+    pub fn policy(&self) -> OverflowPolicy {
+        self.shared.policy
+    }
+
+    /// Returns the number of the items queued in the channel.
+    pub fn len(&self) -> usize {
+        self.shared.lock().queue.len()
+    }
+}
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.shared.lock().sender_count += 1;
+        EventSender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.sender_count -= 1;
+        if state.sender_count == 0 {
+            state.receiver_unpark = None;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EventReceiver {
+    shared: Arc<Shared>,
+}
+impl EventReceiver {
+    /// Returns the number of the items queued in the channel.
+    pub fn len(&self) -> usize {
+        self.shared.lock().queue.len()
+    }
+
+    /// Same as `Stream::poll` except that the time when the item was queued is also returned.
+    pub fn poll_timed(&mut self) -> Poll<Option<(Result<WatcherEvent>, Instant)>, ()> {
+        let mut state = self.shared.lock();
+        if let Some(item) = state.queue.pop_front() {
+            state.sender_unpark = None;
+            Ok(Async::Ready(Some(item)))
+        } else if state.sender_count == 0 {
+            Ok(Async::Ready(None))
+        } else {
+            park(&mut state.receiver_unpark);
+            Ok(Async::NotReady)
+        }
+    }
+}
+impl Stream for EventReceiver {
+    type Item = Result<WatcherEvent>;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let polled = self.poll_timed()?;
+        Ok(polled.map(|item| item.map(|(item, _)| item)))
+    }
+}
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.is_receiver_dropped = true;
+        state.queue.clear();
+        state.sender_unpark = None;
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+}
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn is_full(&self, state: &State) -> bool {
+        self.capacity
+            .is_some_and(|capacity| state.queue.len() >= capacity)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    queue: VecDeque<(Result<WatcherEvent>, Instant)>, // With the time when queued
+    sender_count: usize,
+    is_receiver_dropped: bool,
+
+    // The fibers (or tasks) waiting for the state change (dropping a `Waiter` wakes it up)
+    sender_unpark: Option<Waiter>,
+    receiver_unpark: Option<Waiter>,
+}
+
+#[derive(Debug)]
+enum Waiter {
+    Fiber(Unpark),
+
+    // A `futures` task polling outside of fibers (e.g., via `Watcher::compat`)
+    Task(Task),
+}
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if let Waiter::Task(ref task) = *self {
+            task.notify();
+        }
+    }
+}
+
+/// Returns the number of the inotify events contained in `event`.
+pub fn event_count(event: &WatcherEvent) -> u64 {
+    match *event {
+        WatcherEvent::Batch(ref events) => events.len() as u64,
+        _ => 1,
+    }
+}
+
+fn park(waiter: &mut Option<Waiter>) {
+    // Re-parking the same fiber (or task) would wake it up immediately
+    // by dropping the old `Waiter`
+    let context_id = fiber::with_current_context(|c| c.context_id());
+    if context_id.is_none() && task::is_in_task() {
+        let is_parked = match *waiter {
+            Some(Waiter::Task(ref task)) => task.will_notify_current(),
+            _ => false,
+        };
+        if !is_parked {
+            *waiter = Some(Waiter::Task(task::current()));
+        }
+        return;
+    }
+    let parked_id = match *waiter {
+        Some(Waiter::Fiber(ref unpark)) => Some(unpark.context_id()),
+        _ => None,
+    };
+    if parked_id != context_id {
+        *waiter = fiber::with_current_context(|mut c| c.park()).map(Waiter::Fiber);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::event;
+    use EventMask;
+
+    #[test]
+    fn drop_newest_works() {
+        let (tx, rx) = channel(Some(2), OverflowPolicy::DropNewest);
+        tx.send(Ok(WatcherEvent::StartWatching)).unwrap();
+        tx.try_send(notified("foo")).unwrap();
+        assert_eq!(tx.try_send(notified("bar")), Err(SendError::Full(1)));
+        std::mem::drop(tx);
+        assert_eq!(collect(rx), [WatcherEvent::StartWatching, notified("foo")]);
+    }
+
+    #[test]
+    fn drop_oldest_works() {
+        let (tx, rx) = channel(Some(2), OverflowPolicy::DropOldest);
+        tx.send(Ok(WatcherEvent::StartWatching)).unwrap();
+        tx.try_send(notified("foo")).unwrap();
+        assert_eq!(tx.try_send(notified("bar")), Err(SendError::Full(1)));
+        assert_eq!(tx.try_send(notified("baz")), Err(SendError::Full(1)));
+        assert_eq!(tx.len(), 2);
+        std::mem::drop(tx);
+        assert_eq!(collect(rx), [WatcherEvent::StartWatching, notified("baz")]);
+    }
+
+    #[test]
+    fn evicted_batch_is_counted() {
+        let (tx, rx) = channel(Some(1), OverflowPolicy::DropOldest);
+        let batch = WatcherEvent::Batch(vec![
+            event(EventMask::CREATE, Some("foo"), "/tmp"),
+            event(EventMask::CREATE, Some("bar"), "/tmp"),
+        ]);
+        tx.try_send(batch).unwrap();
+        assert_eq!(tx.try_send(notified("baz")), Err(SendError::Full(2)));
+        std::mem::drop(tx);
+        assert_eq!(collect(rx), [notified("baz")]);
+
+        let (tx, _rx) = channel(Some(1), OverflowPolicy::DropNewest);
+        tx.try_send(notified("foo")).unwrap();
+        let batch = WatcherEvent::Batch(vec![
+            event(EventMask::CREATE, Some("bar"), "/tmp"),
+            event(EventMask::CREATE, Some("baz"), "/tmp"),
+            event(EventMask::CREATE, Some("qux"), "/tmp"),
+        ]);
+        assert_eq!(tx.try_send(batch), Err(SendError::Full(3)));
+    }
+
+    #[test]
+    fn block_works() {
+        let (tx, mut rx) = channel(Some(1), OverflowPolicy::Block);
+        assert!(tx.poll_ready().is_ready());
+        tx.try_send(notified("foo")).unwrap();
+        assert!(tx.poll_ready().is_not_ready());
+        tx.try_send(notified("bar")).unwrap();
+        assert_eq!(rx.len(), 2);
+
+        assert!(rx.poll().unwrap().is_ready());
+        assert_eq!(rx.len(), 1);
+        assert!(tx.poll_ready().is_not_ready());
+        assert!(rx.poll().unwrap().is_ready());
+        assert!(tx.poll_ready().is_ready());
+    }
+
+    #[test]
+    fn disconnection_works() {
+        let (tx, rx) = channel(None, OverflowPolicy::DropNewest);
+        let tx2 = tx.clone();
+        std::mem::drop(tx);
+        tx2.try_send(notified("foo")).unwrap();
+        std::mem::drop(tx2);
+        assert_eq!(collect(rx), [notified("foo")]);
+
+        let (tx, rx) = channel(None, OverflowPolicy::DropNewest);
+        std::mem::drop(rx);
+        assert_eq!(tx.try_send(notified("foo")), Err(SendError::Disconnected));
+    }
+
+    fn notified(name: &str) -> WatcherEvent {
+        WatcherEvent::Notified(event(EventMask::CREATE, Some(name), "/tmp"))
+    }
+
+    fn collect(rx: EventReceiver) -> Vec<WatcherEvent> {
+        rx.wait().map(|item| item.unwrap().unwrap()).collect()
+    }
+}
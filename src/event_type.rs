@@ -0,0 +1,161 @@
+use EventMask;
+
+/// Semantic classification of an inotify event.
+///
+/// Each variant that refers to a file or directory carries the `is_dir` flag
+/// (i.e., whether the event mask contains `EventMask::ISDIR`),
+/// so that a single `match` can distinguish directories from files.
+///
+/// This is created by calling `InotifyEvent::event_type` method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventType {
+    /// File was accessed (`EventMask::ACCESS`).
+    Accessed {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// Metadata changed (`EventMask::ATTRIB`).
+    AttributeChanged {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// File opened for writing was closed (`EventMask::CLOSE_WRITE`).
+    ClosedWrite {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// File or directory not opened for writing was closed (`EventMask::CLOSE_NOWRITE`).
+    ClosedNoWrite {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// File/directory created in watched directory (`EventMask::CREATE`).
+    Created {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// File/directory deleted from watched directory (`EventMask::DELETE`).
+    Deleted {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// Watched file/directory was itself deleted (`EventMask::DELETE_SELF`).
+    DeletedSelf {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// File was modified (`EventMask::MODIFY`).
+    Modified {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// Watched file/directory was itself moved (`EventMask::MOVE_SELF`).
+    MovedSelf {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// File/directory was moved out of watched directory (`EventMask::MOVED_FROM`).
+    MovedFrom {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// File/directory was moved into watched directory (`EventMask::MOVED_TO`).
+    MovedTo {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// File or directory was opened (`EventMask::OPEN`).
+    Opened {
+        /// Whether the subject of the event is a directory.
+        is_dir: bool,
+    },
+
+    /// Filesystem containing watched object was unmounted (`EventMask::UNMOUNT`).
+    Unmounted,
+
+    /// Watch was removed (`EventMask::IGNORED`).
+    Ignored,
+
+    /// Event queue overflowed (`EventMask::Q_OVERFLOW`).
+    QueueOverflow,
+
+    /// None of the above.
+    Unknown,
+}
+impl EventType {
+    /// Classifies the given event mask.
+    pub fn from_mask(mask: EventMask) -> Self {
+        let is_dir = mask.contains(EventMask::ISDIR);
+        if mask.contains(EventMask::Q_OVERFLOW) {
+            EventType::QueueOverflow
+        } else if mask.contains(EventMask::UNMOUNT) {
+            EventType::Unmounted
+        } else if mask.contains(EventMask::IGNORED) {
+            EventType::Ignored
+        } else if mask.contains(EventMask::ACCESS) {
+            EventType::Accessed { is_dir }
+        } else if mask.contains(EventMask::ATTRIB) {
+            EventType::AttributeChanged { is_dir }
+        } else if mask.contains(EventMask::CLOSE_WRITE) {
+            EventType::ClosedWrite { is_dir }
+        } else if mask.contains(EventMask::CLOSE_NOWRITE) {
+            EventType::ClosedNoWrite { is_dir }
+        } else if mask.contains(EventMask::CREATE) {
+            EventType::Created { is_dir }
+        } else if mask.contains(EventMask::DELETE) {
+            EventType::Deleted { is_dir }
+        } else if mask.contains(EventMask::DELETE_SELF) {
+            EventType::DeletedSelf { is_dir }
+        } else if mask.contains(EventMask::MODIFY) {
+            EventType::Modified { is_dir }
+        } else if mask.contains(EventMask::MOVE_SELF) {
+            EventType::MovedSelf { is_dir }
+        } else if mask.contains(EventMask::MOVED_FROM) {
+            EventType::MovedFrom { is_dir }
+        } else if mask.contains(EventMask::MOVED_TO) {
+            EventType::MovedTo { is_dir }
+        } else if mask.contains(EventMask::OPEN) {
+            EventType::Opened { is_dir }
+        } else {
+            EventType::Unknown
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_mask_works() {
+        assert_eq!(
+            EventType::from_mask(EventMask::CREATE),
+            EventType::Created { is_dir: false }
+        );
+        assert_eq!(
+            EventType::from_mask(EventMask::CREATE | EventMask::ISDIR),
+            EventType::Created { is_dir: true }
+        );
+        assert_eq!(
+            EventType::from_mask(EventMask::MOVED_TO | EventMask::ISDIR),
+            EventType::MovedTo { is_dir: true }
+        );
+        assert_eq!(EventType::from_mask(EventMask::IGNORED), EventType::Ignored);
+        assert_eq!(
+            EventType::from_mask(EventMask::Q_OVERFLOW),
+            EventType::QueueOverflow
+        );
+        assert_eq!(EventType::from_mask(EventMask::empty()), EventType::Unknown);
+    }
+}
@@ -0,0 +1,177 @@
+use futures::{Async, Poll, Stream};
+use std::path::{Path, PathBuf};
+
+use {Error, InotifyServiceHandle, WatchMask, Watcher, WatcherEvent};
+
+/// A watcher which waits for the watched path to appear.
+///
+/// If the path does not exist, this stream first produces `WatcherEvent::Pending`,
+/// and watches the nearest existing ancestor directory of the path.
+/// Once the path is created, it starts watching the path with the specified mask and
+/// produces `WatcherEvent::StartWatching` as usual.
+/// After that, this stream behaves like a plain `Watcher`
+/// (e.g., it terminates when the watch is removed).
+///
+/// This is created by calling `InotifyServiceHandle::watch_eventually` method.
+#[derive(Debug)]
+pub struct EventualWatcher {
+    service: InotifyServiceHandle,
+    path: PathBuf,
+    mask: WatchMask,
+    state: State,
+}
+impl EventualWatcher {
+    pub(crate) fn new(service: InotifyServiceHandle, path: PathBuf, mask: WatchMask) -> Self {
+        let state = if path.exists() {
+            State::Watching(service.watch(&path, mask))
+        } else {
+            State::Pending
+        };
+        EventualWatcher {
+            service,
+            path,
+            mask,
+            state,
+        }
+    }
+
+    /// Returns the path to be watched.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn resolve(&mut self) {
+        if self.path.exists() {
+            self.state = State::Watching(self.service.watch(&self.path, self.mask));
+            return;
+        }
+
+        let ancestor = existing_ancestor(&self.path);
+        if self.state.ancestor() == Some(&ancestor) {
+            return;
+        }
+        let mask = WatchMask::CREATE | WatchMask::MOVED_TO | WatchMask::ONLYDIR;
+        let watcher = self.service.watch(&ancestor, mask);
+        self.state = State::Waiting { ancestor, watcher };
+    }
+}
+impl Stream for EventualWatcher {
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let polled = match self.state {
+                State::Pending => {
+                    self.resolve();
+                    if let State::Watching(_) = self.state {
+                        continue;
+                    }
+                    return Ok(Async::Ready(Some(WatcherEvent::Pending)));
+                }
+                State::Watching(ref mut watcher) => return track!(watcher.poll()),
+                State::Waiting {
+                    ref mut watcher, ..
+                } => track!(watcher.poll())?,
+            };
+            match polled {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => {
+                    // The ancestor directory has been removed
+                    // (the state is reset so that a re-created one is watched again).
+                    self.state = State::Pending;
+                    self.resolve();
+                }
+                Async::Ready(Some(_)) => {
+                    // The path may have been created after the previous check
+                    // (including the period before the ancestor watch started)
+                    self.resolve();
+                }
+            }
+        }
+    }
+}
+
+fn existing_ancestor(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors().skip(1) {
+        if ancestor.as_os_str().is_empty() {
+            // `path` is a relative one
+            return PathBuf::from(".");
+        }
+        if ancestor.is_dir() {
+            return ancestor.to_path_buf();
+        }
+    }
+    PathBuf::from("/")
+}
+
+#[derive(Debug)]
+enum State {
+    Pending,
+    Waiting { ancestor: PathBuf, watcher: Watcher },
+    Watching(Watcher),
+}
+impl State {
+    fn ancestor(&self) -> Option<&PathBuf> {
+        if let State::Waiting { ref ancestor, .. } = *self {
+            Some(ancestor)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Stream;
+    use std::fs;
+
+    use super::*;
+    use test_util::{TempDir, TestExecutor};
+    use EventMask;
+
+    #[test]
+    fn eventual_watcher_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor
+            .handle
+            .watch_eventually(dir.join("a/b"), WatchMask::CREATE);
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert_eq!(event, WatcherEvent::Pending);
+
+        fs::create_dir(dir.join("a")).unwrap();
+        fs::create_dir(dir.join("a/b")).unwrap();
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        fs::write(dir.join("a/b/foo"), b"").unwrap();
+        let (event, _) = next_event(&mut executor, watcher);
+        if let WatcherEvent::Notified(e) = event {
+            assert_eq!(e.mask, EventMask::CREATE);
+            assert_eq!(e.path(), dir.join("a/b/foo"));
+        } else {
+            panic!("Unexpected event: {:?}", event);
+        }
+    }
+
+    #[test]
+    fn existing_path_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor
+            .handle
+            .watch_eventually(dir.path(), WatchMask::CREATE);
+        let (event, _) = next_event(&mut executor, watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+    }
+
+    fn next_event(
+        executor: &mut TestExecutor,
+        watcher: EventualWatcher,
+    ) -> (WatcherEvent, EventualWatcher) {
+        let (event, watcher) = executor.wait(watcher.into_future()).ok().unwrap();
+        (event.expect("Unexpected end of stream"), watcher)
+    }
+}
@@ -0,0 +1,281 @@
+use futures::{Async, Poll, Stream};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use {Error, EventMask, InotifyEvent, WatcherEvent};
+
+/// A filesystem operation derived from inotify events.
+///
+/// This is produced by `IntoFsOps` (see `WatcherExt::into_fs_ops`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsOp {
+    /// The file/directory at `path` has been created (or moved in from an unwatched place).
+    Create {
+        /// The path of the file/directory.
+        path: PathBuf,
+
+        /// Whether the created one is a directory.
+        is_dir: bool,
+    },
+
+    /// The file/directory at `path` has been removed (or moved out to an unwatched place).
+    Remove {
+        /// The path of the file/directory.
+        path: PathBuf,
+    },
+
+    /// The file/directory has been renamed from `from` to `to`.
+    Rename {
+        /// The path of the file/directory before the rename.
+        from: PathBuf,
+
+        /// The path of the file/directory after the rename.
+        to: PathBuf,
+    },
+
+    /// The content of the file at `path` has been modified.
+    Modify {
+        /// The path of the file.
+        path: PathBuf,
+    },
+
+    /// The event queue of the kernel has overflowed.
+    ///
+    /// Some operations have been lost, thus the consumer should rescan the watched files.
+    Overflow,
+}
+
+/// A stream which converts the events of a watcher into filesystem operations.
+///
+/// This is created by calling `WatcherExt::into_fs_ops` method.
+#[derive(Debug)]
+pub struct IntoFsOps<S> {
+    inner: S,
+    queue: VecDeque<FsOp>,
+    moved_from: Option<(u32, PathBuf)>,
+    last_modified: Option<PathBuf>,
+}
+impl<S> IntoFsOps<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        IntoFsOps {
+            inner,
+            queue: VecDeque::new(),
+            moved_from: None,
+            last_modified: None,
+        }
+    }
+
+    fn handle_event(&mut self, event: InotifyEvent) {
+        let path = event.path();
+        if event.mask.contains(EventMask::MOVED_TO) {
+            match self.moved_from.take() {
+                Some((cookie, from)) if cookie == event.cookie => {
+                    self.push(FsOp::Rename { from, to: path });
+                }
+                moved_from => {
+                    self.moved_from = moved_from;
+                    self.flush_moved_from();
+                    let is_dir = event.is_dir();
+                    self.push(FsOp::Create { path, is_dir });
+                }
+            }
+            return;
+        }
+
+        self.flush_moved_from();
+        if event.mask.contains(EventMask::MOVED_FROM) {
+            self.moved_from = Some((event.cookie, path));
+        } else if event.mask.contains(EventMask::CREATE) {
+            let is_dir = event.is_dir();
+            self.push(FsOp::Create { path, is_dir });
+        } else if event
+            .mask
+            .intersects(EventMask::DELETE | EventMask::DELETE_SELF)
+        {
+            self.push(FsOp::Remove { path });
+        } else if event
+            .mask
+            .intersects(EventMask::MODIFY | EventMask::CLOSE_WRITE)
+        {
+            // A series of writes to the same file (and the following close) is coalesced
+            let is_closed = event.mask.contains(EventMask::CLOSE_WRITE);
+            if self.last_modified.as_ref() != Some(&path) {
+                self.push(FsOp::Modify { path });
+            }
+            if is_closed {
+                // The next write to the file is a new modification
+                self.last_modified = None;
+            }
+        } else if event.mask.contains(EventMask::Q_OVERFLOW) {
+            self.push(FsOp::Overflow);
+        }
+    }
+
+    fn flush_moved_from(&mut self) {
+        // The counterpart of the rename is outside of the watched files
+        if let Some((_, path)) = self.moved_from.take() {
+            self.push(FsOp::Remove { path });
+        }
+    }
+
+    fn push(&mut self, op: FsOp) {
+        self.last_modified = match op {
+            FsOp::Modify { ref path } => Some(path.clone()),
+            _ => None,
+        };
+        self.queue.push_back(op);
+    }
+}
+impl<S> Stream for IntoFsOps<S>
+where
+    S: Stream<Item = WatcherEvent, Error = Error>,
+{
+    type Item = FsOp;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(op) = self.queue.pop_front() {
+                return Ok(Async::Ready(Some(op)));
+            }
+            match track!(self.inner.poll())? {
+                Async::Ready(Some(WatcherEvent::Notified(event))) => self.handle_event(event),
+                Async::Ready(Some(WatcherEvent::Batch(events))) => {
+                    for event in events {
+                        self.handle_event(event);
+                    }
+                }
+                Async::Ready(Some(_)) => {}
+                Async::Ready(None) => {
+                    self.flush_moved_from();
+                    return Ok(Async::Ready(self.queue.pop_front()));
+                }
+                Async::NotReady => {
+                    // The kernel queues the both sides of a rename at once,
+                    // so the counterpart will never come after this point.
+                    self.flush_moved_from();
+                    self.last_modified = None;
+                    if let Some(op) = self.queue.pop_front() {
+                        return Ok(Async::Ready(Some(op)));
+                    }
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{stream, Future};
+
+    use super::*;
+    use test_util::event;
+    use WatcherExt;
+
+    #[test]
+    fn into_fs_ops_works() {
+        let mut moved_from = event(EventMask::MOVED_FROM, Some("foo"), "/tmp");
+        moved_from.cookie = 1;
+        let mut moved_to = event(EventMask::MOVED_TO, Some("bar"), "/tmp");
+        moved_to.cookie = 1;
+        let mut moved_out = event(EventMask::MOVED_FROM, Some("baz"), "/tmp");
+        moved_out.cookie = 2;
+        let events = vec![
+            WatcherEvent::StartWatching,
+            WatcherEvent::Notified(event(EventMask::CREATE, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::OPEN, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::MODIFY, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::MODIFY, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::CLOSE_WRITE, Some("foo"), "/tmp")),
+            WatcherEvent::Batch(vec![moved_from, moved_to]),
+            WatcherEvent::Notified(moved_out),
+            WatcherEvent::Notified(event(
+                EventMask::MOVED_TO | EventMask::ISDIR,
+                Some("qux"),
+                "/tmp",
+            )),
+            WatcherEvent::Notified(event(EventMask::MODIFY, Some("bar"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::Q_OVERFLOW, None, "")),
+            WatcherEvent::Notified(event(EventMask::MODIFY, Some("bar"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::DELETE, Some("bar"), "/tmp")),
+        ];
+        let ops = stream::iter_ok(events)
+            .into_fs_ops()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            ops,
+            [
+                FsOp::Create {
+                    path: PathBuf::from("/tmp/foo"),
+                    is_dir: false
+                },
+                FsOp::Modify {
+                    path: PathBuf::from("/tmp/foo")
+                },
+                FsOp::Rename {
+                    from: PathBuf::from("/tmp/foo"),
+                    to: PathBuf::from("/tmp/bar")
+                },
+                FsOp::Remove {
+                    path: PathBuf::from("/tmp/baz")
+                },
+                FsOp::Create {
+                    path: PathBuf::from("/tmp/qux"),
+                    is_dir: true
+                },
+                FsOp::Modify {
+                    path: PathBuf::from("/tmp/bar")
+                },
+                FsOp::Overflow,
+                FsOp::Modify {
+                    path: PathBuf::from("/tmp/bar")
+                },
+                FsOp::Remove {
+                    path: PathBuf::from("/tmp/bar")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn each_write_cycle_is_a_modification() {
+        let events = vec![
+            WatcherEvent::Notified(event(EventMask::MODIFY, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::CLOSE_WRITE, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::MODIFY, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::MODIFY, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::CLOSE_WRITE, Some("foo"), "/tmp")),
+        ];
+        let ops = stream::iter_ok(events)
+            .into_fs_ops()
+            .collect()
+            .wait()
+            .unwrap();
+        let modify = FsOp::Modify {
+            path: PathBuf::from("/tmp/foo"),
+        };
+        assert_eq!(ops, [modify.clone(), modify]);
+    }
+
+    #[test]
+    fn unpaired_moved_from_is_flushed_at_end() {
+        let events = vec![WatcherEvent::Notified(event(
+            EventMask::MOVED_FROM,
+            Some("foo"),
+            "/tmp",
+        ))];
+        let ops = stream::iter_ok(events)
+            .into_fs_ops()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            ops,
+            [FsOp::Remove {
+                path: PathBuf::from("/tmp/foo")
+            }]
+        );
+    }
+}
@@ -1,25 +1,43 @@
 use fibers;
-use fibers::io::poll::{EventedHandle, Interest, Register};
+use fibers::io::poll::{EventedHandle, Interest, PollerHandle, Register};
 use fibers::sync::oneshot::Monitor;
 use futures::{Async, Future, Poll, Stream};
 use inotify_sys;
 use libc;
+#[cfg(test)]
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
 use std::ffi::{CStr, CString, OsString};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Read};
 use std::marker::PhantomData;
-use std::mem;
+use std::mem::{self, ManuallyDrop};
 use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::{Path, PathBuf};
+use std::ptr;
 use std::sync::Arc;
 
 use mio_ext::OwnedEventedFd;
-use {Error, ErrorKind, EventMask, Result, WatchMask};
+use observer::SharedObserver;
+use {DecodedEvent, Error, ErrorKind, EventMask, EventType, Result};
+
+/// The initial size of the buffer used to read events from an inotify instance.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 4096;
+
+/// The default upper bound of the read buffer size.
+///
+/// See `InotifyServiceBuilder::max_read_buffer_size`.
+pub const DEFAULT_MAX_READ_BUFFER_SIZE: usize = 64 * 1024;
 
 /// Event notified by [inotify].
 ///
+/// Two events are equal if their `wd`, `mask`, `cookie`, `name` and `watch_path` are equal.
+/// `inode` is not compared because it is a snapshot taken by `lstat(2)` at delivery
+/// (the same kernel event may be delivered with different ones),
+/// and neither is the raw representation (see `InotifyEvent::raw`).
+///
 /// [inotify]: http://man7.org/linux/man-pages/man7/inotify.7.html
 #[derive(Debug, Clone)]
 pub struct InotifyEvent {
@@ -29,6 +47,16 @@ pub struct InotifyEvent {
     pub mask: EventMask,
 
     /// Unique cookie associating related events.
+    ///
+    /// Currently, this is used only for rename events.
+    /// When a file/directory is renamed, a pair of `MOVED_FROM` and `MOVED_TO` events
+    /// which have the same non-zero cookie is generated
+    /// (the former for the old directory and the latter for the new one).
+    /// If the old or new directory is not watched, only one of them is generated.
+    ///
+    /// For all other events, this is set to `0`.
+    ///
+    /// See also `InotifyEvent::is_rename_part` method.
     pub cookie: u32,
 
     /// The file/directory name within to the watched directory.
@@ -36,107 +64,339 @@ pub struct InotifyEvent {
     /// This is present only when an event is returned for a file or directory
     /// inside a watched directory.
     pub name: Option<PathBuf>,
+
+    /// The path of the watched file/directory.
+    ///
+    /// This is the path passed to `InotifyServiceHandle::watch` unless it is rewritten by
+    /// adaptors such as `WatcherExt::relative_to`.
+    pub watch_path: PathBuf,
+
+    /// The identity of the file/directory that the event refers to.
+    ///
+    /// This is set only if `WatchOptions::stat_inode` is enabled and
+    /// the file/directory exists when the event is delivered.
+    pub inode: Option<InodeId>,
+
+    #[cfg(feature = "raw-events")]
+    pub(crate) raw: Option<Arc<RawEvent>>,
+}
+impl InotifyEvent {
+    /// Returns `true` if the event refers to a directory.
+    ///
+    /// This is equivalent to `self.mask.contains(EventMask::ISDIR)`.
+    pub fn is_dir(&self) -> bool {
+        self.mask.contains(EventMask::ISDIR)
+    }
+
+    /// Returns `true` if the event is a part of a rename operation.
+    ///
+    /// That is, the event mask contains `MOVED_FROM` or `MOVED_TO`,
+    /// and `cookie` is non-zero.
+    /// Events sharing the same cookie can be paired up to reconstruct the rename.
+    pub fn is_rename_part(&self) -> bool {
+        let is_moved = self
+            .mask
+            .intersects(EventMask::MOVED_FROM | EventMask::MOVED_TO);
+        is_moved && self.cookie != 0
+    }
+
+    /// Returns `true` if the event refers to the watched path itself rather than
+    /// an entry inside the watched directory.
+    ///
+    /// That is, `name` is `None`.
+    /// The self events (e.g., `DELETE_SELF` and `MOVE_SELF`) never have a name,
+    /// thus they always refer to the watched path.
+    /// For example, `MODIFY` on a watched directory reports modifications of the files
+    /// inside it (thus this returns `false`), while `MODIFY` on a watched file reports
+    /// modifications of the file itself (thus this returns `true`).
+    pub fn refers_to_watched_path(&self) -> bool {
+        self.name.is_none()
+    }
+
+    /// Returns the semantic classification of the event.
+    pub fn event_type(&self) -> EventType {
+        EventType::from_mask(self.mask)
+    }
+
+    /// Returns the full path of the file/directory that the event refers to.
+    ///
+    /// That is, `watch_path` joined with `name` (if present).
+    pub fn path(&self) -> PathBuf {
+        if let Some(ref name) = self.name {
+            self.watch_path.join(name)
+        } else {
+            self.watch_path.clone()
+        }
+    }
+
+    /// Decodes the event into a structured report.
+    pub fn decode(&self) -> DecodedEvent {
+        DecodedEvent::new(self)
+    }
+
+    /// Returns the raw `inotify_event` from which the event has been parsed.
+    ///
+    /// This is intended for diagnostics (e.g., reporting an event that looks wrong).
+    /// It returns `None` if the event has not been read from the kernel
+    /// (e.g., it is made by the user).
+    ///
+    /// This method is available only if the `raw-events` feature is enabled.
+    #[cfg(feature = "raw-events")]
+    pub fn raw(&self) -> Option<&RawEvent> {
+        self.raw.as_deref()
+    }
+}
+
+impl PartialEq for InotifyEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.wd == other.wd
+            && self.mask == other.mask
+            && self.cookie == other.cookie
+            && self.name == other.name
+            && self.watch_path == other.watch_path
+    }
 }
+impl Eq for InotifyEvent {}
+
+/// The raw representation of an event read from an inotify instance.
+///
+/// See `InotifyEvent::raw` and [inotify(7)] for the meaning of the fields.
+///
+/// [inotify(7)]: http://man7.org/linux/man-pages/man7/inotify.7.html
+#[cfg(feature = "raw-events")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawEvent {
+    /// Watch descriptor.
+    pub wd: i32,
+
+    /// Mask describing event (including bits unknown to `EventMask`).
+    pub mask: u32,
+
+    /// Unique cookie associating related events.
+    pub cookie: u32,
 
+    /// The size of the name field (including the terminating and padding NUL bytes).
+    pub len: u32,
+
+    /// The name field as it is (i.e., `len` bytes).
+    pub name: Vec<u8>,
+}
+
+/// Device and inode numbers which identify a file/directory.
+///
+/// Events observed via different paths (e.g., hard links or bind mounts) can be
+/// deduplicated by this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InodeId {
+    /// ID of the device containing the file/directory.
+    pub dev: u64,
+
+    /// Inode number.
+    pub ino: u64,
+}
+impl InodeId {
+    /// Gets the `InodeId` of the file/directory at `path` (without following symbolic links).
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let metadata = track!(fs::symlink_metadata(path).map_err(Error::from))?;
+        Ok(InodeId {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The error number which will be returned by the next read from an inotify instance.
+    pub static INJECTED_READ_ERROR: Cell<Option<i32>> = const { Cell::new(None) };
+
+    /// If `true`, the next read from an inotify instance will return zero bytes (i.e., EOF).
+    pub static INJECTED_READ_EOF: Cell<bool> = const { Cell::new(false) };
+
+    /// The error number which will be returned by the next creation of an inotify instance.
+    pub static INJECTED_INIT_ERROR: Cell<Option<i32>> = const { Cell::new(None) };
+
+    /// The error numbers which will be returned by the next additions of watches (in order).
+    pub static INJECTED_ADD_WATCH_ERRORS: RefCell<VecDeque<i32>> =
+        const { RefCell::new(VecDeque::new()) };
+
+    /// The function which will be called right after the next successful addition of a watch.
+    pub static AFTER_ADD_WATCH: RefCell<Option<Box<dyn FnOnce()>>> = const { RefCell::new(None) };
+}
+
+/// Inotify instance.
+///
+/// As a `Stream`, this terminates when the file descriptor turns out to be invalid.
+///
+/// The file descriptor is closed by the poller after deregistering it (see `OwnedEventedFd`),
+/// rather than when this is dropped.
+/// Thus the poller never refers to a closed descriptor (or to a new one which reuses the number).
 #[derive(Debug)]
 pub struct Inotify {
-    file: File,
+    file: ManuallyDrop<File>, // The file descriptor is owned by `read_monitor`
     events: VecDeque<InotifyEvent>,
+    read_buffer: Vec<u8>,
     read_monitor: ReadMonitor,
+    is_dead: bool,
+    observer: SharedObserver,
     _cannot_sync: PhantomData<*const ()>,
 }
 unsafe impl Send for Inotify {}
 impl Inotify {
-    pub fn new() -> Result<Self> {
+    pub fn new(observer: SharedObserver) -> Result<Self> {
+        track!(Self::with_poller(observer, None))
+    }
+
+    /// Makes a new instance registered to `poller`.
+    ///
+    /// If `poller` is `None`, the poller of the current fiber is used.
+    pub fn with_poller(observer: SharedObserver, poller: Option<PollerHandle>) -> Result<Self> {
         let flags = inotify_sys::IN_NONBLOCK;
-        let fd = unsafe { inotify_sys::inotify_init1(flags) };
-        if fd == -1 {
-            Err(track!(Error::last_os_error()))
-        } else {
-            Ok(Inotify {
-                file: unsafe { File::from_raw_fd(fd) },
-                events: VecDeque::new(),
-                read_monitor: track!(ReadMonitor::new(fd))?,
-                _cannot_sync: PhantomData,
-            })
-        }
+        let result = init(flags);
+        observer.syscall("inotify_init1", format_args!("flags={:#x}", flags), &result);
+        let fd = track!(result.map_err(Error::from))? as RawFd;
+        let read_monitor = ReadMonitor::new(fd, poller);
+        let read_monitor = track!(read_monitor.inspect_err(|_| {
+            // The descriptor has not been handed over to any poller
+            unsafe { libc::close(fd) };
+        }))?;
+        Ok(Inotify {
+            file: ManuallyDrop::new(unsafe { File::from_raw_fd(fd) }),
+            events: VecDeque::new(),
+            read_buffer: vec![0; DEFAULT_READ_BUFFER_SIZE],
+            read_monitor,
+            is_dead: false,
+            observer,
+            _cannot_sync: PhantomData,
+        })
+    }
+    pub fn add_watch<P: AsRef<Path>>(&mut self, path: P, mask: u32) -> Result<WatchDecriptor> {
+        let wd = track!(self.try_add_watch(path, mask, false))?;
+        Ok(wd.expect("Never fails"))
     }
-    pub fn add_watch<P: AsRef<Path>>(
+
+    /// Adds a watch.
+    ///
+    /// If `is_retriable` is `true` and `inotify_add_watch` fails with a transient error
+    /// (i.e., `EINTR`, `EAGAIN` or `ENOMEM`), `Ok(None)` is returned instead of the error,
+    /// so that the caller can retry it later.
+    pub fn try_add_watch<P: AsRef<Path>>(
         &mut self,
         path: P,
-        mask: WatchMask,
-    ) -> Result<WatchDecriptor> {
-        let wd = unsafe {
-            let path = track!(CString::new(
-                path.as_ref().to_path_buf().into_os_string().into_vec()
-            )
-            .map_err(Error::from))?;
-            inotify_sys::inotify_add_watch(self.file.as_raw_fd(), path.as_ptr(), mask.bits())
-        };
-        if wd == -1 {
-            Err(track!(Error::last_os_error()))
-        } else {
-            Ok(WatchDecriptor(wd))
+        mask: u32,
+        is_retriable: bool,
+    ) -> Result<Option<WatchDecriptor>> {
+        let path = path.as_ref();
+        let c_path = track!(
+            CString::new(path.to_path_buf().into_os_string().into_vec()).map_err(Error::from)
+        )?;
+        let fd = self.file.as_raw_fd();
+        let result = add_watch(fd, &c_path, mask);
+        self.observer.syscall(
+            "inotify_add_watch",
+            format_args!("fd={}, path={:?}, mask={:#x}", fd, path, mask),
+            &result,
+        );
+        match result {
+            Err(ref e) if is_retriable && is_transient(e) => Ok(None),
+            _ => {
+                let wd = track!(result.map_err(Error::from))?;
+                Ok(Some(WatchDecriptor(wd as libc::c_int)))
+            }
         }
     }
     pub fn remove_watch(&mut self, wd: WatchDecriptor) -> Result<()> {
-        let result = unsafe { inotify_sys::inotify_rm_watch(self.file.as_raw_fd(), wd.0) };
-        if result == -1 {
-            Err(track!(Error::last_os_error()))
-        } else {
-            Ok(())
-        }
+        let fd = self.file.as_raw_fd();
+        let result = cvt(unsafe { inotify_sys::inotify_rm_watch(fd, wd.0) });
+        self.observer.syscall(
+            "inotify_rm_watch",
+            format_args!("fd={}, wd={}", fd, wd.0),
+            &result,
+        );
+        track!(result.map_err(Error::from))?;
+        Ok(())
     }
 
-    fn read_event(&mut self) -> Result<Option<InotifyEvent>> {
+    pub fn read_event(&mut self) -> Result<Option<InotifyEvent>> {
         if let Some(event) = self.events.pop_front() {
             return Ok(Some(event));
         }
 
-        let mut buf = [0; 4096];
-        match self.file.read(&mut buf) {
+        let mut buf = mem::take(&mut self.read_buffer); // `read` borrows `self` mutably
+        let result = self.read(&mut buf);
+        self.observer.syscall(
+            "read",
+            format_args!("fd={}, count={}", self.file.as_raw_fd(), buf.len()),
+            &result.as_ref().map(|&n| n as i64).map_err(clone_io_error),
+        );
+        self.read_buffer = buf;
+        match result {
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
                     Ok(None)
+                } else if e.raw_os_error() == Some(libc::EBADF) {
+                    self.is_dead = true;
+                    Ok(None)
                 } else {
                     Err(track!(Error::from(e)))
                 }
             }
+            Ok(0) => {
+                // An empty queue is reported by `EAGAIN`, thus EOF means that
+                // the file descriptor no longer refers to a working instance.
+                self.is_dead = true;
+                Ok(None)
+            }
             Ok(read_size) => {
-                let mut offset = 0;
-                while offset < read_size {
-                    let raw_event = unsafe {
-                        &*((buf[offset..]).as_ptr() as *const inotify_sys::inotify_event)
-                    };
-                    offset += mem::size_of::<inotify_sys::inotify_event>() + raw_event.len as usize;
-                    track_assert!(offset <= read_size, ErrorKind::Other);
-
-                    let name = if raw_event.len == 0 {
-                        None
-                    } else {
-                        let start = offset - raw_event.len as usize;
-                        let mut end = offset;
-                        while end > 1 && buf[end - 1] == b'\0' && buf[end - 2] == b'\0' {
-                            end -= 1;
-                        }
-                        let name = track!(
-                            CStr::from_bytes_with_nul(&buf[start..end]).map_err(Error::from)
-                        )?;
-                        let name = PathBuf::from(OsString::from_vec(name.to_bytes().to_owned()));
-                        Some(name)
-                    };
-                    let event = InotifyEvent {
-                        wd: WatchDecriptor(raw_event.wd),
-                        mask: EventMask::from_bits_truncate(raw_event.mask),
-                        cookie: raw_event.cookie,
-                        name,
-                    };
-                    self.events.push_back(event);
+                let buf = &self.read_buffer[..read_size];
+                track!(parse_events(buf, &mut self.events))?;
+                for event in &self.events {
+                    self.observer.raw_event(event);
                 }
                 Ok(self.events.pop_front())
             }
         }
     }
+
+    /// Returns the size of the buffer passed to each `read` system call.
+    pub fn read_buffer_size(&self) -> usize {
+        self.read_buffer.len()
+    }
+
+    /// Sets the size of the buffer passed to each `read` system call.
+    ///
+    /// A larger buffer reads more events per system call.
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        self.read_buffer.resize(size, 0);
+    }
+
+    /// Returns the number of the events which have been read from the kernel but not yet consumed.
+    #[cfg(test)]
+    pub fn buffered_len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Takes all the events which have been read from the kernel but not yet consumed.
+    #[cfg(test)]
+    pub fn drain_all(&mut self) -> Vec<InotifyEvent> {
+        self.events.drain(..).collect()
+    }
+
+    #[cfg(not(test))]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+
+    #[cfg(test)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(errno) = INJECTED_READ_ERROR.with(|e| e.take()) {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        if INJECTED_READ_EOF.with(|e| e.take()) {
+            return Ok(0);
+        }
+        self.file.read(buf)
+    }
 }
 impl Stream for Inotify {
     type Item = InotifyEvent;
@@ -145,15 +405,117 @@ impl Stream for Inotify {
         track!(self.read_monitor.poll())?;
         if let Some(event) = track!(self.read_event())? {
             Ok(Async::Ready(Some(event)))
+        } else if self.is_dead {
+            Ok(Async::Ready(None))
         } else {
             Ok(Async::NotReady)
         }
     }
 }
 
+#[cfg(not(test))]
+fn init(flags: libc::c_int) -> io::Result<i64> {
+    cvt(unsafe { inotify_sys::inotify_init1(flags) })
+}
+
+#[cfg(test)]
+fn init(flags: libc::c_int) -> io::Result<i64> {
+    if let Some(errno) = INJECTED_INIT_ERROR.with(|e| e.take()) {
+        return Err(io::Error::from_raw_os_error(errno));
+    }
+    cvt(unsafe { inotify_sys::inotify_init1(flags) })
+}
+
+#[cfg(not(test))]
+fn add_watch(fd: RawFd, path: &CStr, mask: u32) -> io::Result<i64> {
+    cvt(unsafe { inotify_sys::inotify_add_watch(fd, path.as_ptr(), mask) })
+}
+
+#[cfg(test)]
+fn add_watch(fd: RawFd, path: &CStr, mask: u32) -> io::Result<i64> {
+    if let Some(errno) = INJECTED_ADD_WATCH_ERRORS.with(|e| e.borrow_mut().pop_front()) {
+        return Err(io::Error::from_raw_os_error(errno));
+    }
+    let wd = cvt(unsafe { inotify_sys::inotify_add_watch(fd, path.as_ptr(), mask) })?;
+    if let Some(f) = AFTER_ADD_WATCH.with(|f| f.borrow_mut().take()) {
+        f();
+    }
+    Ok(wd)
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::ENOMEM)
+    )
+}
+
+fn cvt(result: libc::c_int) -> io::Result<i64> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(i64::from(result))
+    }
+}
+
+fn clone_io_error(e: &io::Error) -> io::Error {
+    if let Some(errno) = e.raw_os_error() {
+        io::Error::from_raw_os_error(errno)
+    } else {
+        io::Error::new(e.kind(), e.to_string())
+    }
+}
+
+fn parse_events(buf: &[u8], events: &mut VecDeque<InotifyEvent>) -> Result<()> {
+    let header_size = mem::size_of::<inotify_sys::inotify_event>();
+    let mut offset = 0;
+    while offset < buf.len() {
+        track_assert!(offset + header_size <= buf.len(), ErrorKind::Other);
+        let raw_event: inotify_sys::inotify_event =
+            unsafe { ptr::read_unaligned(buf[offset..].as_ptr() as *const _) };
+        offset += header_size + raw_event.len as usize;
+        track_assert!(offset <= buf.len(), ErrorKind::Other);
+
+        let name = if raw_event.len == 0 {
+            None
+        } else {
+            // The name is terminated by NUL and may be followed by
+            // additional NUL bytes for alignment.
+            // If the name occupies the whole slot (not expected), it is taken as it is.
+            let slot = &buf[offset - raw_event.len as usize..offset];
+            let len = slot.iter().position(|&b| b == b'\0').unwrap_or(slot.len());
+            let name = PathBuf::from(OsString::from_vec(slot[..len].to_owned()));
+            Some(name)
+        };
+        let event = InotifyEvent {
+            wd: WatchDecriptor(raw_event.wd),
+            mask: EventMask::from_bits_truncate(raw_event.mask),
+            cookie: raw_event.cookie,
+            name,
+            watch_path: PathBuf::new(), // Set by `InotifyService`
+            inode: None,                // Ditto
+            #[cfg(feature = "raw-events")]
+            raw: Some(Arc::new(RawEvent {
+                wd: raw_event.wd,
+                mask: raw_event.mask,
+                cookie: raw_event.cookie,
+                len: raw_event.len,
+                name: buf[offset - raw_event.len as usize..offset].to_owned(),
+            })),
+        };
+        events.push_back(event);
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct WatchDecriptor(pub(crate) libc::c_int);
 
+/// Readiness monitor of an inotify file descriptor.
+///
+/// No explicit `Drop` is needed: dropping `handle` (or the unresolved `register`,
+/// whose handle is then dropped by the poller) makes the poller deregister the descriptor
+/// and then drop the `OwnedEventedFd`, which closes it.
 #[derive(Debug)]
 struct ReadMonitor {
     register: Register<OwnedEventedFd>,
@@ -161,12 +523,23 @@ struct ReadMonitor {
     monitor: Option<Monitor<(), io::Error>>,
 }
 impl ReadMonitor {
-    fn new(fd: RawFd) -> Result<Self> {
-        let register = fibers::fiber::with_current_context(|mut context| {
-            context.poller().register(OwnedEventedFd(fd))
-        });
+    fn new(fd: RawFd, poller: Option<PollerHandle>) -> Result<Self> {
+        let register = if let Some(mut poller) = poller {
+            Some(poller.register(OwnedEventedFd(fd)))
+        } else {
+            fibers::fiber::with_current_context(|mut context| {
+                context.poller().register(OwnedEventedFd(fd))
+            })
+        };
+        let register = track_assert_some!(
+            register,
+            ErrorKind::Other,
+            "Not in a fiber context: inotify instances must be created in a fiber \
+             (e.g., poll the service or the watcher on a `fibers` executor), \
+             or the poller must be given explicitly (e.g., `StandaloneWatcher::new_with_poller`)"
+        );
         Ok(ReadMonitor {
-            register: track_assert_some!(register, ErrorKind::Other, "Not in a fiber context"),
+            register,
             handle: None,
             monitor: None,
         })
@@ -193,3 +566,206 @@ impl Future for ReadMonitor {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use fibers::Spawn;
+    use futures;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use test_util::{event, TempDir, TestExecutor};
+    use {WatchMask, WatcherEvent};
+
+    #[test]
+    fn parse_events_works() {
+        let long_name = "a".repeat(255);
+        let mut buf = Vec::new();
+        push_raw_event(&mut buf, EventMask::CREATE, "foo", 16);
+        push_raw_event(&mut buf, EventMask::DELETE, &long_name, 256);
+        push_raw_event(&mut buf, EventMask::MODIFY, "", 0);
+        push_raw_event(&mut buf, EventMask::CREATE, "0123456789abcdef", 16); // No NUL
+
+        let mut events = VecDeque::new();
+        parse_events(&buf, &mut events).unwrap();
+        let names = events
+            .iter()
+            .map(|e| (e.mask, e.name.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                (EventMask::CREATE, Some(PathBuf::from("foo"))),
+                (EventMask::DELETE, Some(PathBuf::from(long_name))),
+                (EventMask::MODIFY, None),
+                (EventMask::CREATE, Some(PathBuf::from("0123456789abcdef"))),
+            ]
+        );
+
+        // Truncated
+        let mut events = VecDeque::new();
+        assert!(parse_events(&buf[..buf.len() - 1], &mut events).is_err());
+    }
+
+    #[test]
+    fn read_event_reads_all_available_events() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let path = dir.path().to_path_buf();
+        let future = futures::lazy(move || {
+            // `Inotify` must be created in a fiber
+            let mut inotify = track!(Inotify::new(Default::default()))?;
+            track!(inotify.add_watch(&path, inotify_sys::IN_CREATE))?;
+            for name in &["foo", "bar", "baz"] {
+                track!(fs::write(path.join(name), b"").map_err(Error::from))?;
+            }
+
+            let first = track!(inotify.read_event())?;
+            let buffered_len = inotify.buffered_len();
+            let rest = inotify.drain_all();
+            Ok::<_, Error>((first, buffered_len, rest, inotify.buffered_len()))
+        });
+        let monitor = executor.executor.spawn_monitor(future);
+        let (first, buffered_len, rest, remaining) = executor.wait(monitor).unwrap();
+
+        assert_eq!(first.and_then(|e| e.name), Some(PathBuf::from("foo")));
+        assert_eq!(buffered_len, 2);
+        let names = rest.into_iter().map(|e| e.name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [Some(PathBuf::from("bar")), Some(PathBuf::from("baz"))]
+        );
+        assert_eq!(remaining, 0);
+    }
+
+    #[test]
+    fn many_instances_can_be_created_and_dropped() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        // The descriptor numbers of the dropped instances are likely to be reused by
+        // the following ones, thus each instance must keep working via the poller
+        let path = dir.path().to_path_buf();
+        let future = futures::stream::iter_ok(0..128).for_each(move |i| {
+            let path = path.clone();
+            futures::lazy(move || {
+                let mut inotify = track!(Inotify::new(Default::default()))?;
+                track!(inotify.add_watch(&path, inotify_sys::IN_CREATE))?;
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(1));
+                    fs::write(path.join(i.to_string()), b"").unwrap();
+                });
+                Ok::<_, Error>(inotify)
+            })
+            .and_then(|inotify| inotify.into_future().map_err(|(e, _)| e))
+            .map(move |(event, _)| {
+                let name = event.and_then(|e| e.name);
+                assert_eq!(name, Some(PathBuf::from(i.to_string())));
+            })
+        });
+        let monitor = executor.executor.spawn_monitor(future);
+        executor.wait(monitor).unwrap();
+    }
+
+    #[test]
+    fn max_length_name_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, mut watcher) = executor.next_event(watcher);
+
+        // Fills the kernel buffer with several events to place the names at various offsets
+        let names = (0..32)
+            .map(|i| format!("{:02}{}", i, "x".repeat(253)))
+            .collect::<Vec<_>>();
+        for name in &names {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        for name in &names {
+            let (event, w) = executor.next_event(watcher);
+            watcher = w;
+            match event {
+                WatcherEvent::Notified(e) => assert_eq!(e.name, Some(PathBuf::from(name))),
+                _ => panic!("Unexpected event: {:?}", event),
+            }
+        }
+    }
+
+    fn push_raw_event(buf: &mut Vec<u8>, mask: EventMask, name: &str, len: u32) {
+        let header = inotify_sys::inotify_event {
+            wd: 1,
+            mask: mask.bits(),
+            cookie: 0,
+            len,
+        };
+        let header = unsafe {
+            ::std::slice::from_raw_parts(
+                &header as *const _ as *const u8,
+                mem::size_of::<inotify_sys::inotify_event>(),
+            )
+        };
+        buf.extend_from_slice(header);
+        let mut slot = vec![0; len as usize];
+        slot[..name.len()].copy_from_slice(name.as_bytes());
+        buf.extend_from_slice(&slot);
+    }
+
+    #[cfg(feature = "raw-events")]
+    #[test]
+    fn raw_works() {
+        let mut buf = Vec::new();
+        push_raw_event(&mut buf, EventMask::CREATE, "foo", 16);
+
+        let mut events = VecDeque::new();
+        parse_events(&buf, &mut events).unwrap();
+        let raw = events[0].raw().unwrap();
+        assert_eq!(raw.wd, 1);
+        assert_eq!(raw.mask, EventMask::CREATE.bits());
+        assert_eq!(raw.len, 16);
+        assert_eq!(raw.name, b"foo\0\0\0\0\0\0\0\0\0\0\0\0\0");
+
+        assert_eq!(event(EventMask::CREATE, Some("foo"), "/tmp").raw(), None);
+
+        // The raw representation is not compared
+        assert_eq!(events[0], event(EventMask::CREATE, Some("foo"), ""));
+    }
+
+    #[test]
+    fn eq_ignores_inode() {
+        let dir = TempDir::new();
+        let mut e = event(EventMask::CREATE, Some("foo"), "/tmp");
+        e.inode = InodeId::from_path(dir.path()).ok();
+        assert_eq!(e, event(EventMask::CREATE, Some("foo"), "/tmp"));
+
+        e.cookie = 1;
+        assert_ne!(e, event(EventMask::CREATE, Some("foo"), "/tmp"));
+    }
+
+    #[test]
+    fn is_rename_part_works() {
+        let mut e = event(EventMask::MOVED_FROM, Some("foo"), "/tmp");
+        assert!(!e.is_rename_part());
+
+        e.cookie = 10;
+        assert!(e.is_rename_part());
+
+        e.mask = EventMask::MOVED_TO | EventMask::ISDIR;
+        assert!(e.is_rename_part());
+
+        e.mask = EventMask::CREATE;
+        assert!(!e.is_rename_part());
+    }
+
+    #[test]
+    fn refers_to_watched_path_works() {
+        assert!(!event(EventMask::MODIFY, Some("foo"), "/tmp").refers_to_watched_path());
+        assert!(event(EventMask::MODIFY, None, "/tmp/foo").refers_to_watched_path());
+        assert!(event(EventMask::DELETE_SELF, None, "/tmp").refers_to_watched_path());
+        assert!(event(EventMask::MOVE_SELF, None, "/tmp").refers_to_watched_path());
+        assert!(event(EventMask::IGNORED, None, "/tmp").refers_to_watched_path());
+    }
+}
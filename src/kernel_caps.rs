@@ -0,0 +1,76 @@
+use libc;
+use std::ffi::CStr;
+use std::mem;
+
+/// The inotify features available on the running kernel.
+///
+/// This is created by calling `kernel_capabilities` function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelCaps {
+    /// The version of the running kernel (major, minor, patch).
+    ///
+    /// `None` if the version could not be determined.
+    /// In that case, all the features below are reported as unavailable.
+    pub version: Option<(u32, u32, u32)>,
+
+    /// Whether `WatchMask::EXCL_UNLINK` is supported (Linux 2.6.36+).
+    pub excl_unlink: bool,
+
+    /// Whether `WatchOptions::mask_create` (i.e., `IN_MASK_CREATE`) is supported (Linux 4.18+).
+    pub mask_create: bool,
+}
+
+/// Detects the inotify features available on the running kernel.
+///
+/// The detection is based on the kernel version reported by `uname(2)`,
+/// because older kernels silently ignore the unknown bits of a watch mask
+/// (thus the features cannot be probed by adding a watch).
+pub fn kernel_capabilities() -> KernelCaps {
+    let version = kernel_version();
+    let since = |required| version.is_some_and(|version| version >= required);
+    KernelCaps {
+        version,
+        excl_unlink: since((2, 6, 36)),
+        mask_create: since((4, 18, 0)),
+    }
+}
+
+fn kernel_version() -> Option<(u32, u32, u32)> {
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+    parse_release(release.to_str().ok()?)
+}
+
+fn parse_release(release: &str) -> Option<(u32, u32, u32)> {
+    // e.g., "5.15.0-91-generic"
+    let mut numbers = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse().ok());
+    let major = numbers.next()??;
+    let minor = numbers.next()??;
+    let patch = numbers.next().and_then(|n| n).unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_release_works() {
+        assert_eq!(parse_release("5.15.0-91-generic"), Some((5, 15, 0)));
+        assert_eq!(parse_release("2.6.32"), Some((2, 6, 32)));
+        assert_eq!(parse_release("4.18"), Some((4, 18, 0)));
+        assert_eq!(parse_release("foo"), None);
+    }
+
+    #[test]
+    fn kernel_capabilities_works() {
+        let caps = kernel_capabilities();
+        assert!(caps.version.is_some());
+        assert!(caps.excl_unlink);
+    }
+}
@@ -17,11 +17,8 @@
 //! use futures::{Future, Stream};
 //!
 //! # fn main() {
-//! let inotify_service = InotifyService::new();
-//! let inotify_handle = inotify_service.handle();
-//!
 //! let mut executor = InPlaceExecutor::new().unwrap();
-//! executor.spawn(inotify_service.map_err(|e| panic!("{}", e)));
+//! let inotify_handle = InotifyService::new().start(&executor.handle());
 //!
 //! executor.spawn(
 //!    inotify_handle
@@ -36,6 +33,8 @@
 #![warn(missing_docs)]
 extern crate fibers;
 extern crate futures;
+#[cfg(feature = "compat")]
+extern crate futures03;
 extern crate inotify;
 extern crate inotify_sys;
 extern crate libc;
@@ -46,16 +45,69 @@ extern crate trackable;
 #[doc(no_inline)]
 pub use inotify::{EventMask, WatchMask};
 
+pub use background_service::BackgroundService;
+pub use clock::{Clock, FibersClock, FibersTimer};
+pub use close_write_watcher::CloseWriteWatcher;
+pub use config_watcher::ConfigWatcher;
+pub use decoded_event::DecodedEvent;
+pub use empty_dir_watcher::EmptyDirWatcher;
 pub use error::{Error, ErrorKind};
-pub use internal_inotify::InotifyEvent;
-pub use service::{InotifyService, InotifyServiceHandle};
-pub use watcher::{Watcher, WatcherEvent};
+pub use event_type::EventType;
+pub use eventual_watcher::EventualWatcher;
+pub use fs_op::{FsOp, IntoFsOps};
+#[cfg(feature = "raw-events")]
+pub use internal_inotify::RawEvent;
+pub use internal_inotify::{InodeId, InotifyEvent};
+pub use kernel_caps::{kernel_capabilities, KernelCaps};
+pub use limits::{inotify_limits, InotifyLimits};
+pub use observer::{InstanceReason, Observer};
+pub use placement_strategy::PlacementStrategy;
+pub use resolved_watcher::ResolvedWatcher;
+pub use service::{InotifyService, InotifyServiceBuilder, InotifyServiceHandle, Reply};
+pub use standalone_watcher::StandaloneWatcher;
+pub use stats::{DropStats, ServiceStats, WatcherStats};
+pub use tree_watcher::{TreeEntry, TreeEvent, TreeWatcher};
+pub use wait_for::WaitFor;
+pub use watch_mask_ext::WatchMaskExt;
+pub use watch_options::{DisconnectPolicy, OverflowPolicy, WatchOptions};
+pub use watcher::{HoldGuard, StdReceiver, Tagged, Watcher, WatcherEvent, WatcherId};
+pub use watcher_ext::{
+    ByPath, FilterPath, FollowPath, HeartbeatItem, IdleTimeout, MaskEvents, PathEvents, RelativeTo,
+    SplitByMask, Touched, TrackInodes, WatcherExt, WithHeartbeat,
+};
 
+mod background_service;
+mod clock;
+mod close_write_watcher;
+#[cfg(feature = "compat")]
+mod compat;
+mod config_watcher;
+mod decoded_event;
+mod empty_dir_watcher;
 mod error;
+mod event_channel;
+mod event_type;
+mod eventual_watcher;
+mod fs_op;
 mod internal_inotify;
+mod kernel_caps;
+mod limits;
 mod mio_ext;
+mod observer;
+mod placement_strategy;
+mod rename_tracker;
+mod resolved_watcher;
 mod service;
+mod standalone_watcher;
+mod stats;
+#[cfg(test)]
+mod test_util;
+mod tree_watcher;
+mod wait_for;
+mod watch_mask_ext;
+mod watch_options;
 mod watcher;
+mod watcher_ext;
 
 /// This crate specific `Result` type.
 pub type Result<T> = std::result::Result<T, Error>;
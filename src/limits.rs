@@ -0,0 +1,62 @@
+use std::fs;
+use std::path::Path;
+use trackable::error::ErrorKindExt;
+
+use {Error, ErrorKind, Result};
+
+/// The system-wide limits of inotify.
+///
+/// This is created by calling `inotify_limits` function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InotifyLimits {
+    /// The upper limit on the number of watches that can be created per real user ID.
+    ///
+    /// This is read from `/proc/sys/fs/inotify/max_user_watches`.
+    pub max_user_watches: u64,
+
+    /// The upper limit on the number of inotify instances that can be created per real user ID.
+    ///
+    /// This is read from `/proc/sys/fs/inotify/max_user_instances`.
+    pub max_user_instances: u64,
+
+    /// The upper limit on the number of events that can be queued to an inotify instance.
+    ///
+    /// If the limit is exceeded, the events are discarded and `EventMask::Q_OVERFLOW` is notified.
+    ///
+    /// This is read from `/proc/sys/fs/inotify/max_queued_events`.
+    pub max_queued_events: u64,
+}
+
+/// Reads the current inotify limits from `/proc/sys/fs/inotify/`.
+pub fn inotify_limits() -> Result<InotifyLimits> {
+    let dir = Path::new("/proc/sys/fs/inotify/");
+    Ok(InotifyLimits {
+        max_user_watches: track!(read_limit(&dir.join("max_user_watches")))?,
+        max_user_instances: track!(read_limit(&dir.join("max_user_instances")))?,
+        max_queued_events: track!(read_limit(&dir.join("max_queued_events")))?,
+    })
+}
+
+fn read_limit(path: &Path) -> Result<u64> {
+    let content = fs::read_to_string(path).map_err(Error::from);
+    let content = track!(content, "path={:?}", path)?;
+    let limit = content
+        .trim()
+        .parse()
+        .map_err(|e| Error::from(ErrorKind::Other.cause(e)));
+    let limit = track!(limit, "path={:?}, content={:?}", path, content)?;
+    Ok(limit)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inotify_limits_works() {
+        let limits = inotify_limits().unwrap();
+        assert!(limits.max_user_watches > 0);
+        assert!(limits.max_user_instances > 0);
+        assert!(limits.max_queued_events > 0);
+    }
+}
@@ -1,10 +1,22 @@
+use libc;
 use std::io::Result;
 use std::os::unix::io::RawFd;
 use mio::{Evented, Poll, PollOpt, Ready, Token};
 use mio::unix::EventedFd;
 
+/// An evented file descriptor which will be closed when dropped.
+///
+/// Because the poller drops registered objects after deregistering them,
+/// the descriptor is guaranteed to be closed after the deregistration.
 #[derive(Debug)]
 pub struct OwnedEventedFd(pub RawFd);
+impl Drop for OwnedEventedFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
 impl Evented for OwnedEventedFd {
     fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
         EventedFd(&self.0).register(poll, token, interest, opts)
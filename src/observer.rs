@@ -0,0 +1,145 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use {InotifyEvent, WatcherId};
+
+/// Observer of the internal state of `InotifyService`.
+///
+/// This is useful to export the metrics of the service (e.g., as Prometheus gauges)
+/// without issuing queries to the service.
+///
+/// The methods are invoked on the fiber running the service, so they should return quickly.
+/// All of them have default implementations that do nothing.
+///
+/// An observer can be set by `InotifyServiceBuilder::observer` method.
+pub trait Observer: Send + Sync + 'static {
+    /// Called when the number of the inotify instances managed by the service has changed.
+    fn instance_count_changed(&self, _count: usize) {}
+
+    /// Called when the service has created a new inotify instance (i.e., file descriptor).
+    ///
+    /// `index` is the index of the instance (see `WatcherStats::inotify_index`),
+    /// and `reason` tells why the instance was needed.
+    fn instance_created(&self, _index: usize, _reason: InstanceReason) {}
+
+    /// Called when the service has closed the inotify instance at `index`.
+    ///
+    /// This is called when the last watcher of the instance is removed,
+    /// when a dead instance is replaced (see `InstanceReason::Recovery`),
+    /// and when the service is dropped.
+    fn instance_destroyed(&self, _index: usize) {}
+
+    /// Called when the number of the watchers registered to the service has changed.
+    fn watcher_count_changed(&self, _count: usize) {}
+
+    /// Called when an event for the watcher `id` is dropped because the event channel of
+    /// the watcher is full (see `WatchOptions::channel_capacity`).
+    ///
+    /// `dropped` is the cumulative number of the events dropped for the watcher.
+    fn watcher_lagged(&self, _id: WatcherId, _dropped: u64) {}
+
+    /// Called when the service finds that the `Watcher` of `id` has been dropped
+    /// before the service processed its deregistration
+    /// (i.e., when the service fails to deliver an event to the watcher).
+    ///
+    /// This is called only for the watchers whose `WatchOptions::disconnect_policy` is
+    /// `DisconnectPolicy::Report` or `DisconnectPolicy::Fatal`, and only once per watcher
+    /// (the watcher is deregistered by the service right after that).
+    /// `path` is the path watched by the watcher.
+    fn watcher_disconnected(&self, _id: WatcherId, _path: &Path) {}
+
+    /// Called right after the kernel watch of the watcher `id` has been removed
+    /// (i.e., `inotify_rm_watch` has succeeded).
+    ///
+    /// `path` is the path watched by the watcher and `wd` is the watch descriptor.
+    ///
+    /// Note that this is not called when the watch is removed by the kernel
+    /// (i.e., `EventMask::IGNORED`), nor when the watcher is dropped while
+    /// the kernel watch is still shared with other watchers
+    /// (see `InotifyServiceBuilder::merge_masks`).
+    fn watch_removed(&self, _id: WatcherId, _path: &Path, _wd: i32) {}
+
+    /// Called when the mask of the watcher `id` contains bits which are not meaningful
+    /// in a watch request (e.g., `IN_IGNORED` or `IN_Q_OVERFLOW`).
+    ///
+    /// `bits` are the offending bits. They are stripped before the mask is passed to
+    /// the kernel, and the watcher is registered as usual.
+    fn mask_stripped(&self, _id: WatcherId, _path: &Path, _bits: u32) {}
+
+    /// Called when the read buffer of the `index`-th inotify instance has been enlarged
+    /// to `size` bytes because the kernel queue of the instance has overflowed
+    /// (see `InotifyServiceBuilder::max_read_buffer_size`).
+    fn read_buffer_grown(&self, _index: usize, _size: usize) {}
+
+    /// Called when an inotify event (i.e., `WatcherEvent::Notified` or `WatcherEvent::Batch`)
+    /// is handed out by `Watcher` of `id`.
+    ///
+    /// `queued` is the duration for which the event had been queued in the event channel of
+    /// the watcher (i.e., since the service read it from the inotify instance).
+    /// This is useful to see whether the consumer of the watcher keeps up with the events.
+    ///
+    /// Unlike the other methods, this is invoked on the fiber polling the watcher.
+    fn event_delivered(&self, _id: WatcherId, _queued: Duration) {}
+
+    /// Called when an event is read from an inotify instance.
+    ///
+    /// At this point, `InotifyEvent::watch_path` of the event has not been set yet.
+    fn raw_event(&self, _event: &InotifyEvent) {}
+
+    /// Called when the service has issued a system call.
+    ///
+    /// `name` is the name of the system call (e.g., `"inotify_add_watch"`),
+    /// `args` is a human readable representation of its arguments, and
+    /// `result` is the return value or the error.
+    ///
+    /// Note that `args` is formatted lazily, thus this hook is cheap unless `args` is used.
+    fn syscall(&self, _name: &str, _args: fmt::Arguments, _result: &io::Result<i64>) {}
+}
+
+/// The reason why an inotify instance was created (see `Observer::instance_created`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InstanceReason {
+    /// The service had no instances when a watcher was added.
+    FirstWatcher,
+
+    /// A watcher was kicked out of its instance by a newer watcher of the same inode
+    /// (see `InotifyServiceHandle::watch`).
+    Collision,
+
+    /// The placement strategy put a watcher on a new instance (see `PlacementStrategy`).
+    Placement,
+
+    /// The instance had become invalid and was re-created in place.
+    Recovery,
+}
+
+#[derive(Debug)]
+struct NoopObserver;
+impl Observer for NoopObserver {}
+
+#[derive(Clone)]
+pub(crate) struct SharedObserver(Arc<dyn Observer>);
+impl SharedObserver {
+    pub fn new<O: Observer>(observer: O) -> Self {
+        SharedObserver(Arc::new(observer))
+    }
+}
+impl ::std::ops::Deref for SharedObserver {
+    type Target = dyn Observer;
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+impl Default for SharedObserver {
+    fn default() -> Self {
+        Self::new(NoopObserver)
+    }
+}
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedObserver(_)")
+    }
+}
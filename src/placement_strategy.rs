@@ -0,0 +1,42 @@
+/// Strategy for distributing watchers across inotify instances.
+///
+/// Each inotify instance consumes a file descriptor, and has its own event queue
+/// whose length is limited by `/proc/sys/fs/inotify/max_queued_events`.
+/// Thus the strategy trades the number of file descriptors against the queue pressure of
+/// the instances.
+///
+/// Regardless of the strategy, if the inode of a new watcher has already been watched in
+/// the selected instance, the existing watcher is kicked out to the next instance
+/// (see `InotifyServiceHandle::watch`).
+///
+/// This can be set by `InotifyServiceBuilder::placement_strategy` method.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PlacementStrategy {
+    /// Places all the watchers in the first instance, and creates new instances only if
+    /// collisions force a split.
+    ///
+    /// This minimizes the number of file descriptors, but all of the events share
+    /// a single queue in most cases.
+    /// In particular, any number of watchers of distinct inodes are placed in
+    /// a single instance (i.e., a single file descriptor).
+    ///
+    /// This is the default strategy.
+    #[default]
+    Compact,
+
+    /// Spreads the watchers evenly across up to the given number of instances.
+    ///
+    /// A new watcher is placed in the instance that has the fewest watches.
+    /// This reduces the risk of `EventMask::Q_OVERFLOW` under heavy load at the cost of
+    /// (a fixed number of) extra file descriptors.
+    Spread(usize),
+
+    /// Places each watcher in a dedicated instance.
+    ///
+    /// This isolates the watchers from each other (e.g., a queue overflow caused by
+    /// a noisy directory does not affect the other watchers),
+    /// but consumes a file descriptor per watcher.
+    /// Note that the number of instances is limited by
+    /// `/proc/sys/fs/inotify/max_user_instances` (`128` by default on many systems).
+    Dedicated,
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use internal_inotify::InotifyEvent;
+use watcher::WatcherId;
+use EventMask;
+
+/// The default duration for which the halves of renames are kept.
+pub const DEFAULT_RENAME_EXPIRY: Duration = Duration::from_millis(100);
+
+/// Tracker which pairs up `MOVED_FROM` and `MOVED_TO` events sharing the same cookie.
+///
+/// Because the cookie is shared by all inotify instances,
+/// the halves of a rename observed by different watchers (and instances) can be paired up.
+#[derive(Debug)]
+pub struct RenameTracker {
+    renames: HashMap<u32, Rename>,
+    expiry: Duration,
+}
+impl RenameTracker {
+    pub fn new(expiry: Duration) -> Self {
+        RenameTracker {
+            renames: HashMap::new(),
+            expiry,
+        }
+    }
+
+    /// Records a half of a rename observed by the watcher.
+    ///
+    /// If the rename has been completed (i.e., both halves have been observed),
+    /// this returns the paths of the rename and the interested watchers which have not
+    /// been notified of it yet.
+    pub fn track(
+        &mut self,
+        watcher_id: WatcherId,
+        is_interested: bool,
+        event: &InotifyEvent,
+    ) -> Option<RenameNotification> {
+        let rename = self.renames.entry(event.cookie).or_insert_with(|| Rename {
+            from_path: None,
+            to_path: None,
+            watchers: Vec::new(),
+            notified: Vec::new(),
+            since: Instant::now(),
+        });
+        if event.mask.contains(EventMask::MOVED_FROM) {
+            rename.from_path = Some(event.path());
+        } else {
+            rename.to_path = Some(event.path());
+        }
+        if is_interested && !rename.watchers.contains(&watcher_id) {
+            rename.watchers.push(watcher_id);
+        }
+
+        if let (Some(from), Some(to)) = (rename.from_path.clone(), rename.to_path.clone()) {
+            // The entry is kept until it expires,
+            // so that other watchers observing the same halves can be notified.
+            let watchers = rename
+                .watchers
+                .iter()
+                .filter(|w| !rename.notified.contains(w))
+                .cloned()
+                .collect::<Vec<_>>();
+            rename.notified.extend_from_slice(&watchers);
+            Some((watchers, Some(from), Some(to)))
+        } else {
+            None
+        }
+    }
+
+    /// Removes the expired entries.
+    ///
+    /// The renames of which only one half has been observed (i.e., the files moved into or
+    /// out of the watched directories) are returned with the interested watchers.
+    pub fn expire(&mut self) -> Vec<RenameNotification> {
+        let expiry = self.expiry;
+        let mut expired = Vec::new();
+        self.renames.retain(|_, r| {
+            if r.since.elapsed() < expiry {
+                return true;
+            }
+            let is_completed = r.from_path.is_some() && r.to_path.is_some();
+            if !is_completed && !r.watchers.is_empty() {
+                let watchers = r.watchers.split_off(0);
+                expired.push((watchers, r.from_path.take(), r.to_path.take()));
+            }
+            false
+        });
+        expired
+    }
+
+    /// Returns the duration until the earliest entry expires.
+    pub fn next_expiry(&self) -> Option<Duration> {
+        self.renames
+            .values()
+            .map(|r| {
+                self.expiry
+                    .checked_sub(r.since.elapsed())
+                    .unwrap_or_default()
+            })
+            .min()
+    }
+}
+
+/// The interested watchers, and the source and destination paths of a rename.
+///
+/// `None` means that the side has not been observed by the service.
+pub type RenameNotification = (Vec<WatcherId>, Option<PathBuf>, Option<PathBuf>);
+
+#[derive(Debug)]
+struct Rename {
+    from_path: Option<PathBuf>,
+    to_path: Option<PathBuf>,
+    watchers: Vec<WatcherId>,
+    notified: Vec<WatcherId>,
+    since: Instant,
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+    use test_util::event;
+
+    #[test]
+    fn rename_tracker_works() {
+        let mut tracker = RenameTracker::new(Duration::from_millis(10));
+
+        let mut from = event(EventMask::MOVED_FROM, Some("foo"), "/a");
+        from.cookie = 1;
+        let mut to = event(EventMask::MOVED_TO, Some("bar"), "/b");
+        to.cookie = 1;
+        assert_eq!(tracker.track(0, true, &from), None);
+        assert_eq!(
+            tracker.track(1, true, &to),
+            Some((
+                vec![0, 1],
+                Some(PathBuf::from("/a/foo")),
+                Some(PathBuf::from("/b/bar"))
+            ))
+        );
+        assert!(tracker.next_expiry().is_some());
+
+        // Expired
+        from.cookie = 2;
+        to.cookie = 2;
+        assert_eq!(tracker.track(0, true, &from), None);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            tracker.expire(),
+            [(vec![0], Some(PathBuf::from("/a/foo")), None)]
+        );
+        assert_eq!(tracker.next_expiry(), None);
+        assert_eq!(tracker.track(1, true, &to), None);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            tracker.expire(),
+            [(vec![1], None, Some(PathBuf::from("/b/bar")))]
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use futures::{Async, Poll, Stream};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use {Error, EventMask, InotifyServiceHandle, Result, WatchMask, Watcher, WatcherEvent};
+
+/// A watcher which watches the physical path resolved from a logical path.
+///
+/// The resolver is called to get the path to be watched when this stream is polled
+/// for the first time, and again each time the watch is removed by the kernel
+/// (i.e., `EventMask::IGNORED` is notified), because the mapping from the logical path
+/// may have been changed.
+/// The watcher re-added after the re-resolution produces `WatcherEvent::RestartWatching`
+/// instead of `WatcherEvent::StartWatching`.
+///
+/// If the resolver fails, the stream produces the error and terminates.
+///
+/// This is created by calling `InotifyServiceHandle::watch_resolved` method.
+pub struct ResolvedWatcher<F> {
+    service: InotifyServiceHandle,
+    logical: String,
+    resolver: F,
+    mask: WatchMask,
+    watcher: Option<Watcher>,
+    is_rewatched: bool,
+}
+impl<F> ResolvedWatcher<F>
+where
+    F: Fn(&str) -> Result<PathBuf>,
+{
+    pub(crate) fn new(
+        service: InotifyServiceHandle,
+        logical: String,
+        resolver: F,
+        mask: WatchMask,
+    ) -> Self {
+        ResolvedWatcher {
+            service,
+            logical,
+            resolver,
+            mask,
+            watcher: None,
+            is_rewatched: false,
+        }
+    }
+
+    /// Returns the logical path given to `InotifyServiceHandle::watch_resolved`.
+    pub fn logical_path(&self) -> &str {
+        &self.logical
+    }
+
+    /// Returns the physical path currently being watched.
+    ///
+    /// `None` if the logical path has not been resolved yet (or is being re-resolved).
+    pub fn path(&self) -> Option<&Path> {
+        self.watcher.as_ref().map(|w| w.path())
+    }
+}
+impl<F> Stream for ResolvedWatcher<F>
+where
+    F: Fn(&str) -> Result<PathBuf>,
+{
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.watcher.is_none() {
+            let path = track!((self.resolver)(&self.logical), "logical={:?}", self.logical)?;
+            self.watcher = Some(self.service.watch(path, self.mask));
+        }
+        let polled = track!(self.watcher.as_mut().expect("Never fails").poll())?;
+        match polled {
+            Async::Ready(Some(WatcherEvent::StartWatching)) if self.is_rewatched => {
+                self.is_rewatched = false;
+                Ok(Async::Ready(Some(WatcherEvent::RestartWatching)))
+            }
+            Async::Ready(Some(WatcherEvent::Notified(ref e)))
+                if e.mask.contains(EventMask::IGNORED) =>
+            {
+                // Resolved again by the next call
+                self.watcher = None;
+                self.is_rewatched = true;
+                Ok(polled)
+            }
+            _ => Ok(polled),
+        }
+    }
+}
+impl<F> fmt::Debug for ResolvedWatcher<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResolvedWatcher")
+            .field("logical", &self.logical)
+            .field("mask", &self.mask)
+            .field("watcher", &self.watcher)
+            .field("is_rewatched", &self.is_rewatched)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Stream;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use trackable::error::ErrorKindExt;
+
+    use super::*;
+    use test_util::{TempDir, TestExecutor};
+    use ErrorKind;
+
+    #[test]
+    fn resolved_watcher_works() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("v1")).unwrap();
+        fs::create_dir(dir.join("v2")).unwrap();
+        let mut executor = TestExecutor::new();
+
+        let current = Arc::new(Mutex::new(dir.join("v1")));
+        let mapping = Arc::clone(&current);
+        let resolver = move |logical: &str| {
+            assert_eq!(logical, "current");
+            Ok(mapping.lock().unwrap().clone())
+        };
+        let logical = "current".to_owned();
+        let watcher = executor
+            .handle
+            .watch_resolved(logical, resolver, WatchMask::CREATE);
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        assert_eq!(watcher.path(), Some(dir.join("v1").as_path()));
+
+        // The mapping is switched, and the old target is removed
+        *current.lock().unwrap() = dir.join("v2");
+        fs::remove_dir(dir.join("v1")).unwrap();
+        let (event, watcher) = next_event(&mut executor, watcher);
+        match event {
+            WatcherEvent::Notified(e) => assert!(e.mask.contains(EventMask::IGNORED)),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+        assert_eq!(watcher.path(), Some(dir.join("v2").as_path()));
+
+        fs::write(dir.join("v2/foo"), b"").unwrap();
+        let (event, _) = next_event(&mut executor, watcher);
+        match event {
+            WatcherEvent::Notified(e) => assert_eq!(e.path(), dir.join("v2/foo")),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn resolution_error_works() {
+        let mut executor = TestExecutor::new();
+        let resolver = |_: &str| Err(ErrorKind::InvalidInput.error().into());
+        let watcher = executor
+            .handle
+            .watch_resolved("foo".to_owned(), resolver, WatchMask::CREATE);
+        let error = executor.wait(watcher.into_future()).err().unwrap().0;
+        assert_eq!(*error.kind(), ErrorKind::InvalidInput);
+    }
+
+    fn next_event<F>(
+        executor: &mut TestExecutor,
+        watcher: ResolvedWatcher<F>,
+    ) -> (WatcherEvent, ResolvedWatcher<F>)
+    where
+        F: Fn(&str) -> Result<PathBuf>,
+    {
+        let (event, watcher) = executor.wait(watcher.into_future()).ok().unwrap();
+        (event.expect("Unexpected end of stream"), watcher)
+    }
+}
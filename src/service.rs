@@ -1,17 +1,45 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use fibers::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use fibers::time::timer::{self, Timeout};
+use fibers::sync::{mpsc, oneshot};
+use fibers::Spawn;
 use futures::{Async, Future, Poll, Stream};
 
-use {Error, ErrorKind, Result, WatchMask, Watcher, WatcherEvent};
-use internal_inotify::{Inotify, WatchDecriptor};
+use inotify_sys;
+use trackable::error::ErrorKindExt;
+
+use {
+    BackgroundService, Clock, CloseWriteWatcher, ConfigWatcher, DisconnectPolicy, DropStats,
+    EmptyDirWatcher, Error, ErrorKind, EventMask, EventualWatcher, FibersClock, InodeId,
+    OverflowPolicy, PlacementStrategy, ResolvedWatcher, Result, ServiceStats, TreeWatcher, WaitFor,
+    WatchMask, WatchOptions, Watcher, WatcherEvent, WatcherStats,
+};
+use event_channel::{self, EventSender, SendError};
+use internal_inotify::{self, Inotify, InotifyEvent, WatchDecriptor};
+use kernel_caps;
+use observer::{InstanceReason, Observer, SharedObserver};
+use rename_tracker::{self, RenameNotification, RenameTracker};
 use watcher::WatcherId;
 
+// The bits meaningful in a watch request (the others are only reported in events)
+const WATCH_REQUEST_BITS: u32 = inotify_sys::IN_ALL_EVENTS
+    | inotify_sys::IN_DONT_FOLLOW
+    | inotify_sys::IN_EXCL_UNLINK
+    | inotify_sys::IN_MASK_ADD
+    | inotify_sys::IN_MASK_CREATE
+    | inotify_sys::IN_ONESHOT
+    | inotify_sys::IN_ONLYDIR;
+
 /// [Inotify] service.
 ///
-/// This is a [`Future`] that never terminate except error cases.
+/// This is a [`Future`] that never terminate except error cases
+/// or the case where `InotifyServiceHandle::shutdown` is called.
 /// Internally it manages zero or more file descriptors of [inotify] as needed.
 ///
 /// [inotify]: https://en.wikipedia.org/wiki/Inotify
@@ -19,22 +47,44 @@ use watcher::WatcherId;
 #[derive(Debug)]
 pub struct InotifyService {
     inotifies: Vec<InotifyState>,
-    command_tx: mpsc::Sender<Command>,
+    command_tx: CommandSender,
     command_rx: mpsc::Receiver<Command>,
     watcher_id: Arc<AtomicUsize>,
+    is_alive: Arc<AtomicBool>,
+    error: Arc<Mutex<Option<Error>>>,
     watchers: HashMap<WatcherId, WatcherState>,
+    disconnected_watchers: Vec<WatcherId>,
+    replaced_watchers: Vec<WatcherId>,
+    batched_watchers: Vec<WatcherId>,
+    high_priority_watchers: HashSet<WatcherId>,
+    blocking_watchers: HashSet<WatcherId>,
+    flush_on_shutdown: bool,
+    discard_initial_events: bool,
+    merge_masks: bool,
+    placement_strategy: PlacementStrategy,
+    is_shutdown: bool,
+    observer: SharedObserver,
+    reported_instance_count: usize,
+    reported_watcher_count: usize,
+    rename_tracker: RenameTracker,
+    rename_timer: Option<Timeout>,
+    max_watches: Option<usize>,
+    activity_clock: u64, // Logical clock to order the activities of the watchers
+    case_insensitive_paths: bool,
+    identical_watches: Option<HashMap<(PathBuf, WatchMask), WatcherId>>,
+    replay_buffer_size: usize,
+    max_read_buffer_size: usize,
+    add_watch_retries: usize,
+    retrying_watchers: Vec<RetryingWatcher>,
+    shutdown_signal: ShutdownSignal,
+    untaken_drops: u64, // The drops of the removed watchers (see `take_drop_stats`)
 }
 impl InotifyService {
-    /// Makes a new `InotifyService` instance.
+    /// Makes a new `InotifyService` instance with the default settings.
+    ///
+    /// If you want to customize the settings, please use `InotifyServiceBuilder` instead.
     pub fn new() -> Self {
-        let (command_tx, command_rx) = mpsc::channel();
-        InotifyService {
-            inotifies: Vec::new(),
-            command_tx,
-            command_rx,
-            watcher_id: Arc::new(AtomicUsize::new(0)),
-            watchers: HashMap::new(),
-        }
+        InotifyServiceBuilder::new().finish()
     }
 
     /// Returns the handle of this service.
@@ -42,106 +92,1078 @@ impl InotifyService {
         InotifyServiceHandle {
             command_tx: self.command_tx.clone(),
             watcher_id: Arc::clone(&self.watcher_id),
+            is_alive: Arc::clone(&self.is_alive),
+            error: Arc::clone(&self.error),
+            observer: self.observer.clone(),
         }
     }
 
+    /// Spawns this service on `spawner`, and returns the handle of it.
+    ///
+    /// This is a shorthand of spawning the service by yourself,
+    /// except that an error terminating the service does not panic.
+    /// Instead, `InotifyServiceHandle::is_alive` turns `false` and
+    /// the error can be retrieved via `InotifyServiceHandle::error`.
+    pub fn start<S: Spawn>(self, spawner: &S) -> InotifyServiceHandle {
+        let handle = self.handle();
+        spawner.spawn(self.then(|_| Ok(()))); // The error is kept by `poll` method
+        handle
+    }
+
+    /// Spawns this service on a dedicated thread running its own executor.
+    ///
+    /// See the documentation of `BackgroundService` for details.
+    pub fn spawn_background(self) -> Result<BackgroundService> {
+        track!(BackgroundService::spawn(self))
+    }
+
     fn handle_command(&mut self, command: Command) -> Result<()> {
         match command {
-            Command::RegisterWatcher {
+            Command::RegisterWatcher { watcher } => {
+                track!(self.register_watcher(*watcher, 0))?;
+            }
+            Command::RegisterWatchers { watchers } => {
+                for watcher in watchers {
+                    track!(self.register_watcher(watcher, 0))?;
+                }
+            }
+            Command::DeregisterWatcher { watcher_id } => {
+                track!(self.deregister_watcher(watcher_id))?;
+            }
+            Command::DeregisterWatchers { watcher_ids } => {
+                track!(self.deregister_watchers(watcher_ids))?;
+            }
+            Command::SetHighPriority {
+                watcher_id,
+                enabled,
+            } => {
+                if enabled && self.watchers.contains_key(&watcher_id) {
+                    self.high_priority_watchers.insert(watcher_id);
+                } else {
+                    self.high_priority_watchers.remove(&watcher_id);
+                }
+            }
+            Command::GetStats { reply_tx } => {
+                let stats = ServiceStats {
+                    instance_count: self.inotifies.len(),
+                    instance_events: self.inotifies.iter().map(|i| i.events).collect(),
+                    watchers: self
+                        .watchers
+                        .values()
+                        .filter(|w| w.follower.is_none())
+                        .map(|w| WatcherStats {
+                            id: w.id,
+                            path: w.path.clone(),
+                            mask: w.mask,
+                            wd: w.wd.map(|wd| wd.0),
+                            inotify_index: w.wd.map(|_| w.inotify_index),
+                            dropped: w.dropped,
+                            pending_len: w.event_tx.len(),
+                        })
+                        .collect(),
+                };
+                let _ = reply_tx.send(Ok(stats));
+            }
+            Command::TakeDropStats { reply_tx } => {
+                let mut stats = DropStats {
+                    dropped: self.untaken_drops,
+                    watchers: HashMap::new(),
+                };
+                for w in self.watchers.values_mut() {
+                    if w.follower.is_some() {
+                        continue;
+                    }
+                    let dropped = w.dropped - w.taken_dropped;
+                    w.taken_dropped = w.dropped;
+                    stats.dropped += dropped;
+                    stats.watchers.insert(w.id, dropped);
+                }
+                self.untaken_drops = 0;
+                let _ = reply_tx.send(Ok(stats));
+            }
+            Command::Rewatch {
                 watcher_id,
                 path,
-                mask,
+                reply_tx,
+            } => {
+                let result = track!(self.rewatch(watcher_id, path));
+                let _ = reply_tx.send(result);
+            }
+            Command::Unwatch {
+                path,
+                is_prefix,
+                reply_tx,
+            } => {
+                let result = track!(self.unwatch(&path, is_prefix));
+                let _ = reply_tx.send(result);
+            }
+            Command::GetMask {
+                watcher_id,
+                reply_tx,
+            } => {
+                let result = track!(self.get_watcher(watcher_id)).map(|w| w.mask);
+                let _ = reply_tx.send(result);
+            }
+            Command::Redirect {
+                watcher_id,
                 event_tx,
             } => {
-                let watcher = WatcherState {
-                    id: watcher_id,
-                    inotify_index: 0,
-                    wd: WatchDecriptor(-1), // dummy (updated in `register_watcher()`)
-                    path,
-                    mask,
-                    event_tx,
-                };
-                track!(self.register_watcher(watcher))?;
+                self.redirect(watcher_id, event_tx);
             }
-            Command::DeregisterWatcher { watcher_id } => {
-                track!(self.deregister_watcher(watcher_id))?;
+            Command::Hold {
+                watcher_id,
+                token,
+                limit,
+            } => {
+                if let Some(watcher) = self.find_watcher_mut(watcher_id) {
+                    watcher.holds.push((token, limit));
+                }
+            }
+            Command::Release { watcher_id, token } => {
+                self.release(watcher_id, token);
+            }
+            Command::Shutdown => {
+                self.is_shutdown = true;
             }
         }
         Ok(())
     }
-    fn register_watcher(&mut self, mut watcher: WatcherState) -> Result<()> {
-        track_assert!(!self.watchers.contains_key(&watcher.id), ErrorKind::Other);
-        let is_succeeded = track!(self.add_watch(&mut watcher))?;
-        if is_succeeded {
+    fn register_watcher(&mut self, mut watcher: WatcherState, retry: usize) -> Result<()> {
+        if self.watchers.contains_key(&watcher.id) {
+            // Should never happen, but the other watchers are not affected even if it does
+            let e = Error::from(ErrorKind::Other.cause("Duplicate watcher ID"));
+            let _ = watcher.send(Err(track!(e, "id={}", watcher.id)));
+            return Ok(());
+        }
+        if self.join_identical_watch(&mut watcher) {
             self.watchers.insert(watcher.id, watcher);
+            self.report_counts();
+            return Ok(());
+        }
+        if let Some(max_watches) = self.max_watches {
+            track!(self.evict_watchers(max_watches))?;
+            if self.watch_count() >= max_watches {
+                let e = ErrorKind::Other.cause("No room for a new watch");
+                let e = track!(Error::from(e), "max_watches={}", max_watches);
+                let _ = watcher.send(Err(e));
+                return Ok(());
+            }
+        }
+        let stray_bits = watcher.mask.bits() & !WATCH_REQUEST_BITS;
+        if stray_bits != 0 && retry == 0 {
+            // Stripped by `WatcherState::kernel_mask()`
+            self.observer
+                .mask_stripped(watcher.id, &watcher.path, stray_bits);
+        }
+        watcher.last_active = self.tick();
+        watcher.inotify_index = self.select_instance();
+        let is_retriable = retry < self.add_watch_retries;
+        let is_succeeded = match track!(self.try_add_watch(
+            &mut watcher,
+            InstanceReason::Placement,
+            is_retriable
+        ))? {
+            None => {
+                // Retries later without blocking the service.
+                // The delay is doubled on each retry, starting from 1 millisecond.
+                let timer = timer::timeout(Duration::from_millis(1 << retry.min(16)));
+                self.retrying_watchers.push(RetryingWatcher {
+                    watcher,
+                    retry: retry + 1,
+                    timer,
+                });
+                return Ok(());
+            }
+            Some(is_succeeded) => is_succeeded,
+        };
+        if is_succeeded {
+            if watcher.event_tx.policy() == OverflowPolicy::Block {
+                self.blocking_watchers.insert(watcher.id);
+            }
+            if watcher.options.follow_replacement {
+                track!(self.watch_parent(&mut watcher))?;
+            }
+            let (watcher_id, is_syncing) = (watcher.id, watcher.is_syncing);
+            if let Some(key) = watcher.identical_key.clone() {
+                if let Some(ref mut identical_watches) = self.identical_watches {
+                    identical_watches.insert(key, watcher_id);
+                }
+            }
+            self.watchers.insert(watcher_id, watcher);
+            if is_syncing {
+                track!(self.sync_watcher(watcher_id))?;
+            }
+        }
+        self.report_counts();
+        Ok(())
+    }
+    fn join_identical_watch(&mut self, watcher: &mut WatcherState) -> bool {
+        // See `InotifyServiceBuilder::dedup_identical_watches`
+        let is_joinable = watcher.follower.is_none()
+            && !watcher.options.follow_replacement
+            && !watcher.is_syncing;
+        let identical_watches = match self.identical_watches {
+            Some(ref identical_watches) if is_joinable => identical_watches,
+            _ => return false,
+        };
+        let key = (self.normalize_path(&watcher.path), watcher.mask);
+        let existing = identical_watches
+            .get(&key)
+            .and_then(|id| self.watchers.get(id))
+            .and_then(|w| w.wd.map(|wd| (w.inotify_index, wd)));
+        watcher.identical_key = Some(key);
+        let (i, wd) = match existing {
+            None => return false,
+            Some(x) => x,
+        };
+
+        // Subscribes to the existing kernel watch without issuing a system call
+        match self.inotifies[i].wds.get_mut(&wd) {
+            None => return false,
+            Some(watcher_ids) => watcher_ids.push(watcher.id),
+        }
+        if watcher.event_tx.policy() == OverflowPolicy::Block {
+            self.blocking_watchers.insert(watcher.id);
+        }
+        watcher.inotify_index = i;
+        watcher.wd = Some(wd);
+        watcher.started = true;
+        watcher.last_active = self.tick();
+        let _ = watcher.send(Ok(WatcherEvent::StartWatching));
+        self.replay_history(watcher, i, wd);
+        true
+    }
+    fn sync_watcher(&mut self, watcher_id: WatcherId) -> Result<()> {
+        // Discards the events queued for the new watch so far,
+        // because they are reflected in the baseline scanned below.
+        let i = self.watchers[&watcher_id].inotify_index;
+        track!(self.flush_instance(i))?;
+
+        let watcher = match self.watchers.get_mut(&watcher_id) {
+            None => return Ok(()),
+            Some(watcher) => watcher,
+        };
+        watcher.is_syncing = false;
+
+        // The changes made during (or after) the scan are delivered as subsequent events
+        let item = track!(scan_baseline(&watcher.path)).map(WatcherEvent::Baseline);
+        let _ = watcher.send(item);
+        Ok(())
+    }
+    fn evict_watchers(&mut self, max_watches: usize) -> Result<()> {
+        // Evicts the least recently active watchers until there is room for a new watch
+        while self.watch_count() >= max_watches {
+            let lru = self
+                .watchers
+                .values()
+                .filter(|w| w.follower.is_none())
+                .min_by_key(|w| w.last_active)
+                .map(|w| w.id);
+            let watcher_id = match lru {
+                None => break,
+                Some(watcher_id) => watcher_id,
+            };
+            if let Some(watcher) = self.watchers.get_mut(&watcher_id) {
+                let _ = watcher.flush_batch();
+                let _ = watcher.send(Ok(WatcherEvent::Evicted));
+            }
+            track!(self.remove_watcher(watcher_id))?;
+        }
+        self.shrink_instances();
+        Ok(())
+    }
+    fn watch_count(&self) -> usize {
+        self.inotifies.iter().map(|x| x.wds.len()).sum()
+    }
+    fn tick(&mut self) -> u64 {
+        self.activity_clock += 1;
+        self.activity_clock
+    }
+    fn watch_parent(&mut self, watcher: &mut WatcherState) -> Result<()> {
+        let parent = match watcher.path.parent() {
+            None => return Ok(()), // The root directory is never replaced
+            Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+            Some(parent) => parent,
+        };
+        let mut parent_watcher = WatcherState {
+            id: self.watcher_id.fetch_add(1, Ordering::SeqCst),
+            inotify_index: self.select_instance(),
+            wd: None,
+            started: false,
+            path: parent.to_path_buf(),
+            mask: WatchMask::CREATE | WatchMask::MOVED_TO | WatchMask::ONLYDIR,
+            options: WatchOptions {
+                shared: true,
+                ..Default::default()
+            },
+            event_tx: watcher.event_tx.clone(), // Used only to report errors
+            dropped: 0,
+            taken_dropped: 0,
+            batch: Vec::new(),
+            last_active: 0,
+            is_syncing: false,
+            identical_key: None,
+            names: None,
+            holds: Vec::new(),
+            held: VecDeque::new(),
+            parent: None,
+            follower: Some(watcher.id),
+        };
+        if track!(self.add_watch(&mut parent_watcher, InstanceReason::Placement))? {
+            watcher.parent = Some(parent_watcher.id);
+            self.watchers.insert(parent_watcher.id, parent_watcher);
         }
         Ok(())
     }
     fn deregister_watcher(&mut self, watcher_id: WatcherId) -> Result<()> {
-        if let Some(watcher) = self.watchers.remove(&watcher_id) {
-            let mut i = watcher.inotify_index;
-            track!(self.inotifies[i].inotify.remove_watch(watcher.wd))?;
-            track_assert_some!(self.inotifies[i].wds.remove(&watcher.wd), ErrorKind::Other);
-            while i + 1 == self.inotifies.len() && self.inotifies[i].wds.is_empty() {
-                self.inotifies.pop();
-                i -= 1;
+        track!(self.remove_watcher(watcher_id))?;
+        self.shrink_instances();
+        self.report_counts();
+        Ok(())
+    }
+    fn deregister_watchers(&mut self, watcher_ids: Vec<WatcherId>) -> Result<()> {
+        for watcher_id in watcher_ids {
+            track!(self.remove_watcher(watcher_id))?;
+        }
+        self.shrink_instances();
+        self.report_counts();
+        Ok(())
+    }
+    fn remove_watcher(&mut self, watcher_id: WatcherId) -> Result<()> {
+        self.high_priority_watchers.remove(&watcher_id);
+        self.blocking_watchers.remove(&watcher_id);
+        self.retrying_watchers
+            .retain(|r| r.watcher.id != watcher_id);
+        if let Some(mut watcher) = self.watchers.remove(&watcher_id) {
+            // The held items are delivered before the stream terminates
+            let _ = watcher.release_held();
+            self.untaken_drops += watcher.dropped - watcher.taken_dropped;
+            if let Some(ref key) = watcher.identical_key {
+                self.forget_identical_watch(key, watcher_id);
+            }
+            if let Some(wd) = watcher.wd {
+                let i = watcher.inotify_index;
+                track!(self.release_wd(i, wd, watcher_id, &watcher.path))?;
+            }
+            if let Some(parent_id) = watcher.parent {
+                track!(self.remove_watcher(parent_id))?;
+            }
+        }
+        Ok(())
+    }
+    fn normalize_path(&self, path: &Path) -> PathBuf {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        if self.case_insensitive_paths {
+            PathBuf::from(path.to_string_lossy().to_lowercase())
+        } else {
+            path
+        }
+    }
+    fn forget_identical_watch(&mut self, key: &(PathBuf, WatchMask), watcher_id: WatcherId) {
+        if let Some(ref mut identical_watches) = self.identical_watches {
+            if identical_watches.get(key) == Some(&watcher_id) {
+                identical_watches.remove(key);
             }
         }
+    }
+    fn release_wd(
+        &mut self,
+        inotify_index: usize,
+        wd: WatchDecriptor,
+        watcher_id: WatcherId,
+        path: &Path,
+    ) -> Result<()> {
+        let inotify = &mut self.inotifies[inotify_index];
+        let remaining_ids = if let Some(watcher_ids) = inotify.wds.get_mut(&wd) {
+            watcher_ids.retain(|&id| id != watcher_id);
+            watcher_ids.clone()
+        } else {
+            // The mapping has gone, so the watch has already been removed by the kernel
+            // (i.e., `EventMask::IGNORED` has been notified).
+            return Ok(());
+        };
+        if remaining_ids.is_empty() {
+            // Removes the kernel watch only if no other watchers share it
+            inotify.wds.remove(&wd);
+            track!(inotify.inotify.remove_watch(wd))?;
+            self.observer.watch_removed(watcher_id, path, wd.0);
+        } else {
+            self.notify_subscriber_left(&remaining_ids);
+        }
         Ok(())
     }
-    fn add_watch(&mut self, watcher: &mut WatcherState) -> Result<bool> {
+    fn notify_subscriber_left(&mut self, watcher_ids: &[WatcherId]) {
+        // See `WatchOptions::notify_subscribers`
+        let remaining = watcher_ids
+            .iter()
+            .filter_map(|id| self.watchers.get(id))
+            .filter(|w| w.follower.is_none())
+            .count();
+        for &watcher_id in watcher_ids {
+            if let Some(watcher) = self.watchers.get_mut(&watcher_id) {
+                if !watcher.options.notify_subscribers || watcher.follower.is_some() {
+                    continue;
+                }
+                let result = watcher.flush_batch();
+                let event = WatcherEvent::SubscriberLeft { remaining };
+                let _ = watcher.send(Ok(event));
+                let dropped = watcher.dropped;
+                self.handle_send_result(watcher_id, dropped, result);
+            }
+        }
+    }
+    fn unwatch(&mut self, path: &Path, is_prefix: bool) -> Result<usize> {
+        let path = self.normalize_path(path);
+        let watcher_ids = self
+            .watchers
+            .values()
+            .filter(|w| {
+                if w.follower.is_some() {
+                    return false;
+                }
+                let watched = self.normalize_path(&w.path);
+                if is_prefix {
+                    watched.starts_with(&path)
+                } else {
+                    watched == path
+                }
+            })
+            .map(|w| w.id)
+            .collect::<Vec<_>>();
+        let count = watcher_ids.len();
+        track!(self.deregister_watchers(watcher_ids))?;
+        Ok(count)
+    }
+    fn redirect(&mut self, watcher_id: WatcherId, event_tx: EventSender) {
+        let watcher = match self.watchers.get_mut(&watcher_id) {
+            None => {
+                let e = Error::from(ErrorKind::InvalidInput.cause("The watcher is not registered"));
+                let _ = event_tx.send(Err(track!(e, "id={}", watcher_id)));
+                return;
+            }
+            Some(watcher) => watcher,
+        };
+
+        // The events batched so far belong to the old sink.
+        // The old sink is closed by dropping the sender, so its stream terminates
+        // after the events queued in it.
+        let _ = watcher.flush_batch();
+        let _ = watcher.release_held();
+        watcher.event_tx = event_tx.clone();
+        if let Some(parent) = watcher.parent.and_then(|id| self.watchers.get_mut(&id)) {
+            parent.event_tx = event_tx;
+        }
+    }
+    fn select_instance(&self) -> usize {
+        let empty = self.inotifies.iter().position(|x| x.wds.is_empty());
+        match self.placement_strategy {
+            PlacementStrategy::Compact => 0,
+            PlacementStrategy::Spread(n) if self.inotifies.len() < n => {
+                empty.unwrap_or(self.inotifies.len())
+            }
+            PlacementStrategy::Spread(_) => (0..self.inotifies.len())
+                .min_by_key(|&i| self.inotifies[i].wds.len())
+                .unwrap_or(0),
+            PlacementStrategy::Dedicated => empty.unwrap_or(self.inotifies.len()),
+        }
+    }
+    fn shrink_instances(&mut self) {
+        while self.inotifies.last().is_some_and(|x| x.wds.is_empty()) {
+            self.inotifies.pop();
+            self.observer.instance_destroyed(self.inotifies.len());
+        }
+    }
+    fn find_watcher_mut(&mut self, watcher_id: WatcherId) -> Option<&mut WatcherState> {
+        // The watcher may be waiting for the retry of its registration
+        if self.watchers.contains_key(&watcher_id) {
+            return self.watchers.get_mut(&watcher_id);
+        }
+        self.retrying_watchers
+            .iter_mut()
+            .map(|r| &mut r.watcher)
+            .find(|w| w.id == watcher_id)
+    }
+    fn release(&mut self, watcher_id: WatcherId, token: usize) {
+        let watcher = match self.find_watcher_mut(watcher_id) {
+            None => return,
+            Some(watcher) => watcher,
+        };
+        watcher.holds.retain(|&(t, _)| t != token);
+        if watcher.holds.is_empty() {
+            let result = watcher.release_held();
+            let dropped = watcher.dropped;
+            self.handle_send_result(watcher_id, dropped, result);
+        }
+    }
+    fn get_watcher(&self, watcher_id: WatcherId) -> Result<&WatcherState> {
+        let watcher = self.watchers.get(&watcher_id);
+        let watcher = track_assert_some!(
+            watcher,
+            ErrorKind::InvalidInput,
+            "The watcher is not registered: id={}",
+            watcher_id
+        );
+        Ok(watcher)
+    }
+    fn report_counts(&mut self) {
+        if self.reported_instance_count != self.inotifies.len() {
+            self.reported_instance_count = self.inotifies.len();
+            self.observer.instance_count_changed(self.inotifies.len());
+        }
+        let watcher_count = self
+            .watchers
+            .values()
+            .filter(|w| w.follower.is_none())
+            .count();
+        if self.reported_watcher_count != watcher_count {
+            self.reported_watcher_count = watcher_count;
+            self.observer.watcher_count_changed(watcher_count);
+        }
+    }
+    fn add_watch(&mut self, watcher: &mut WatcherState, reason: InstanceReason) -> Result<bool> {
+        let is_succeeded = track!(self.try_add_watch(watcher, reason, false))?;
+        Ok(is_succeeded.expect("Never fails"))
+    }
+
+    // Returns `Ok(None)` if `is_retriable` is `true` and the addition has failed
+    // with a transient error (see `Inotify::try_add_watch`).
+    fn try_add_watch(
+        &mut self,
+        watcher: &mut WatcherState,
+        reason: InstanceReason,
+        is_retriable: bool,
+    ) -> Result<Option<bool>> {
         let i = watcher.inotify_index;
-        if i == self.inotifies.len() {
-            self.inotifies.push(track!(InotifyState::new())?);
+        if let Err(e) = track!(self.open_instance(i, reason)) {
+            // E.g., the limit of instances has been reached.
+            // Only the watcher fails, and the service keeps running.
+            let _ = watcher.send(Err(e));
+            return Ok(Some(false));
         }
 
-        let mut mask = watcher.mask;
-        mask.remove(WatchMask::MASK_ADD);
-        let result = track!(self.inotifies[i].inotify.add_watch(&watcher.path, mask));
+        let inotify = &mut self.inotifies[i].inotify;
+        let result = track!(watcher.kernel_mask(self.merge_masks))
+            .and_then(|mask| track!(inotify.try_add_watch(&watcher.path, mask, is_retriable)));
         let wd = match result {
             Err(e) => {
-                let _ = watcher.event_tx.send(Err(e));
-                return Ok(false);
+                let _ = watcher.send(Err(e));
+                return Ok(Some(false));
             }
-            Ok(wd) => wd,
+            Ok(None) => return Ok(None),
+            Ok(Some(wd)) => wd,
         };
+        track!(self.assign_wd(watcher, wd))?;
+        Ok(Some(true))
+    }
+    fn open_instance(&mut self, i: usize, reason: InstanceReason) -> Result<()> {
+        if i == self.inotifies.len() {
+            let inotify = track!(InotifyState::new(self.observer.clone()))?;
+            self.inotifies.push(inotify);
+            let reason = if i == 0 {
+                InstanceReason::FirstWatcher
+            } else {
+                reason
+            };
+            self.observer.instance_created(i, reason);
+        }
+        Ok(())
+    }
+    fn assign_wd(&mut self, watcher: &mut WatcherState, wd: WatchDecriptor) -> Result<()> {
+        let i = watcher.inotify_index;
+        let is_overwriting = self.inotifies[i]
+            .wds
+            .get(&wd)
+            .is_some_and(|ids| ids.iter().any(|&id| id != watcher.id));
+        if is_overwriting {
+            // Delivers the events queued in the instance with the current mapping,
+            // so that the existing watchers receive the events occurred before the modification
+            // (prior to `WatcherEvent::RestartWatching` if they are kicked out).
+            track!(self.flush_instance(i))?;
+        }
+
+        let mut watcher_ids = self.inotifies[i].wds.remove(&wd).unwrap_or_default();
 
-        if let Some(overwritten_id) = self.inotifies[i].wds.insert(wd, watcher.id) {
-            let mut overwritten_watcher =
-                track_assert_some!(self.watchers.remove(&overwritten_id), ErrorKind::Other);
-            overwritten_watcher.inotify_index = i + 1;
-            track!(self.add_watch(&mut overwritten_watcher))?;
-            self.watchers
-                .insert(overwritten_watcher.id, overwritten_watcher);
+        // If the same watcher re-added its path, the kernel has updated the mask of
+        // the existing watch in place.
+        // If a watcher in the mapping no longer exists,
+        // the mapping was a stale one and the kernel has just reused the descriptor.
+        let watchers = &self.watchers;
+        watcher_ids.retain(|id| *id != watcher.id && watchers.contains_key(id));
+
+        // Unless the watchers share the watch,
+        // the mask of the watch has been replaced with the new one,
+        // so the existing watchers are kicked out to the next instance.
+        let is_shared = |w: &WatcherState| self.merge_masks || w.options.shared;
+        let (mut watcher_ids, overwritten_ids): (Vec<_>, Vec<_>) = watcher_ids
+            .into_iter()
+            .partition(|id| is_shared(watcher) && is_shared(&self.watchers[id]));
+        for overwritten_id in overwritten_ids {
+            if let Some(mut overwritten_watcher) = self.watchers.remove(&overwritten_id) {
+                overwritten_watcher.inotify_index = i + 1;
+                if track!(self.add_watch(&mut overwritten_watcher, InstanceReason::Collision))? {
+                    self.watchers
+                        .insert(overwritten_watcher.id, overwritten_watcher);
+                } else if let Some(parent_id) = overwritten_watcher.parent {
+                    // The error has been delivered to the watcher, and it is no longer watching
+                    track!(self.remove_watcher(parent_id))?;
+                }
+            }
+        }
+        let is_joining = !watcher_ids.is_empty() && !watcher.started;
+        if watcher_ids.is_empty() {
+            // The history belongs to the previous watch of the descriptor
+            self.inotifies[i].history.remove(&wd);
         }
+        watcher_ids.push(watcher.id);
+        self.inotifies[i].wds.insert(wd, watcher_ids);
 
-        watcher.wd = wd;
-        let event = if i == 0 {
+        watcher.wd = Some(wd);
+        let event = if watcher.started {
+            WatcherEvent::RestartWatching
+        } else {
             WatcherEvent::StartWatching
+        };
+        watcher.started = true;
+
+        // The events batched so far precede the control event
+        let result = watcher.flush_batch();
+        self.handle_send_result(watcher.id, watcher.dropped, result);
+        if watcher.follower.is_none() {
+            let _ = watcher.send(Ok(event));
+        }
+        if is_joining {
+            self.replay_history(watcher, i, wd);
+        }
+        Ok(())
+    }
+    fn replay_history(
+        &mut self,
+        watcher: &mut WatcherState,
+        inotify_index: usize,
+        wd: WatchDecriptor,
+    ) {
+        // See `InotifyServiceBuilder::replay_buffer_size`
+        if watcher.follower.is_some() || watcher.is_syncing {
+            return;
+        }
+        let history = match self.inotifies[inotify_index].history.get(&wd) {
+            None => return,
+            Some(history) => history.clone(),
+        };
+        for mut event in history {
+            if !watcher.is_interested_in(event.mask)
+                || !watcher.is_interested_in_name(event.name.as_deref())
+            {
+                continue;
+            }
+            event.watch_path = watcher.path.clone();
+            if watcher.options.stat_inode {
+                event.inode = InodeId::from_path(event.path()).ok();
+            }
+            let result = watcher.send_event(event);
+            self.handle_send_result(watcher.id, watcher.dropped, result);
+        }
+        let result = watcher.flush_batch();
+        self.handle_send_result(watcher.id, watcher.dropped, result);
+    }
+    fn rewatch(&mut self, watcher_id: WatcherId, path: PathBuf) -> Result<()> {
+        let old = track!(self.get_watcher(watcher_id))?;
+        let old_path = old.path.clone();
+        let old_key = old.identical_key.clone();
+        let mut watcher = old.clone_for_rewatch(path);
+        let old_wd = watcher.wd;
+        if old_wd.is_none() {
+            // The instance of the watcher may have been released
+            // (see `WatchOptions::follow_replacement`), so the watcher is placed again.
+            watcher.inotify_index = self.select_instance();
+            track!(self.open_instance(watcher.inotify_index, InstanceReason::Placement))?;
+        }
+        let i = watcher.inotify_index;
+
+        // Adds the new watch before removing the old one, so that there is no gap.
+        // If it fails, the old watch is left untouched.
+        let inotify = &mut self.inotifies[i].inotify;
+        let mask = track!(watcher.kernel_mask(self.merge_masks))?;
+        let wd = track!(inotify.add_watch(&watcher.path, mask))?;
+
+        track!(self.assign_wd(&mut watcher, wd))?;
+        if let Some(key) = old_key {
+            // The watcher no longer watches the path
+            self.forget_identical_watch(&key, watcher_id);
+        }
+        if let Some(old_wd) = old_wd.filter(|&old_wd| old_wd != wd) {
+            track!(self.release_wd(i, old_wd, watcher_id, &old_path))?;
+        }
+        self.watchers.insert(watcher_id, watcher);
+        Ok(())
+    }
+    fn migrate_watcher(&mut self, watcher_id: WatcherId) -> Result<()> {
+        let (path, is_dangling) = match self.watchers.get(&watcher_id) {
+            None => return Ok(()),
+            Some(w) => (w.path.clone(), w.wd.is_none()),
+        };
+        if fs::symlink_metadata(&path).is_err() {
+            // Removed again. The next appearance will be notified via the parent directory.
+            return Ok(());
+        }
+        if is_dangling {
+            // The instance of the watcher may have been released,
+            // so the watcher is placed again as a new one.
+            let mut watcher = self.watchers.remove(&watcher_id).expect("Never fails");
+            watcher.inotify_index = self.select_instance();
+            track!(self.add_watch(&mut watcher, InstanceReason::Placement))?;
+            self.watchers.insert(watcher_id, watcher);
+        } else if let Err(e) = track!(self.rewatch(watcher_id, path)) {
+            if let Some(watcher) = self.watchers.get_mut(&watcher_id) {
+                let _ = watcher.send(Err(e));
+            }
+        }
+        Ok(())
+    }
+    fn discard_events(&mut self, inotify_index: usize) -> Result<()> {
+        // See `InotifyServiceBuilder::discard_initial_events`
+        let mut control_events = Vec::new();
+        while let Some(event) = track!(self.inotifies[inotify_index].inotify.read_event())? {
+            let control_mask = EventMask::IGNORED | EventMask::Q_OVERFLOW | EventMask::UNMOUNT;
+            if event.mask.intersects(control_mask) {
+                // The states of the watches and the instance must follow these events
+                control_events.push(event);
+            }
+        }
+        for event in control_events {
+            self.handle_event(inotify_index, event);
+        }
+        Ok(())
+    }
+    fn handle_event(&mut self, inotify_index: usize, event: InotifyEvent) {
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            self.grow_read_buffer(inotify_index);
+        }
+        let inotify = &mut self.inotifies[inotify_index];
+        inotify.events += 1;
+        let watcher_ids = if event.mask.contains(EventMask::Q_OVERFLOW) {
+            // Some events have been lost, and they may belong to any of the watchers
+            Some(inotify.wds.values().flatten().cloned().collect())
+        } else if event.mask.contains(EventMask::IGNORED) {
+            // The kernel has removed the watch, so the descriptor may be reused.
+            inotify.history.remove(&event.wd);
+            inotify.wds.remove(&event.wd)
         } else {
-            WatcherEvent::RestartWatching
+            let watcher_ids = inotify.wds.get(&event.wd).cloned();
+            if watcher_ids.is_some() && self.replay_buffer_size > 0 {
+                let history = inotify.history.entry(event.wd).or_default();
+                if history.len() == self.replay_buffer_size {
+                    history.pop_front();
+                }
+                history.push_back(event.clone());
+            }
+            watcher_ids
+        };
+        for watcher_id in watcher_ids.into_iter().flatten() {
+            self.deliver_event(watcher_id, event.clone());
+        }
+    }
+    fn grow_read_buffer(&mut self, inotify_index: usize) {
+        // See `InotifyServiceBuilder::max_read_buffer_size`
+        let inotify = &mut self.inotifies[inotify_index].inotify;
+        let size = inotify.read_buffer_size();
+        let new_size = (size * 2).min(self.max_read_buffer_size);
+        if new_size > size {
+            inotify.set_read_buffer_size(new_size);
+            self.observer.read_buffer_grown(inotify_index, new_size);
+        }
+    }
+    fn deliver_event(&mut self, watcher_id: WatcherId, mut event: InotifyEvent) {
+        let watcher = self.watchers.get_mut(&watcher_id).expect("Never fails");
+        if let Some(follower_id) = watcher.follower {
+            self.handle_parent_event(watcher_id, follower_id, event);
+            return;
+        }
+        if watcher.parent.is_some() && event.mask.contains(EventMask::IGNORED) {
+            // The watched inode has gone, but the watcher waits for a new one
+            // (see `WatchOptions::follow_replacement`).
+            watcher.wd = None;
+            return;
+        }
+        if watcher.is_syncing && !event.mask.contains(EventMask::IGNORED) {
+            // See `InotifyServiceHandle::watch_synced`
+            return;
+        }
+        if !watcher.is_interested_in(event.mask) {
+            // The kernel watch is shared with other watchers (see `merge_masks`)
+            return;
+        }
+        if !watcher.is_interested_in_name(event.name.as_deref()) {
+            // See `InotifyServiceHandle::watch_files`
+            return;
+        }
+        self.activity_clock += 1;
+        watcher.last_active = self.activity_clock;
+        event.watch_path = watcher.path.clone();
+        if watcher.options.stat_inode {
+            event.inode = InodeId::from_path(event.path()).ok();
+        }
+        let rename = if event.is_rename_part() {
+            let is_interested = watcher.options.track_renames;
+            self.rename_tracker.track(watcher_id, is_interested, &event)
+        } else {
+            None
         };
-        let _ = watcher.event_tx.send(Ok(event));
-        Ok(true)
+        let result = watcher.send_event(event);
+        if watcher.batch.len() == 1 {
+            // The batch will be sent at the end of the current pass of `poll_service`
+            self.batched_watchers.push(watcher_id);
+        }
+        let dropped = watcher.dropped;
+        self.handle_send_result(watcher_id, dropped, result);
+        if let Some(rename) = rename {
+            self.notify_rename(rename);
+        }
+    }
+    fn notify_rename(&mut self, (watcher_ids, from_path, to_path): RenameNotification) {
+        for watcher_id in watcher_ids {
+            if let Some(watcher) = self.watchers.get_mut(&watcher_id) {
+                let result = watcher.flush_batch();
+                let event = WatcherEvent::Moved {
+                    from_path: from_path.clone(),
+                    to_path: to_path.clone(),
+                };
+                let _ = watcher.send(Ok(event));
+                let dropped = watcher.dropped;
+                self.handle_send_result(watcher_id, dropped, result);
+            }
+        }
+    }
+    fn flush_batches(&mut self) {
+        for watcher_id in self.batched_watchers.split_off(0) {
+            if let Some(watcher) = self.watchers.get_mut(&watcher_id) {
+                let result = watcher.flush_batch();
+                let dropped = watcher.dropped;
+                self.handle_send_result(watcher_id, dropped, result);
+            }
+        }
+    }
+    fn handle_send_result(
+        &mut self,
+        watcher_id: WatcherId,
+        dropped: u64,
+        result: ::std::result::Result<(), SendError>,
+    ) {
+        match result {
+            Ok(()) => {}
+            Err(SendError::Full(_)) => {
+                self.observer.watcher_lagged(watcher_id, dropped);
+            }
+            Err(SendError::Disconnected) => {
+                // The `Watcher` has been dropped but the deregistration command has not
+                // arrived yet. We deregister it proactively to release the kernel watch
+                // immediately.
+                if self.disconnected_watchers.contains(&watcher_id) {
+                    return;
+                }
+                self.disconnected_watchers.push(watcher_id);
+                if let Some(watcher) = self.watchers.get(&watcher_id) {
+                    if watcher.options.disconnect_policy != DisconnectPolicy::Cleanup {
+                        self.observer
+                            .watcher_disconnected(watcher_id, &watcher.path);
+                    }
+                }
+            }
+        }
+    }
+    fn handle_parent_event(
+        &mut self,
+        parent_id: WatcherId,
+        follower_id: WatcherId,
+        event: InotifyEvent,
+    ) {
+        if event.mask.contains(EventMask::IGNORED) {
+            // The parent directory has been removed, so the path will never appear again
+            self.watchers.remove(&parent_id);
+            if let Some(follower) = self.watchers.get_mut(&follower_id) {
+                follower.parent = None;
+                if follower.wd.is_none() {
+                    let event = InotifyEvent {
+                        name: None,
+                        watch_path: follower.path.clone(),
+                        ..event
+                    };
+                    let _ = follower.send(Ok(WatcherEvent::Notified(event)));
+                }
+            }
+            return;
+        }
+
+        let is_replaced = self.watchers.get(&follower_id).is_some_and(|follower| {
+            event.name.as_ref().map(|name| name.as_os_str()) == follower.path.file_name()
+        });
+        if is_replaced {
+            // The migration is deferred, because this may be called while reading an instance
+            self.replaced_watchers.push(follower_id);
+        }
+    }
+    fn recover_instance(&mut self, inotify_index: usize) -> Result<()> {
+        // The file descriptor of the instance has become invalid for some reason,
+        // so we re-create the instance and re-add the watchers which were on it.
+        self.inotifies[inotify_index] = track!(InotifyState::new(self.observer.clone()))?;
+        self.observer.instance_destroyed(inotify_index);
+        self.observer
+            .instance_created(inotify_index, InstanceReason::Recovery);
+        let watcher_ids = self
+            .watchers
+            .values()
+            .filter(|w| w.inotify_index == inotify_index)
+            .map(|w| w.id)
+            .collect::<Vec<_>>();
+        for watcher_id in watcher_ids {
+            if let Some(mut watcher) = self.watchers.remove(&watcher_id) {
+                if track!(self.add_watch(&mut watcher, InstanceReason::Recovery))? {
+                    self.watchers.insert(watcher_id, watcher);
+                }
+            }
+        }
+        Ok(())
+    }
+    fn polling_order(&self) -> Vec<usize> {
+        let mut order = self
+            .high_priority_watchers
+            .iter()
+            .filter_map(|id| self.watchers.get(id).map(|w| w.inotify_index))
+            .collect::<Vec<_>>();
+        order.sort();
+        order.dedup();
+        if order.is_empty() {
+            return (0..self.inotifies.len()).collect();
+        }
+
+        let high_priority_count = order.len();
+        for i in 0..self.inotifies.len() {
+            if !order[..high_priority_count].contains(&i) {
+                order.push(i);
+            }
+        }
+        order
+    }
+    fn is_blocked(&self, inotify_index: usize) -> bool {
+        // If a blocking watcher is full, the service stops reading its instance and
+        // the fiber will be unparked when the consumer catches up
+        // (or the service will be woken up by `Command::Release` if it is held).
+        self.blocking_watchers
+            .iter()
+            .filter_map(|id| self.watchers.get(id))
+            .filter(|w| w.inotify_index == inotify_index)
+            .any(|w| w.is_held_full() || w.event_tx.poll_ready().is_not_ready())
+    }
+    fn flush(&mut self) -> Result<()> {
+        for i in 0..self.inotifies.len() {
+            track!(self.flush_instance(i))?;
+        }
+        self.flush_batches();
+        Ok(())
+    }
+    fn flush_instance(&mut self, inotify_index: usize) -> Result<()> {
+        while let Some(event) = track!(self.inotifies[inotify_index].inotify.read_event())? {
+            self.handle_event(inotify_index, event);
+        }
+        Ok(())
+    }
+    fn poll_service(&mut self) -> Poll<(), Error> {
+        // Repeats until the state of the service settles down,
+        // so that the instances created or modified in a pass are read without waiting
+        // for the next wakeup.
+        let mut is_changed = true;
+        while is_changed {
+            is_changed = false;
+            if self.shutdown_signal.is_signaled() {
+                // Same as `Command::Shutdown`
+                self.is_shutdown = true;
+                return Ok(Async::Ready(()));
+            }
+            while let Async::Ready(Some(command)) = self.command_rx.poll().expect("Never fails") {
+                self.command_tx.queue_len.fetch_sub(1, Ordering::SeqCst);
+                track!(self.handle_command(command))?;
+                if self.is_shutdown {
+                    return Ok(Async::Ready(()));
+                }
+                is_changed = true;
+            }
+            for i in self.polling_order() {
+                if !self.inotifies[i].is_polled {
+                    self.inotifies[i].is_polled = true;
+                    if self.discard_initial_events {
+                        track!(self.discard_events(i))?;
+                    }
+                }
+                while !self.is_blocked(i) {
+                    match track!(self.inotifies[i].inotify.poll())? {
+                        Async::NotReady => break,
+                        Async::Ready(None) => {
+                            track!(self.recover_instance(i))?;
+                            is_changed = true;
+                            break;
+                        }
+                        Async::Ready(Some(event)) => {
+                            self.handle_event(i, event);
+                        }
+                    }
+                }
+            }
+            for rename in self.rename_tracker.expire() {
+                self.notify_rename(rename);
+            }
+            self.flush_batches();
+            while let Some(watcher_id) = self.disconnected_watchers.pop() {
+                let is_fatal = self
+                    .watchers
+                    .get(&watcher_id)
+                    .is_some_and(|w| w.options.disconnect_policy == DisconnectPolicy::Fatal);
+                track!(self.deregister_watcher(watcher_id))?;
+                track_assert!(
+                    !is_fatal,
+                    ErrorKind::Other,
+                    "The consumer of the watcher has been disconnected: id={:?}",
+                    watcher_id
+                );
+                is_changed = true;
+            }
+            while let Some(watcher_id) = self.replaced_watchers.pop() {
+                track!(self.migrate_watcher(watcher_id))?;
+                is_changed = true;
+            }
+            let mut i = 0;
+            while i < self.retrying_watchers.len() {
+                let timer = &mut self.retrying_watchers[i].timer;
+                if track!(timer.poll().map_err(Error::from))?.is_ready() {
+                    let RetryingWatcher { watcher, retry, .. } = self.retrying_watchers.remove(i);
+                    track!(self.register_watcher(watcher, retry))?;
+                    is_changed = true;
+                } else {
+                    i += 1;
+                }
+            }
+            if !is_changed {
+                // Wakes up when the pending halves of renames expire
+                self.rename_timer = self.rename_tracker.next_expiry().map(timer::timeout);
+                if let Some(ref mut timer) = self.rename_timer {
+                    is_changed = track!(timer.poll().map_err(Error::from))?.is_ready();
+                }
+            }
+        }
+        Ok(Async::NotReady)
     }
 }
 impl Future for InotifyService {
     type Item = ();
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        while let Async::Ready(Some(command)) = self.command_rx.poll().expect("Never fails") {
-            track!(self.handle_command(command))?;
+        let polled = track!(self.poll_service());
+        if polled.as_ref().map_or(true, |a| a.is_ready()) {
+            self.is_alive.store(false, Ordering::SeqCst);
         }
-        for inotify in &mut self.inotifies {
-            while let Async::Ready(Some(event)) = track!(inotify.inotify.poll())? {
-                if let Some(watcher_id) = inotify.wds.get(&event.wd) {
-                    let _ = self.watchers[watcher_id]
-                        .event_tx
-                        .send(Ok(WatcherEvent::Notified(event)));
-                }
-            }
+        if let Err(ref e) = polled {
+            *self.error.lock().unwrap_or_else(|e| e.into_inner()) = Some(e.clone());
         }
-        Ok(Async::NotReady)
+        polled
     }
 }
 impl Default for InotifyService {
@@ -149,12 +1171,358 @@ impl Default for InotifyService {
         Self::new()
     }
 }
+impl Drop for InotifyService {
+    fn drop(&mut self) {
+        self.is_alive.store(false, Ordering::SeqCst);
+        if self.flush_on_shutdown {
+            let _ = self.flush();
+        }
+        for watcher in self.watchers.values_mut() {
+            let _ = watcher.release_held();
+        }
+        while self.inotifies.pop().is_some() {
+            self.observer.instance_destroyed(self.inotifies.len());
+        }
+    }
+}
+
+/// `InotifyService` builder.
+#[derive(Debug, Clone)]
+pub struct InotifyServiceBuilder {
+    flush_on_shutdown: bool,
+    discard_initial_events: bool,
+    merge_masks: bool,
+    placement_strategy: PlacementStrategy,
+    max_pending_requests: Option<usize>,
+    observer: SharedObserver,
+    rename_expiry: Duration,
+    max_watches: Option<usize>,
+    case_insensitive_paths: bool,
+    dedup_identical_watches: bool,
+    replay_buffer_size: usize,
+    max_read_buffer_size: usize,
+    add_watch_retries: usize,
+    shutdown_signal: ShutdownSignal,
+}
+impl InotifyServiceBuilder {
+    /// Makes a new `InotifyServiceBuilder` with the default settings.
+    pub fn new() -> Self {
+        InotifyServiceBuilder {
+            flush_on_shutdown: false,
+            discard_initial_events: false,
+            merge_masks: false,
+            placement_strategy: PlacementStrategy::default(),
+            max_pending_requests: None,
+            observer: SharedObserver::default(),
+            rename_expiry: rename_tracker::DEFAULT_RENAME_EXPIRY,
+            max_watches: None,
+            case_insensitive_paths: false,
+            dedup_identical_watches: false,
+            replay_buffer_size: 0,
+            max_read_buffer_size: internal_inotify::DEFAULT_MAX_READ_BUFFER_SIZE,
+            add_watch_retries: 0,
+            shutdown_signal: ShutdownSignal::default(),
+        }
+    }
+
+    /// Sets whether the service flushes pending events when it exits.
+    ///
+    /// If `true`, the service reads all the events queued in the kernel
+    /// and delivers them to the watchers before closing the channels of the watchers.
+    /// This applies both when the service is shut down by `InotifyServiceHandle::shutdown`
+    /// and when the service is dropped without the graceful shutdown.
+    ///
+    /// The default value is `false`.
+    pub fn flush_on_shutdown(&mut self, enabled: bool) -> &mut Self {
+        self.flush_on_shutdown = enabled;
+        self
+    }
+
+    /// Sets whether the service discards the events queued in an inotify instance
+    /// before the instance is polled for the first time.
+    ///
+    /// If `true`, the events which occurred between the creation of an inotify instance
+    /// (including re-created ones) and its first polling are not delivered to the watchers.
+    /// This gives "start from now" semantics when the service is slow to start.
+    /// The `IGNORED`, `Q_OVERFLOW` and `UNMOUNT` events are delivered even in that period,
+    /// because they report changes of the watches rather than of the files.
+    ///
+    /// The default value is `false`.
+    pub fn discard_initial_events(&mut self, enabled: bool) -> &mut Self {
+        self.discard_initial_events = enabled;
+        self
+    }
+
+    /// Sets whether watchers of the same inode share a kernel watch.
+    ///
+    /// If `true`, when a watcher is added for an inode already watched in an inotify instance,
+    /// the service passes `IN_MASK_ADD` to the kernel so that the watch covers the union of
+    /// the masks of the watchers, instead of kicking out the existing watcher to another
+    /// instance (see `InotifyServiceHandle::watch`).
+    /// Each watcher still receives only the events matching its own mask.
+    /// This saves file descriptors when many components watch the same directories.
+    /// To enable this for specific watchers only, use `WatchOptions::shared` instead.
+    ///
+    /// Note that the mask of a shared watch is never narrowed while it is alive,
+    /// even after some of the watchers sharing it are dropped.
+    ///
+    /// The default value is `false`.
+    pub fn merge_masks(&mut self, enabled: bool) -> &mut Self {
+        self.merge_masks = enabled;
+        self
+    }
+
+    /// Sets the strategy for distributing watchers across inotify instances.
+    ///
+    /// See the documentation of `PlacementStrategy` for the tradeoffs of the strategies.
+    ///
+    /// The default value is `PlacementStrategy::Compact`.
+    pub fn placement_strategy(&mut self, strategy: PlacementStrategy) -> &mut Self {
+        self.placement_strategy = strategy;
+        self
+    }
+
+    /// Sets the maximum number of the requests pending in the service.
+    ///
+    /// If the number of the requests which have been issued by handles but not yet handled
+    /// by the service reaches `max`, the requests which can report errors
+    /// (e.g., `InotifyServiceHandle::watch` and `InotifyServiceHandle::stats`) are rejected
+    /// with an error which has the kind `ErrorKind::Busy` until the service catches up.
+    ///
+    /// This is an admission limit rather than the capacity of the underlying channel.
+    /// Requests for deregistering watchers (issued when `Watcher`s are dropped or by
+    /// `InotifyServiceHandle::deregister_batch`), requests for holding and releasing events
+    /// (see `Watcher::hold`) and shutdown requests are never rejected,
+    /// because there is no way to report errors for them and
+    /// dropping them would leak kernel resources.
+    /// They are counted as pending requests, so the number may temporarily exceed `max`.
+    ///
+    /// By default, there is no limit.
+    pub fn max_pending_requests(&mut self, max: usize) -> &mut Self {
+        self.max_pending_requests = Some(max);
+        self
+    }
+
+    /// Sets the observer of the service.
+    ///
+    /// By default, the service has no observer.
+    pub fn observer<O: Observer>(&mut self, observer: O) -> &mut Self {
+        self.observer = SharedObserver::new(observer);
+        self
+    }
+
+    /// Sets the duration for which the service waits for the other half of a rename.
+    ///
+    /// When the service observes a `MOVED_FROM` (or `MOVED_TO`) event,
+    /// it keeps the event for the duration to pair it up with the corresponding
+    /// `MOVED_TO` (or `MOVED_FROM`) event (see `WatchOptions::track_renames`).
+    /// If the duration is too short, legitimate pairs may be split under heavy load.
+    ///
+    /// When an unpaired half expires, the interested watchers are notified of it as
+    /// a rename whose other side is unknown (i.e., `WatcherEvent::Moved` containing `None`).
+    ///
+    /// The default value is `100` milliseconds.
+    pub fn rename_expiry(&mut self, duration: Duration) -> &mut Self {
+        self.rename_expiry = duration;
+        self
+    }
+
+    /// Sets the maximum number of the kernel watches held by the service.
+    ///
+    /// When adding a new watcher would exceed the limit, the service evicts
+    /// the least recently active watchers (i.e., the ones which have not received events
+    /// for the longest time) to make room for it, instead of failing.
+    /// The evicted watchers produce `WatcherEvent::Evicted` as their final event.
+    /// The internal watches for `WatchOptions::follow_replacement` are also counted.
+    ///
+    /// This is useful for applications which watch a huge and dynamic set of paths
+    /// (e.g., file indexers) and can afford to re-watch the evicted ones on demand.
+    ///
+    /// By default, the number of the watches is unlimited.
+    pub fn max_watches(&mut self, max: usize) -> &mut Self {
+        self.max_watches = Some(max);
+        self
+    }
+
+    /// Sets whether the service compares paths case-insensitively.
+    ///
+    /// If `true`, the paths passed to `InotifyServiceHandle::unwatch` match the watched paths
+    /// regardless of their cases (e.g., `/Data` matches `/data`).
+    /// Likewise, `dedup_identical_watches` regards such paths as identical.
+    /// This is intended for case-insensitive (but case-preserving) filesystems.
+    ///
+    /// This is a best-effort feature: the paths are compared after a simple Unicode lowercase
+    /// conversion, which may differ from the case folding rules of the filesystem.
+    /// Note that the kernel side is not affected, thus the events still have the names
+    /// as they are stored in the filesystem.
+    ///
+    /// The default value is `false`.
+    pub fn case_insensitive_paths(&mut self, enabled: bool) -> &mut Self {
+        self.case_insensitive_paths = enabled;
+        self
+    }
+
+    /// Sets whether a watcher of the same path and mask as an existing one joins
+    /// the existing watch.
+    ///
+    /// If `true`, when `InotifyServiceHandle::watch` (or its variants) is called with
+    /// a path (compared after canonicalization, and case-insensitively if
+    /// `case_insensitive_paths` is enabled) and a mask identical to those of an active
+    /// watcher, the new watcher subscribes to the kernel watch of the existing one
+    /// instead of adding a new watch (and kicking out the existing one).
+    /// The watchers share the event delivery: both of them receive the same events,
+    /// each through its own channel, and the kernel watch is released when the last of
+    /// them is dropped.
+    /// This is useful for idempotent configuration loading.
+    ///
+    /// Unlike `merge_masks` (or `WatchOptions::shared`), this applies only to
+    /// the exactly identical requests, and the kernel is not involved at all.
+    /// The watchers following replacements (`WatchOptions::follow_replacement`) and
+    /// the ones made by `InotifyServiceHandle::watch_synced` are excluded.
+    ///
+    /// The default value is `false`.
+    pub fn dedup_identical_watches(&mut self, enabled: bool) -> &mut Self {
+        self.dedup_identical_watches = enabled;
+        self
+    }
+
+    /// Sets the number of the recent events replayed to a watcher joining a shared watch.
+    ///
+    /// The service keeps the last `size` events of each kernel watch.
+    /// When a new watcher starts sharing an existing kernel watch
+    /// (see `WatchOptions::shared`, `merge_masks` and `dedup_identical_watches`),
+    /// the kept events matching its mask are delivered to it right after
+    /// `WatcherEvent::StartWatching` and before any live events,
+    /// so that late subscribers get recent context.
+    ///
+    /// `0` disables the replay.
+    ///
+    /// The default value is `0`.
+    pub fn replay_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.replay_buffer_size = size;
+        self
+    }
+
+    /// Sets the upper bound of the size of the buffer used to read events from
+    /// each inotify instance.
+    ///
+    /// The buffer initially has 4096 bytes.
+    /// Every time the kernel queue of an instance overflows (i.e., `EventMask::Q_OVERFLOW`),
+    /// the buffer of the instance is doubled within this bound,
+    /// so that the service drains the queue with fewer system calls to keep up with
+    /// the event rate. The growth is reported by `Observer::read_buffer_grown`.
+    ///
+    /// Note that the service already reads all the available events of an instance
+    /// each time it is woken up, and this bound limits only the memory and the amount of
+    /// events handled per system call.
+    /// Values smaller than 4096 disable the growth.
+    ///
+    /// The default value is `65536`.
+    pub fn max_read_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.max_read_buffer_size = size;
+        self
+    }
+
+    /// Sets the number of the retries of a watch addition failed with a transient error.
+    ///
+    /// If `inotify_add_watch` fails with `EINTR`, `EAGAIN` or `ENOMEM`,
+    /// the service retries it up to `count` times before delivering the error to the watcher.
+    /// The retries are delayed by exponential backoff starting from 1 millisecond
+    /// (e.g., `5` retries take 31 milliseconds at most).
+    /// The service keeps handling the other watchers during the backoff,
+    /// since the retries are scheduled by timers instead of blocking it.
+    ///
+    /// Only the watches of newly registered watchers are retried.
+    /// Note that the timers work only if the service runs in a fiber.
+    ///
+    /// Permanent errors (e.g., `ENOENT` or `EACCES`) are delivered immediately.
+    ///
+    /// The default value is `0`.
+    pub fn add_watch_retries(&mut self, count: usize) -> &mut Self {
+        self.add_watch_retries = count;
+        self
+    }
+
+    /// Sets the future which shuts down the service when it completes (successfully or not).
+    ///
+    /// The shutdown is the same as the one requested by `InotifyServiceHandle::shutdown`
+    /// (e.g., the streams of all the watchers reach the end), and whichever comes first
+    /// takes effect. This is useful to tie the service to the lifecycle of an application
+    /// (e.g., a ctrl-C handler).
+    ///
+    /// Note that the future is polled in the fiber of the service, and it is moved into
+    /// the service built first by this builder (or its clones).
+    pub fn shutdown_on<F>(&mut self, future: F) -> &mut Self
+    where
+        F: Future + Send + 'static,
+    {
+        let future: BoxShutdownFuture = Box::new(future.then(|_| Ok(())));
+        self.shutdown_signal = ShutdownSignal(Arc::new(Mutex::new(Some(future))));
+        self
+    }
+
+    /// Builds a new `InotifyService` instance with the specified settings.
+    pub fn finish(&self) -> InotifyService {
+        let (command_tx, command_rx) = mpsc::channel();
+        let command_tx = CommandSender {
+            inner: command_tx,
+            queue_len: Arc::new(AtomicUsize::new(0)),
+            max_pending: self.max_pending_requests,
+        };
+        InotifyService {
+            inotifies: Vec::new(),
+            command_tx,
+            command_rx,
+            watcher_id: Arc::new(AtomicUsize::new(0)),
+            is_alive: Arc::new(AtomicBool::new(true)),
+            error: Arc::new(Mutex::new(None)),
+            watchers: HashMap::new(),
+            disconnected_watchers: Vec::new(),
+            replaced_watchers: Vec::new(),
+            batched_watchers: Vec::new(),
+            high_priority_watchers: HashSet::new(),
+            blocking_watchers: HashSet::new(),
+            flush_on_shutdown: self.flush_on_shutdown,
+            discard_initial_events: self.discard_initial_events,
+            merge_masks: self.merge_masks,
+            placement_strategy: self.placement_strategy,
+            is_shutdown: false,
+            observer: self.observer.clone(),
+            reported_instance_count: 0,
+            reported_watcher_count: 0,
+            rename_tracker: RenameTracker::new(self.rename_expiry),
+            rename_timer: None,
+            max_watches: self.max_watches,
+            activity_clock: 0,
+            case_insensitive_paths: self.case_insensitive_paths,
+            identical_watches: if self.dedup_identical_watches {
+                Some(HashMap::new())
+            } else {
+                None
+            },
+            replay_buffer_size: self.replay_buffer_size,
+            max_read_buffer_size: self.max_read_buffer_size,
+            add_watch_retries: self.add_watch_retries,
+            retrying_watchers: Vec::new(),
+            shutdown_signal: self.shutdown_signal.take(),
+            untaken_drops: 0,
+        }
+    }
+}
+impl Default for InotifyServiceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 /// Handle of `InotifyService`.
 #[derive(Debug, Clone)]
 pub struct InotifyServiceHandle {
-    command_tx: mpsc::Sender<Command>,
+    command_tx: CommandSender,
     watcher_id: Arc<AtomicUsize>,
+    is_alive: Arc<AtomicBool>,
+    error: Arc<Mutex<Option<Error>>>,
+    pub(crate) observer: SharedObserver,
 }
 impl InotifyServiceHandle {
     /// Makes a new `Watcher` that watches `path` with the given mask.
@@ -164,58 +1532,2670 @@ impl InotifyServiceHandle {
     /// After that the service will create new inotify instance (i.e., file descriptor) and
     /// re-add the victim watcher to it.
     /// In that case the re-added watcher will receive the event `WatcherEvent::RestartWatching`.
+    /// This can be avoided by enabling `InotifyServiceBuilder::merge_masks`.
+    ///
+    /// If too many requests are pending (see `InotifyServiceBuilder::max_pending_requests`),
+    /// the resulting watcher will produce an error which has the kind `ErrorKind::Busy`.
     pub fn watch<P: AsRef<Path>>(&self, path: P, mask: WatchMask) -> Watcher {
-        let watcher_id = self.watcher_id.fetch_add(1, Ordering::SeqCst);
-        let (event_tx, event_rx) = mpsc::channel();
+        self.watch_with_options(path, mask, WatchOptions::default())
+    }
+
+    /// Makes a new `Watcher` that watches `path` with the given mask and options.
+    ///
+    /// See the documentation of `watch` method for details.
+    pub fn watch_with_options<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mask: WatchMask,
+        options: WatchOptions,
+    ) -> Watcher {
+        let (watcher, state) = self.new_watcher(path.as_ref(), mask, options);
+        let event_tx = state.event_tx.clone();
+        let command = Command::RegisterWatcher {
+            watcher: Box::new(state),
+        };
+        if let Err(e) = track!(self.command_tx.try_send(command)) {
+            let _ = event_tx.send(Err(e));
+        }
+        watcher
+    }
+
+    /// Makes a new `Watcher` that shares the kernel watch with the other deduplicated watchers
+    /// of the same file/directory.
+    ///
+    /// This is equivalent to calling `watch_with_options` with `WatchOptions::shared` enabled.
+    /// It is useful when independent components watch the same files without coordination,
+    /// because the repeated calls do not consume extra kernel watches or instances.
+    pub fn watch_deduped<P: AsRef<Path>>(&self, path: P, mask: WatchMask) -> Watcher {
+        let options = WatchOptions {
+            shared: true,
+            ..Default::default()
+        };
+        self.watch_with_options(path, mask, options)
+    }
+
+    /// Makes a new `Watcher` that watches the files named `names` in the directory `dir`.
+    ///
+    /// Only a single watch is added to `dir`, and the events whose `name` is not contained
+    /// in `names` are discarded by the service before being delivered to the watcher.
+    /// The events on `dir` itself (e.g., `IGNORED` or `DELETE_SELF`) are always delivered.
+    ///
+    /// This is cheaper than watching each of the files, and also catches the files
+    /// which are created after the watch is added.
+    pub fn watch_files<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        names: Vec<OsString>,
+        mask: WatchMask,
+    ) -> Watcher {
+        let (watcher, mut state) = self.new_watcher(dir.as_ref(), mask, WatchOptions::default());
+        state.names = Some(names.into_iter().collect());
+        let event_tx = state.event_tx.clone();
+        let command = Command::RegisterWatcher {
+            watcher: Box::new(state),
+        };
+        if let Err(e) = track!(self.command_tx.try_send(command)) {
+            let _ = event_tx.send(Err(e));
+        }
+        watcher
+    }
+
+    /// Makes a new `Watcher` that watches `path` and produces the baseline of the directory
+    /// prior to the live events.
+    ///
+    /// After adding the watch, the service discards the events already queued for it,
+    /// scans the entries of the directory, and produces them as `WatcherEvent::Baseline`
+    /// right after `WatcherEvent::StartWatching`. The live events follow.
+    /// Because the scan is performed after the watch is added, every change is reflected
+    /// either in the baseline or in the subsequent events (some changes made during the scan
+    /// may be reflected in both).
+    ///
+    /// Like inotify watches, the scan is not recursive.
+    /// If the scan fails, the watcher produces the error instead of the baseline.
+    pub fn watch_synced<P: AsRef<Path>>(&self, path: P, mask: WatchMask) -> Watcher {
+        let (watcher, mut state) = self.new_watcher(path.as_ref(), mask, WatchOptions::default());
+        state.is_syncing = true;
+        let event_tx = state.event_tx.clone();
         let command = Command::RegisterWatcher {
+            watcher: Box::new(state),
+        };
+        if let Err(e) = track!(self.command_tx.try_send(command)) {
+            let _ = event_tx.send(Err(e));
+        }
+        watcher
+    }
+
+    /// Makes a new `EventualWatcher` that watches `path` with the given mask
+    /// once the path exists.
+    ///
+    /// Unlike `watch` method, this does not fail even if the path does not exist yet.
+    pub fn watch_eventually<P: AsRef<Path>>(&self, path: P, mask: WatchMask) -> EventualWatcher {
+        EventualWatcher::new(self.clone(), path.as_ref().to_path_buf(), mask)
+    }
+
+    /// Makes a new `ResolvedWatcher` that watches the physical path resolved from `logical`
+    /// by `resolver`.
+    ///
+    /// This is useful for overlay/virtual filesystems which map logical paths to
+    /// physical ones. The path is re-resolved each time the watch is removed
+    /// (see the documentation of `ResolvedWatcher` for details).
+    pub fn watch_resolved<F>(
+        &self,
+        logical: String,
+        resolver: F,
+        mask: WatchMask,
+    ) -> ResolvedWatcher<F>
+    where
+        F: Fn(&str) -> Result<PathBuf>,
+    {
+        ResolvedWatcher::new(self.clone(), logical, resolver, mask)
+    }
+
+    /// Watches `path` with the given mask, and collects the next `n` events of the watcher.
+    ///
+    /// The resulting events include control events such as `WatcherEvent::StartWatching`.
+    /// If the watcher terminates before producing `n` events
+    /// (e.g., the watched file has been deleted), the events produced so far are returned.
+    ///
+    /// The watcher is deregistered when the returned future is dropped.
+    pub fn watch_take<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mask: WatchMask,
+        n: u64,
+    ) -> impl Future<Item = Vec<WatcherEvent>, Error = Error> {
+        self.watch(path, mask).take(n).collect()
+    }
+
+    /// Makes new `Watcher`s that watch the given paths with the corresponding masks.
+    ///
+    /// This is equivalent to calling `watch` method for each pair,
+    /// but all of the watchers are registered by a single request to the service.
+    /// It is efficient to register a large number of watchers at once.
+    ///
+    /// If some of the watchers could not be added, the errors will be reported by
+    /// the streams of the individual watchers.
+    pub fn watch_batch(&self, watches: Vec<(PathBuf, WatchMask)>) -> Vec<Watcher> {
+        let (watchers, states): (Vec<_>, Vec<_>) = watches
+            .into_iter()
+            .map(|(path, mask)| self.new_watcher(&path, mask, WatchOptions::default()))
+            .unzip();
+        let event_txs = states
+            .iter()
+            .map(|w| w.event_tx.clone())
+            .collect::<Vec<_>>();
+        let command = Command::RegisterWatchers { watchers: states };
+        if let Err(e) = track!(self.command_tx.try_send(command)) {
+            for event_tx in event_txs {
+                let _ = event_tx.send(Err(e.clone()));
+            }
+        }
+        watchers
+    }
+
+    /// Makes a new `CloseWriteWatcher` that notifies each completed write to the file `path`.
+    ///
+    /// `EventMask::CLOSE_WRITE` is the signal that a writer has finished writing the file,
+    /// so this is suitable to pick up files written by other processes
+    /// without reacting to every `EventMask::MODIFY` during the writes.
+    ///
+    /// The parent directory of `path` is watched with `WatchMask::CLOSE_WRITE`,
+    /// and the events of the other entries are filtered out.
+    /// The kernel watch of the directory is shared with the other deduplicated watchers
+    /// (see `watch_deduped`), so calling this for many files in the same directory is cheap.
+    pub fn watch_on_close<P: AsRef<Path>>(&self, path: P) -> CloseWriteWatcher {
+        let path = path.as_ref();
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let watcher = self.watch_deduped(parent, WatchMask::CLOSE_WRITE | WatchMask::ONLYDIR);
+        CloseWriteWatcher::new(watcher, path.file_name().map(|n| n.to_os_string()))
+    }
+
+    /// Makes a new `WaitFor` future that watches `path` with `mask` and
+    /// resolves with the first event satisfying `predicate`.
+    ///
+    /// E.g., waiting for the creation of `done.flag` in `dir` can be written as
+    /// `wait_for(dir, WatchMask::CREATE, |e| e.name == Some("done.flag".into()))`.
+    ///
+    /// The control events (e.g., `WatcherEvent::StartWatching`) are not passed to `predicate`.
+    /// See the documentation of `WaitFor` for details.
+    pub fn wait_for<P, F>(&self, path: P, mask: WatchMask, predicate: F) -> WaitFor<F>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&InotifyEvent) -> bool,
+    {
+        WaitFor::new(self.watch(path, mask), predicate)
+    }
+
+    /// Makes a new `ConfigWatcher` that produces the contents of the file `path`
+    /// each time the file is changed.
+    ///
+    /// The changes notified within `debounce` from the previous one are delivered at once.
+    ///
+    /// The parent directory of `path` is watched with `WatchMask::CLOSE_WRITE` and
+    /// `WatchMask::MOVED_TO` (see `watch_files`), so the replacement of the file by a rename
+    /// is also picked up.
+    /// See the documentation of `ConfigWatcher` for details.
+    pub fn watch_config<P: AsRef<Path>>(&self, path: P, debounce: Duration) -> ConfigWatcher {
+        self.watch_config_with_clock(path, debounce, FibersClock)
+    }
+
+    /// Same as `watch_config` except that the debounce timers are made by `clock`.
+    pub fn watch_config_with_clock<P, C>(
+        &self,
+        path: P,
+        debounce: Duration,
+        clock: C,
+    ) -> ConfigWatcher<C>
+    where
+        P: AsRef<Path>,
+        C: Clock,
+    {
+        let path = path.as_ref();
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let names = path
+            .file_name()
+            .map(|n| n.to_os_string())
+            .into_iter()
+            .collect();
+        let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::ONLYDIR;
+        let watcher = self.watch_files(parent, names, mask);
+        ConfigWatcher::new(watcher, path.to_path_buf(), debounce, clock)
+    }
+
+    /// Makes a new `TreeWatcher` that watches the directory tree under `root` recursively.
+    ///
+    /// See the documentation of `TreeWatcher` for details.
+    pub fn watch_tree<P: AsRef<Path>>(&self, root: P) -> TreeWatcher {
+        TreeWatcher::new(self.clone(), root.as_ref().to_path_buf())
+    }
+
+    /// Makes a new `EmptyDirWatcher` that watches the directory `path`
+    /// until the directory becomes empty.
+    ///
+    /// `WatchMask::DELETE`, `WatchMask::MOVED_FROM` and `WatchMask::ONLYDIR` are
+    /// added to `mask` implicitly.
+    pub fn watch_until_empty<P: AsRef<Path>>(&self, path: P, mask: WatchMask) -> EmptyDirWatcher {
+        let mask = mask | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::ONLYDIR;
+        let watcher = self.watch(&path, mask);
+        EmptyDirWatcher::new(watcher, path.as_ref().to_path_buf())
+    }
+
+    fn new_watcher(
+        &self,
+        path: &Path,
+        mask: WatchMask,
+        options: WatchOptions,
+    ) -> (Watcher, WatcherState) {
+        let watcher_id = self.watcher_id.fetch_add(1, Ordering::SeqCst);
+        let (event_tx, event_rx) =
+            event_channel::channel(options.channel_capacity, options.overflow_policy);
+        let state = WatcherState {
+            id: watcher_id,
+            inotify_index: 0,
+            wd: None, // Set by `InotifyService::assign_wd()`
+            started: false,
+            path: path.to_path_buf(),
+            mask,
+            options: options.clone(),
+            event_tx,
+            dropped: 0,
+            taken_dropped: 0,
+            batch: Vec::new(),
+            last_active: 0, // Set by `InotifyService::register_watcher()`
+            is_syncing: false,
+            identical_key: None,
+            names: None,
+            holds: Vec::new(),
+            held: VecDeque::new(),
+            parent: None,
+            follower: None,
+        };
+        let watcher = Watcher::new(
+            watcher_id,
+            self.clone(),
+            path.to_path_buf(),
+            mask,
+            options,
+            event_rx,
+        );
+        (watcher, state)
+    }
+
+    /// Stops the given watchers at once.
+    ///
+    /// This is equivalent to dropping each of the watchers,
+    /// but all of them are deregistered by a single request to the service.
+    /// It is efficient to stop a large number of watchers at once.
+    pub fn deregister_batch(&self, watchers: Vec<Watcher>) {
+        let watcher_ids = watchers.into_iter().map(Watcher::detach).collect();
+        let command = Command::DeregisterWatchers { watcher_ids };
+        self.command_tx.send(command);
+    }
+
+    /// Stops all the watchers which watch `path`.
+    ///
+    /// The paths are compared after canonicalization
+    /// (if a path cannot be canonicalized, it is compared as it is),
+    /// and case-insensitively if `InotifyServiceBuilder::case_insensitive_paths` is enabled.
+    /// The streams of the stopped watchers will reach the end.
+    ///
+    /// The resulting future returns the number of the stopped watchers.
+    pub fn unwatch<P: AsRef<Path>>(&self, path: P) -> Reply<usize> {
+        let path = path.as_ref().to_path_buf();
+        self.query(|reply_tx| Command::Unwatch {
+            path,
+            is_prefix: false,
+            reply_tx,
+        })
+    }
+
+    /// Stops all the watchers which watch `prefix` or the paths under it.
+    ///
+    /// This is useful to tear down a group of watchers (e.g., the ones in a directory tree)
+    /// at once without tracking each `Watcher`.
+    /// The paths are compared component-wise (e.g., `/foo/barbaz` is not under `/foo/bar`)
+    /// in the same manner as `unwatch` method.
+    /// The watchers of `prefix` itself are also stopped.
+    ///
+    /// The resulting future returns the number of the stopped watchers.
+    pub fn unwatch_prefix<P: AsRef<Path>>(&self, prefix: P) -> Reply<usize> {
+        let path = prefix.as_ref().to_path_buf();
+        self.query(|reply_tx| Command::Unwatch {
+            path,
+            is_prefix: true,
+            reply_tx,
+        })
+    }
+
+    /// Retrieves the statistics of the service.
+    ///
+    /// If too many requests are pending (see `InotifyServiceBuilder::max_pending_requests`),
+    /// the resulting future will fail with an error which has the kind `ErrorKind::Busy`.
+    pub fn stats(&self) -> Reply<ServiceStats> {
+        self.query(|reply_tx| Command::GetStats { reply_tx })
+    }
+
+    /// Retrieves the numbers of the events dropped since the last call of this method,
+    /// and resets them to zero.
+    ///
+    /// This is useful to compute the drop rates over intervals.
+    /// The per-watcher counters reported by `stats` (i.e., `WatcherStats::dropped`) remain
+    /// cumulative and are not affected by this.
+    ///
+    /// If too many requests are pending (see `InotifyServiceBuilder::max_pending_requests`),
+    /// the resulting future will fail with an error which has the kind `ErrorKind::Busy`.
+    pub fn take_drop_stats(&self) -> Reply<DropStats> {
+        self.query(|reply_tx| Command::TakeDropStats { reply_tx })
+    }
+
+    /// Returns `true` if the service has not terminated yet.
+    ///
+    /// This returns `false` once the `InotifyService` future has completed
+    /// (e.g., by `shutdown` method or an error) or has been dropped.
+    /// After that, no events will be delivered to the watchers.
+    pub fn is_alive(&self) -> bool {
+        self.is_alive.load(Ordering::SeqCst)
+    }
+
+    /// Returns the error which has terminated the service, if any.
+    pub fn error(&self) -> Option<Error> {
+        self.error.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Shuts down the service gracefully.
+    ///
+    /// After handling this request, the `InotifyService` future will terminate successfully and
+    /// the streams of all the existing watchers will reach the end.
+    ///
+    /// If `InotifyServiceBuilder::flush_on_shutdown` is enabled,
+    /// the events pending at that time will be delivered before the end of the streams.
+    ///
+    /// The service can also be shut down by a future (see `InotifyServiceBuilder::shutdown_on`).
+    pub fn shutdown(&self) {
+        self.command_tx.send(Command::Shutdown);
+    }
+
+    pub(crate) fn set_high_priority(&self, watcher_id: WatcherId, enabled: bool) -> Result<()> {
+        let command = Command::SetHighPriority {
+            watcher_id,
+            enabled,
+        };
+        track!(self.command_tx.try_send(command))
+    }
+
+    pub(crate) fn rewatch(&self, watcher_id: WatcherId, path: PathBuf) -> Reply<()> {
+        self.query(|reply_tx| Command::Rewatch {
+            watcher_id,
+            path,
+            reply_tx,
+        })
+    }
+
+    pub(crate) fn current_mask(&self, watcher_id: WatcherId) -> Reply<WatchMask> {
+        self.query(|reply_tx| Command::GetMask {
             watcher_id,
-            path: path.as_ref().to_path_buf(),
+            reply_tx,
+        })
+    }
+
+    fn query<T, F>(&self, f: F) -> Reply<T>
+    where
+        F: FnOnce(oneshot::Sender<Result<T>>) -> Command,
+    {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let command = f(reply_tx);
+        Reply::new(track!(self.command_tx.try_send(command)).map(|()| reply_rx))
+    }
+
+    pub(crate) fn redirect(
+        &self,
+        watcher_id: WatcherId,
+        path: &Path,
+        mask: WatchMask,
+        options: WatchOptions,
+    ) -> Watcher {
+        let (event_tx, event_rx) =
+            event_channel::channel(options.channel_capacity, options.overflow_policy);
+        let watcher = Watcher::new(
+            watcher_id,
+            self.clone(),
+            path.to_path_buf(),
             mask,
+            options,
+            event_rx,
+        );
+        let command = Command::Redirect {
+            watcher_id,
             event_tx,
         };
-        let _ = self.command_tx.send(command);
-        Watcher::new(watcher_id, self.clone(), event_rx)
+        self.command_tx.send(command);
+        watcher
     }
 
     pub(crate) fn deregister_watcher(&self, watcher_id: WatcherId) {
         let command = Command::DeregisterWatcher { watcher_id };
-        let _ = self.command_tx.send(command);
+        self.command_tx.send(command);
+    }
+
+    pub(crate) fn hold(&self, watcher_id: WatcherId, token: usize, limit: usize) {
+        let command = Command::Hold {
+            watcher_id,
+            token,
+            limit,
+        };
+        self.command_tx.send(command);
+    }
+
+    pub(crate) fn release(&self, watcher_id: WatcherId, token: usize) {
+        let command = Command::Release { watcher_id, token };
+        self.command_tx.send(command);
+    }
+}
+
+/// A future which represents the reply of a query to `InotifyService`.
+///
+/// This fails if the query could not be sent, or if the service terminated before replying.
+#[derive(Debug)]
+pub struct Reply<T> {
+    inner: Option<Result<oneshot::Receiver<Result<T>>>>,
+}
+impl<T> Reply<T> {
+    fn new(reply_rx: Result<oneshot::Receiver<Result<T>>>) -> Self {
+        Reply {
+            inner: Some(reply_rx),
+        }
+    }
+}
+impl<T> Future for Reply<T> {
+    type Item = T;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner.take().expect("Cannot poll a completed Reply") {
+            Err(e) => Err(e),
+            Ok(mut reply_rx) => {
+                if let Async::Ready(result) = track!(reply_rx.poll().map_err(Error::from))? {
+                    track!(result).map(Async::Ready)
+                } else {
+                    self.inner = Some(Ok(reply_rx));
+                    Ok(Async::NotReady)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CommandSender {
+    inner: mpsc::Sender<Command>,
+    queue_len: Arc<AtomicUsize>,
+    max_pending: Option<usize>, // See `InotifyServiceBuilder::max_pending_requests`
+}
+impl CommandSender {
+    fn send(&self, command: Command) {
+        self.queue_len.fetch_add(1, Ordering::SeqCst);
+        let _ = self.inner.send(command);
+    }
+    fn try_send(&self, command: Command) -> Result<()> {
+        let queue_len = self.queue_len.fetch_add(1, Ordering::SeqCst);
+        if let Some(max_pending) = self.max_pending {
+            if queue_len >= max_pending {
+                self.queue_len.fetch_sub(1, Ordering::SeqCst);
+                track_panic!(
+                    ErrorKind::Busy,
+                    "Too many pending requests: max={}",
+                    max_pending
+                );
+            }
+        }
+        let _ = self.inner.send(command);
+        Ok(())
     }
 }
 
 #[derive(Debug)]
 enum Command {
     RegisterWatcher {
-        watcher_id: WatcherId,
-        path: PathBuf,
-        mask: WatchMask,
-        event_tx: mpsc::Sender<Result<WatcherEvent>>,
+        watcher: Box<WatcherState>,
+    },
+    RegisterWatchers {
+        watchers: Vec<WatcherState>,
     },
     DeregisterWatcher {
         watcher_id: WatcherId,
     },
-}
-
-#[derive(Debug)]
-struct WatcherState {
-    id: WatcherId,
-    inotify_index: usize,
-    wd: WatchDecriptor,
+    DeregisterWatchers {
+        watcher_ids: Vec<WatcherId>,
+    },
+    SetHighPriority {
+        watcher_id: WatcherId,
+        enabled: bool,
+    },
+    GetStats {
+        reply_tx: oneshot::Sender<Result<ServiceStats>>,
+    },
+    TakeDropStats {
+        reply_tx: oneshot::Sender<Result<DropStats>>,
+    },
+    Rewatch {
+        watcher_id: WatcherId,
+        path: PathBuf,
+        reply_tx: oneshot::Sender<Result<()>>,
+    },
+    GetMask {
+        watcher_id: WatcherId,
+        reply_tx: oneshot::Sender<Result<WatchMask>>,
+    },
+    Unwatch {
+        path: PathBuf,
+        is_prefix: bool,
+        reply_tx: oneshot::Sender<Result<usize>>,
+    },
+    Redirect {
+        watcher_id: WatcherId,
+        event_tx: EventSender,
+    },
+    Hold {
+        watcher_id: WatcherId,
+        token: usize,
+        limit: usize,
+    },
+    Release {
+        watcher_id: WatcherId,
+        token: usize,
+    },
+    Shutdown,
+}
+
+// A watcher waiting for the retry of its watch addition
+// (see `InotifyServiceBuilder::add_watch_retries`)
+#[derive(Debug)]
+struct RetryingWatcher {
+    watcher: WatcherState,
+    retry: usize,
+    timer: Timeout,
+}
+
+#[derive(Debug)]
+struct WatcherState {
+    id: WatcherId,
+    inotify_index: usize,
+    wd: Option<WatchDecriptor>,
+    started: bool,
     path: PathBuf,
     mask: WatchMask,
-    event_tx: mpsc::Sender<Result<WatcherEvent>>,
+    options: WatchOptions,
+    event_tx: EventSender,
+    dropped: u64,
+    taken_dropped: u64,       // See `InotifyServiceHandle::take_drop_stats`
+    batch: Vec<InotifyEvent>, // See `WatchOptions::batch_events`
+    last_active: u64,         // See `InotifyServiceBuilder::max_watches`
+    is_syncing: bool,         // See `InotifyServiceHandle::watch_synced`
+    identical_key: Option<(PathBuf, WatchMask)>, // See `dedup_identical_watches`
+    names: Option<HashSet<OsString>>, // See `InotifyServiceHandle::watch_files`
+    holds: Vec<(usize, usize)>, // The tokens and limits of `Watcher::hold`
+    held: VecDeque<Result<WatcherEvent>>, // The items buffered while held
+
+    // The internal watcher of the parent directory (see `WatchOptions::follow_replacement`)
+    parent: Option<WatcherId>,
+
+    // If this is an internal watcher of a parent directory, the watcher following the path
+    follower: Option<WatcherId>,
+}
+impl WatcherState {
+    fn clone_for_rewatch(&self, path: PathBuf) -> Self {
+        WatcherState {
+            id: self.id,
+            inotify_index: self.inotify_index,
+            wd: self.wd,
+            started: self.started,
+            path,
+            mask: self.mask,
+            options: self.options.clone(),
+            event_tx: self.event_tx.clone(),
+            dropped: self.dropped,
+            taken_dropped: self.taken_dropped,
+            batch: self.batch.clone(),
+            last_active: self.last_active,
+            is_syncing: false,
+            identical_key: None,
+            names: self.names.clone(),
+            holds: self.holds.clone(),
+            held: self.held.clone(),
+            parent: self.parent,
+            follower: self.follower,
+        }
+    }
+    fn kernel_mask(&self, merge_masks: bool) -> Result<u32> {
+        let mut mask = self.mask;
+        mask.remove(WatchMask::MASK_ADD);
+        let mut mask = mask.bits() & WATCH_REQUEST_BITS;
+        if self.options.mask_create {
+            track_assert!(
+                kernel_caps::kernel_capabilities().mask_create,
+                ErrorKind::InvalidInput,
+                "`IN_MASK_CREATE` is not supported by the running kernel"
+            );
+            mask |= inotify_sys::IN_MASK_CREATE;
+        } else if merge_masks || self.options.shared {
+            mask |= inotify_sys::IN_MASK_ADD;
+        }
+        Ok(mask)
+    }
+    fn send_event(&mut self, event: InotifyEvent) -> ::std::result::Result<(), SendError> {
+        // `IGNORED` terminates the stream of the watcher, so it must not be dropped (or delayed)
+        let is_ignored = event.mask.contains(EventMask::IGNORED);
+        if self.options.batch_events {
+            self.batch.push(event);
+            return if is_ignored {
+                self.flush_batch()
+            } else {
+                Ok(())
+            };
+        }
+        let result = self.deliver(WatcherEvent::Notified(event), is_ignored);
+        if let Err(SendError::Full(dropped)) = result {
+            self.dropped += dropped;
+        }
+        result
+    }
+    fn flush_batch(&mut self) -> ::std::result::Result<(), SendError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let events = ::std::mem::take(&mut self.batch);
+        let is_ignored = events.iter().any(|e| e.mask.contains(EventMask::IGNORED));
+        let result = self.deliver(WatcherEvent::Batch(events), is_ignored);
+        if let Err(SendError::Full(dropped)) = result {
+            self.dropped += dropped;
+        }
+        result
+    }
+    // Sends `event` to the channel, or buffers it while held (see `Watcher::hold`).
+    // If `is_forced` is `true`, `event` is never dropped.
+    fn deliver(
+        &mut self,
+        event: WatcherEvent,
+        is_forced: bool,
+    ) -> ::std::result::Result<(), SendError> {
+        let limit = match self.hold_limit() {
+            None if is_forced => return self.event_tx.send(Ok(event)),
+            None => return self.event_tx.try_send(event),
+            Some(limit) => limit,
+        };
+        let mut result = Ok(());
+        if self.held.len() >= limit && !is_forced {
+            // Same as `EventSender::try_send`
+            match self.event_tx.policy() {
+                OverflowPolicy::DropNewest => {
+                    return Err(SendError::Full(event_channel::event_count(&event)));
+                }
+                OverflowPolicy::DropOldest => {
+                    // Control events and errors are never dropped
+                    let oldest = self.held.iter().position(|item| {
+                        matches!(
+                            *item,
+                            Ok(WatcherEvent::Notified(_)) | Ok(WatcherEvent::Batch(_))
+                        )
+                    });
+                    match oldest.and_then(|i| self.held.remove(i)) {
+                        Some(Ok(evicted)) => {
+                            result = Err(SendError::Full(event_channel::event_count(&evicted)));
+                        }
+                        _ => return Err(SendError::Full(event_channel::event_count(&event))),
+                    }
+                }
+                OverflowPolicy::Block => {} // The service stops reading instead
+            }
+        }
+        self.held.push_back(Ok(event));
+        result
+    }
+
+    // Sends a control event or an error, which is never dropped.
+    // While held, it is buffered after the held events to keep the order.
+    fn send(&mut self, item: Result<WatcherEvent>) -> ::std::result::Result<(), SendError> {
+        if self.holds.is_empty() {
+            self.event_tx.send(item)
+        } else {
+            self.held.push_back(item);
+            Ok(())
+        }
+    }
+    fn release_held(&mut self) -> ::std::result::Result<(), SendError> {
+        // Delivered regardless of the capacity of the channel, because they have been
+        // kept losslessly
+        let mut result = Ok(());
+        while let Some(item) = self.held.pop_front() {
+            if let Err(e) = self.event_tx.send(item) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+    fn hold_limit(&self) -> Option<usize> {
+        self.holds.iter().map(|&(_, limit)| limit).max()
+    }
+    fn is_held_full(&self) -> bool {
+        self.hold_limit()
+            .is_some_and(|limit| self.held.len() >= limit)
+    }
+    fn is_interested_in(&self, mask: EventMask) -> bool {
+        let always = EventMask::IGNORED | EventMask::UNMOUNT | EventMask::Q_OVERFLOW;
+        mask.intersects(always) || mask.bits() & self.mask.bits() & inotify_sys::IN_ALL_EVENTS != 0
+    }
+    fn is_interested_in_name(&self, name: Option<&Path>) -> bool {
+        // Nameless events (e.g., `IGNORED`) refer to the watched directory itself
+        match (&self.names, name) {
+            (Some(names), Some(name)) => names.contains(name.as_os_str()),
+            _ => true,
+        }
+    }
 }
 
 #[derive(Debug)]
 struct InotifyState {
     inotify: Inotify,
-    wds: HashMap<WatchDecriptor, WatcherId>,
+    wds: HashMap<WatchDecriptor, Vec<WatcherId>>,
+    history: HashMap<WatchDecriptor, VecDeque<InotifyEvent>>, // See `replay_buffer_size`
+    events: u64,                                              // See `ServiceStats::instance_events`
+    is_polled: bool,
 }
 impl InotifyState {
-    fn new() -> Result<Self> {
+    fn new(observer: SharedObserver) -> Result<Self> {
         Ok(InotifyState {
-            inotify: track!(Inotify::new())?,
+            inotify: track!(Inotify::new(observer))?,
             wds: HashMap::new(),
+            history: HashMap::new(),
+            events: 0,
+            is_polled: false,
         })
     }
 }
+
+type BoxShutdownFuture = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+/// See `InotifyServiceBuilder::shutdown_on`.
+#[derive(Clone, Default)]
+struct ShutdownSignal(Arc<Mutex<Option<BoxShutdownFuture>>>);
+impl ShutdownSignal {
+    fn lock(&self) -> MutexGuard<'_, Option<BoxShutdownFuture>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn take(&self) -> Self {
+        ShutdownSignal(Arc::new(Mutex::new(self.lock().take())))
+    }
+    fn is_signaled(&self) -> bool {
+        match self.lock().as_mut() {
+            None => false,
+            Some(future) => !matches!(future.poll(), Ok(Async::NotReady)),
+        }
+    }
+}
+impl fmt::Debug for ShutdownSignal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ShutdownSignal {{ .. }}")
+    }
+}
+
+fn scan_baseline(path: &Path) -> Result<Vec<PathBuf>> {
+    if !track!(fs::metadata(path).map_err(Error::from))?.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut paths = Vec::new();
+    for entry in track!(fs::read_dir(path).map_err(Error::from))? {
+        let entry = track!(entry.map_err(Error::from))?;
+        paths.push(entry.path());
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt;
+    use std::fs;
+    use std::io;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use fibers::{Executor, InPlaceExecutor};
+    use internal_inotify::{
+        AFTER_ADD_WATCH, INJECTED_ADD_WATCH_ERRORS, INJECTED_INIT_ERROR, INJECTED_READ_EOF,
+        INJECTED_READ_ERROR,
+    };
+    use libc;
+    use test_util::{event, TempDir, TestExecutor};
+
+    #[test]
+    fn same_inode_watchers_are_split_into_different_instances() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (event, watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        let (event, watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+        assert!(executor.is_service_alive());
+
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        let index = |id| {
+            let watcher = stats.watchers.iter().find(|w| w.id == id);
+            watcher.map(|w| w.inotify_index)
+        };
+        assert_eq!(index(watcher0.id()), Some(Some(1)));
+        assert_eq!(index(watcher1.id()), Some(Some(0)));
+    }
+
+    #[test]
+    fn stats_contain_watch_descriptors() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("sub")).unwrap();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+        let watcher1 = executor.handle.watch(dir.join("sub"), WatchMask::DELETE);
+        let (_, watcher1) = executor.next_event(watcher1);
+
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        let watch = |id| {
+            let watcher = stats.watchers.iter().find(|w| w.id == id).unwrap();
+            (watcher.path.clone(), watcher.mask, watcher.wd)
+        };
+        assert_eq!(
+            watch(watcher0.id()),
+            (dir.path().to_path_buf(), WatchMask::CREATE, Some(1))
+        );
+        assert_eq!(
+            watch(watcher1.id()),
+            (dir.join("sub"), WatchMask::DELETE, Some(2))
+        );
+    }
+
+    #[test]
+    fn stats_contain_instance_events() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let (_, watcher1) = executor.next_event(watcher1);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::write(dir.join("bar"), b"").unwrap();
+        fs::remove_file(dir.join("foo")).unwrap();
+        let (_, watcher0) = executor.next_event(watcher0);
+        let (_, watcher0) = executor.next_event(watcher0);
+        let (_, watcher1) = executor.next_event(watcher1);
+
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.instance_count, 2);
+        let index = |id| {
+            let watcher = stats.watchers.iter().find(|w| w.id == id).unwrap();
+            watcher.inotify_index.unwrap()
+        };
+        assert_eq!(stats.instance_events[index(watcher0.id())], 2);
+        assert_eq!(stats.instance_events[index(watcher1.id())], 1);
+    }
+
+    #[test]
+    fn kicked_out_watcher_receives_queued_events_first() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+
+        // The event is queued in the kernel when `watcher1` kicks out `watcher0`
+        fs::write(dir.join("foo"), b"").unwrap();
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let (event, _watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        let (event, watcher0) = executor.next_event(watcher0);
+        assert!(is_notified(&event, EventMask::CREATE));
+        let (event, _watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+    }
+
+    #[test]
+    fn drop_watcher_after_kernel_removed_watch() {
+        let dir = TempDir::new();
+        let file = dir.join("foo");
+        fs::write(&file, b"").unwrap();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor.handle.watch(&file, WatchMask::DELETE_SELF);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        fs::remove_file(&file).unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::DELETE_SELF));
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::IGNORED));
+
+        // Deregistering the watcher must not try to remove the (already removed) kernel watch
+        drop(watcher);
+        executor.run_for(Duration::from_millis(10));
+        assert!(executor.is_service_alive());
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (event, _watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        assert!(executor.is_service_alive());
+    }
+
+    #[test]
+    fn flush_on_shutdown_works() {
+        let dir = TempDir::new();
+        let service = InotifyServiceBuilder::new()
+            .flush_on_shutdown(true)
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        executor.handle.shutdown();
+        executor.wait_service().unwrap();
+
+        let events = executor.wait(watcher.collect()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(is_notified(&events[0], EventMask::CREATE));
+    }
+
+    #[test]
+    fn shutdown_on_works() {
+        let dir = TempDir::new();
+        let (signal_tx, signal_rx) = oneshot::channel::<()>();
+        let service = InotifyServiceBuilder::new().shutdown_on(signal_rx).finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        executor.run_for(Duration::from_millis(10));
+        assert!(executor.is_service_alive());
+
+        signal_tx.send(()).unwrap();
+        executor.wait_service().unwrap();
+        assert!(!executor.handle.is_alive());
+        assert!(executor.wait(watcher.collect()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn max_pending_requests_works() {
+        let dir = TempDir::new();
+        let service = InotifyServiceBuilder::new()
+            .max_pending_requests(1)
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+
+        let (event, _watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        let e = executor.wait(watcher1.into_future()).err().unwrap().0;
+        assert_eq!(*e.kind(), ErrorKind::Busy);
+
+        // Waits until the service catches up
+        executor.run_for(Duration::from_millis(10));
+        let watcher2 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let (event, watcher2) = executor.next_event(watcher2);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        // Deregistrations are never rejected, but they are counted as pending requests
+        std::mem::drop(watcher2);
+        let e = executor.wait(executor.handle.stats()).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Busy);
+        executor.run_for(Duration::from_millis(10));
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.watchers.len(), 1);
+    }
+
+    #[test]
+    fn mask_create_works() {
+        if !kernel_caps::kernel_capabilities().mask_create {
+            return;
+        }
+
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (event, watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        let options = WatchOptions {
+            mask_create: true,
+            ..Default::default()
+        };
+        let watcher1 = executor
+            .handle
+            .watch_with_options(dir.path(), WatchMask::DELETE, options);
+        let e = executor.wait(watcher1.into_future()).err().unwrap().0;
+        assert_eq!(*e.kind(), ErrorKind::AlreadyExists);
+
+        // `watcher0` has not been kicked out
+        fs::write(dir.join("foo"), b"").unwrap();
+        let (event, _watcher0) = executor.next_event(watcher0);
+        assert!(is_notified(&event, EventMask::CREATE));
+    }
+
+    #[test]
+    fn batch_events_works() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("sub")).unwrap();
+        let mut executor = TestExecutor::new();
+
+        let options = WatchOptions {
+            batch_events: true,
+            ..Default::default()
+        };
+        let mask = WatchMask::CREATE | WatchMask::DELETE_SELF;
+        let watcher = executor
+            .handle
+            .watch_with_options(dir.join("sub"), mask, options);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        for name in &["foo", "bar", "baz"] {
+            fs::write(dir.join("sub").join(name), b"").unwrap();
+        }
+        let (event, watcher) = executor.next_event(watcher);
+        if let WatcherEvent::Batch(ref events) = event {
+            let names = events.iter().map(|e| e.name.clone().unwrap());
+            let names = names.map(|n| n.into_os_string()).collect::<Vec<_>>();
+            assert_eq!(names, ["foo", "bar", "baz"]);
+        } else {
+            panic!("Unexpected event: {:?}", event);
+        }
+
+        // A batch containing `IGNORED` terminates the watcher
+        fs::remove_dir_all(dir.join("sub")).unwrap();
+        let events = executor.wait(watcher.collect()).unwrap();
+        assert_eq!(events.len(), 1);
+        if let WatcherEvent::Batch(ref events) = events[0] {
+            assert!(events.last().unwrap().mask.contains(EventMask::IGNORED));
+        } else {
+            panic!("Unexpected event: {:?}", events[0]);
+        }
+    }
+
+    #[test]
+    fn kicked_out_watcher_fails_alone() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+
+        // The instance for the kicked out watcher cannot be created
+        INJECTED_INIT_ERROR.with(|e| e.set(Some(libc::EMFILE)));
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        let e = executor.wait(watcher0.into_future()).err().unwrap().0;
+        assert_eq!(*e.kind(), ErrorKind::WatchLimitExceeded);
+
+        // The service and the other watcher keep running
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::remove_file(dir.join("foo")).unwrap();
+        let (event, _watcher1) = executor.next_event(watcher1);
+        assert!(is_notified(&event, EventMask::DELETE));
+        assert!(executor.is_service_alive());
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.watchers.len(), 1);
+    }
+
+    #[test]
+    fn add_watch_retries_works() {
+        let dir = TempDir::new();
+        let service = InotifyServiceBuilder::new().add_watch_retries(2).finish();
+        let mut executor = TestExecutor::with_service(service);
+        let inject = |errors: &[i32]| {
+            INJECTED_ADD_WATCH_ERRORS.with(|e| e.borrow_mut().extend(errors));
+        };
+
+        // Transient errors
+        inject(&[libc::EINTR, libc::ENOMEM]);
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (event, _watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        // Too many transient errors
+        inject(&[libc::EAGAIN, libc::EAGAIN, libc::EAGAIN]);
+        let watcher = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let e = executor.wait(watcher.into_future()).err().unwrap().0;
+        assert_eq!(*e.kind(), ErrorKind::Other);
+
+        // Permanent errors are not retried
+        inject(&[libc::EACCES, libc::EACCES]);
+        let watcher = executor.handle.watch(dir.path(), WatchMask::MODIFY);
+        let e = executor.wait(watcher.into_future()).err().unwrap().0;
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        assert_eq!(INJECTED_ADD_WATCH_ERRORS.with(|e| e.borrow_mut().len()), 1);
+        INJECTED_ADD_WATCH_ERRORS.with(|e| e.borrow_mut().clear());
+        assert!(executor.is_service_alive());
+    }
+
+    #[test]
+    fn add_watch_retries_do_not_block_service() {
+        let dir = TempDir::new();
+        let service = InotifyServiceBuilder::new().add_watch_retries(8).finish();
+        let mut executor = TestExecutor::with_service(service);
+        INJECTED_ADD_WATCH_ERRORS.with(|e| e.borrow_mut().extend(&[libc::EAGAIN; 8]));
+
+        // The service replies while the watcher is waiting for the retries
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert!(stats.watchers.is_empty());
+        assert_eq!(INJECTED_ADD_WATCH_ERRORS.with(|e| e.borrow_mut().len()), 7);
+
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        assert!(INJECTED_ADD_WATCH_ERRORS.with(|e| e.borrow_mut().is_empty()));
+        fs::write(dir.join("foo"), b"").unwrap();
+        let (event, _watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::CREATE));
+    }
+
+    #[test]
+    fn recover_dead_instance() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        // Simulates that the file descriptor of the instance has been closed
+        INJECTED_READ_ERROR.with(|e| e.set(Some(libc::EBADF)));
+        fs::write(dir.join("foo"), b"").unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+
+        fs::write(dir.join("bar"), b"").unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::CREATE));
+        assert!(executor.is_service_alive());
+
+        // A read returning zero bytes is also regarded as a dead instance
+        INJECTED_READ_EOF.with(|e| e.set(true));
+        fs::write(dir.join("baz"), b"").unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+
+        fs::write(dir.join("qux"), b"").unwrap();
+        let (event, _watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::CREATE));
+    }
+
+    #[test]
+    fn stat_inode_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let options = WatchOptions {
+            stat_inode: true,
+            ..Default::default()
+        };
+        let watcher = executor
+            .handle
+            .watch_with_options(dir.path(), WatchMask::CREATE, options);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::hard_link(dir.join("foo"), dir.join("bar")).unwrap();
+        let (foo, watcher) = executor.next_event(watcher);
+        let (bar, _watcher) = executor.next_event(watcher);
+        match (foo, bar) {
+            (WatcherEvent::Notified(foo), WatcherEvent::Notified(bar)) => {
+                assert!(foo.inode.is_some());
+                assert_eq!(foo.inode, bar.inode);
+            }
+            events => panic!("Unexpected events: {:?}", events),
+        }
+    }
+
+    #[test]
+    fn observer_works() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let (_, watcher1) = executor.next_event(watcher1);
+        std::mem::drop(watcher0);
+        std::mem::drop(watcher1);
+        executor.run_for(Duration::from_millis(10));
+
+        assert_eq!(*observer.instance_counts.lock().unwrap(), [1, 2, 1, 0]);
+        assert_eq!(*observer.watcher_counts.lock().unwrap(), [1, 2, 1, 0]);
+    }
+
+    #[test]
+    fn event_delivered_hook_works() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher) = executor.next_event(watcher);
+        assert!(observer.delivered_events.lock().unwrap().is_empty());
+
+        // The event waits in the channel until the watcher is polled
+        fs::write(dir.join("foo"), b"").unwrap();
+        executor.run_for(Duration::from_millis(50));
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::CREATE));
+
+        let delivered = observer.delivered_events.lock().unwrap();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].0, watcher.id());
+        assert!(delivered[0].1 >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn disconnect_policy_works() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let options = WatchOptions {
+            disconnect_policy: DisconnectPolicy::Report,
+            ..Default::default()
+        };
+        let reported = executor
+            .handle
+            .watch_with_options(dir.path(), WatchMask::CREATE, options);
+        let (_, reported) = executor.next_event(reported);
+        let cleaned = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, cleaned) = executor.next_event(cleaned);
+
+        // Dropped without the deregistration commands
+        let reported_id = reported.detach();
+        cleaned.detach();
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::write(dir.join("bar"), b"").unwrap();
+        executor.run_for(Duration::from_millis(50));
+
+        assert_eq!(
+            *observer.disconnected.lock().unwrap(),
+            [(reported_id, dir.path().to_path_buf())]
+        );
+        let stats = executor.handle.stats();
+        assert!(executor.wait(stats).unwrap().watchers.is_empty());
+    }
+
+    #[test]
+    fn fatal_disconnect_policy_terminates_service() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let options = WatchOptions {
+            disconnect_policy: DisconnectPolicy::Fatal,
+            ..Default::default()
+        };
+        let watcher = executor
+            .handle
+            .watch_with_options(dir.path(), WatchMask::CREATE, options);
+        let (_, watcher) = executor.next_event(watcher);
+        let id = watcher.detach();
+        fs::write(dir.join("foo"), b"").unwrap();
+
+        let error = executor.wait_service().err().unwrap();
+        assert_eq!(*error.kind(), ErrorKind::Other);
+        assert_eq!(
+            *observer.disconnected.lock().unwrap(),
+            [(id, dir.path().to_path_buf())]
+        );
+    }
+
+    #[test]
+    fn instance_hooks_work() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let (_, watcher1) = executor.next_event(watcher1);
+        assert_eq!(
+            *observer.created_instances.lock().unwrap(),
+            [
+                (0, InstanceReason::FirstWatcher),
+                (1, InstanceReason::Collision)
+            ]
+        );
+
+        // Re-creates the instance of `watcher1`
+        INJECTED_READ_ERROR.with(|e| e.set(Some(libc::EBADF)));
+        fs::write(dir.join("foo"), b"").unwrap();
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+        assert_eq!(
+            observer.created_instances.lock().unwrap()[2],
+            (0, InstanceReason::Recovery)
+        );
+        assert_eq!(*observer.destroyed_instances.lock().unwrap(), [0]);
+
+        std::mem::drop(watcher0);
+        executor.run_for(Duration::from_millis(10));
+        assert_eq!(*observer.destroyed_instances.lock().unwrap(), [0, 1]);
+
+        std::mem::drop(watcher1);
+        std::mem::drop(executor);
+        assert_eq!(*observer.destroyed_instances.lock().unwrap(), [0, 1, 0]);
+    }
+
+    #[test]
+    fn watch_removed_hook_works() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .merge_masks(true)
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let (_, watcher1) = executor.next_event(watcher1);
+        let watcher1_id = watcher1.id();
+
+        // The kernel watch is still shared with `watcher1`
+        std::mem::drop(watcher0);
+        executor.run_for(Duration::from_millis(10));
+        assert!(observer.removed_watches.lock().unwrap().is_empty());
+
+        std::mem::drop(watcher1);
+        executor.run_for(Duration::from_millis(10));
+        assert_eq!(
+            *observer.removed_watches.lock().unwrap(),
+            [(watcher1_id, dir.path().to_path_buf())]
+        );
+    }
+
+    #[test]
+    fn watch_batch_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watchers = executor.handle.watch_batch(vec![
+            (dir.path().to_path_buf(), WatchMask::CREATE),
+            (dir.join("nonexistent"), WatchMask::CREATE),
+            (dir.path().to_path_buf(), WatchMask::DELETE),
+        ]);
+        let mut watchers = watchers.into_iter();
+
+        let (event, watcher0) = executor.next_event(watchers.next().unwrap());
+        assert_eq!(event, WatcherEvent::StartWatching);
+        let (event, _watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+
+        let watcher1 = watchers.next().unwrap();
+        let e = executor.wait(watcher1.into_future()).err().unwrap().0;
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        let (event, _watcher2) = executor.next_event(watchers.next().unwrap());
+        assert_eq!(event, WatcherEvent::StartWatching);
+    }
+
+    #[test]
+    fn deregister_batch_works() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let mut watchers = Vec::new();
+        for mask in &[WatchMask::CREATE, WatchMask::DELETE, WatchMask::MODIFY] {
+            let watcher = executor.handle.watch(dir.path(), *mask);
+            let (_, watcher) = executor.next_event(watcher);
+            watchers.push(watcher);
+        }
+        executor.handle.deregister_batch(watchers);
+        executor.run_for(Duration::from_millis(10));
+
+        assert_eq!(*observer.instance_counts.lock().unwrap(), [1, 2, 3, 0]);
+        assert_eq!(*observer.watcher_counts.lock().unwrap(), [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn into_std_receiver_works() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let watcher_id = watcher.id();
+        let rx = watcher.into_std_receiver(&executor.executor.handle());
+        executor.run_for(Duration::from_millis(10));
+        assert_eq!(rx.try_recv().unwrap().unwrap(), WatcherEvent::StartWatching);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        executor.run_for(Duration::from_millis(10));
+        let event = rx.try_recv().unwrap().unwrap();
+        assert!(is_notified(&event, EventMask::CREATE));
+
+        // The forwarder terminates as soon as the receiver is dropped, and the watch is released
+        std::mem::drop(rx);
+        executor.run_for(Duration::from_millis(10));
+        assert_eq!(
+            *observer.removed_watches.lock().unwrap(),
+            [(watcher_id, dir.path().to_path_buf())]
+        );
+    }
+
+    #[test]
+    fn stray_mask_bits_are_stripped() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let stray_bits = inotify_sys::IN_IGNORED | inotify_sys::IN_Q_OVERFLOW;
+        let mask = unsafe { WatchMask::from_bits_unchecked(inotify_sys::IN_CREATE | stray_bits) };
+        let watcher = executor.handle.watch(dir.path(), mask);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        assert_eq!(
+            *observer.stripped_masks.lock().unwrap(),
+            [(watcher.id(), stray_bits)]
+        );
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        let (event, _watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::CREATE));
+
+        // Valid control bits are not reported
+        let mask = WatchMask::CREATE | WatchMask::ONLYDIR | WatchMask::MASK_ADD;
+        let watcher = executor.handle.watch(dir.path(), mask);
+        let (event, _watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        assert_eq!(observer.stripped_masks.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn hold_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let options = WatchOptions {
+            channel_capacity: Some(1),
+            ..Default::default()
+        };
+        let watcher = executor
+            .handle
+            .watch_with_options(dir.path(), WatchMask::CREATE, options);
+        let (_, mut watcher) = executor.next_event(watcher);
+
+        // The events are buffered by the service until all the guards are dropped
+        let guard0 = watcher.hold(10);
+        let guard1 = watcher.hold(10);
+        for name in &["foo", "bar", "baz"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        executor.run_for(Duration::from_millis(10));
+        assert_eq!(watcher.pending_len(), 0);
+        std::mem::drop(guard0);
+        executor.run_for(Duration::from_millis(10));
+        assert_eq!(watcher.pending_len(), 0);
+
+        std::mem::drop(guard1);
+        for name in &["foo", "bar", "baz"] {
+            let (event, w) = executor.next_event(watcher);
+            match event {
+                WatcherEvent::Notified(e) => assert_eq!(e.name, Some(PathBuf::from(name))),
+                e => panic!("Unexpected event: {:?}", e),
+            }
+            watcher = w;
+        }
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.watchers[0].dropped, 0);
+    }
+
+    #[test]
+    fn hold_limit_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let options = WatchOptions {
+            channel_capacity: Some(1),
+            overflow_policy: OverflowPolicy::DropOldest,
+            ..Default::default()
+        };
+        let watcher = executor
+            .handle
+            .watch_with_options(dir.path(), WatchMask::CREATE, options);
+        let (_, mut watcher) = executor.next_event(watcher);
+
+        // The events exceeding the limit are handled by the overflow policy
+        let guard = watcher.hold(2);
+        for name in &["foo", "bar", "baz"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        executor.run_for(Duration::from_millis(10));
+        std::mem::drop(guard);
+        executor.run_for(Duration::from_millis(10));
+        assert_eq!(watcher.pending_len(), 2);
+        for name in &["bar", "baz"] {
+            let (event, w) = executor.next_event(watcher);
+            match event {
+                WatcherEvent::Notified(e) => assert_eq!(e.name, Some(PathBuf::from(name))),
+                e => panic!("Unexpected event: {:?}", e),
+            }
+            watcher = w;
+        }
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.watchers[0].dropped, 1);
+    }
+
+    #[test]
+    fn take_drop_stats_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let options = WatchOptions {
+            channel_capacity: Some(1),
+            ..Default::default()
+        };
+        let watcher = executor
+            .handle
+            .watch_with_options(dir.path(), WatchMask::CREATE, options);
+        let (_, watcher) = executor.next_event(watcher);
+        for name in &["foo", "bar", "baz"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        executor.run_for(Duration::from_millis(10));
+
+        let stats = executor.wait(executor.handle.take_drop_stats()).unwrap();
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(stats.watchers.get(&watcher.id()), Some(&2));
+        let stats = executor.wait(executor.handle.take_drop_stats()).unwrap();
+        assert_eq!(stats.dropped, 0);
+        assert_eq!(stats.watchers.get(&watcher.id()), Some(&0));
+
+        // The cumulative counter is not reset
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.watchers[0].dropped, 2);
+
+        // The drops of deregistered watchers are also counted
+        let (_, watcher) = executor.next_event(watcher);
+        for name in &["qux", "quux"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        executor.run_for(Duration::from_millis(10));
+        std::mem::drop(watcher);
+        executor.run_for(Duration::from_millis(10));
+        let stats = executor.wait(executor.handle.take_drop_stats()).unwrap();
+        assert_eq!(stats.dropped, 1);
+        assert!(stats.watchers.is_empty());
+    }
+
+    #[test]
+    fn bounded_event_channel_works() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let options = WatchOptions {
+            channel_capacity: Some(2),
+            ..Default::default()
+        };
+        let watcher = executor
+            .handle
+            .watch_with_options(dir.path(), WatchMask::CREATE, options);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        for name in &["foo", "bar", "baz", "qux"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        executor.run_for(Duration::from_millis(10));
+
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.instance_count, 1);
+        assert_eq!(stats.watchers.len(), 1);
+        assert_eq!(stats.watchers[0].dropped, 2);
+        assert_eq!(stats.watchers[0].pending_len, 2);
+        assert_eq!(watcher.pending_len(), 2);
+        let id = stats.watchers[0].id;
+        assert_eq!(*observer.lagged.lock().unwrap(), [(id, 1), (id, 2)]);
+
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::CREATE));
+        assert_eq!(watcher.pending_len(), 1);
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::CREATE));
+        assert_eq!(watcher.pending_len(), 0);
+
+        // The consumer has caught up
+        fs::write(dir.join("quux"), b"").unwrap();
+        let (event, _watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::CREATE));
+    }
+
+    #[test]
+    fn overflow_policy_works() {
+        let received_names = |policy, count| {
+            let dir = TempDir::new();
+            let mut executor = TestExecutor::new();
+            let options = WatchOptions {
+                channel_capacity: Some(2),
+                overflow_policy: policy,
+                ..Default::default()
+            };
+            let watcher =
+                executor
+                    .handle
+                    .watch_with_options(dir.path(), WatchMask::CREATE, options);
+            let (_, mut watcher) = executor.next_event(watcher);
+
+            for name in &["foo", "bar", "baz", "qux"] {
+                fs::write(dir.join(name), b"").unwrap();
+            }
+            executor.run_for(Duration::from_millis(10));
+
+            let mut names = Vec::new();
+            for _ in 0..count {
+                let (event, w) = executor.next_event(watcher);
+                watcher = w;
+                if let WatcherEvent::Notified(e) = event {
+                    names.push(e.name.unwrap());
+                }
+            }
+            let stats = executor.wait(executor.handle.stats()).unwrap();
+            (names, stats.watchers[0].dropped)
+        };
+
+        let (names, dropped) = received_names(OverflowPolicy::DropOldest, 2);
+        assert_eq!(names, [Path::new("baz"), Path::new("qux")]);
+        assert_eq!(dropped, 2);
+
+        let (names, dropped) = received_names(OverflowPolicy::Block, 4);
+        assert_eq!(
+            names,
+            [
+                Path::new("foo"),
+                Path::new("bar"),
+                Path::new("baz"),
+                Path::new("qux")
+            ]
+        );
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn rename_into_and_out_of_watched_set_works() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("watched")).unwrap();
+        fs::create_dir(dir.join("unwatched")).unwrap();
+        fs::write(dir.join("watched/foo"), b"").unwrap();
+        fs::write(dir.join("unwatched/bar"), b"").unwrap();
+        let service = InotifyServiceBuilder::new()
+            .rename_expiry(Duration::from_millis(10))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let options = WatchOptions {
+            track_renames: true,
+            ..Default::default()
+        };
+        let mask = WatchMask::MOVED_FROM | WatchMask::MOVED_TO;
+        let watcher = executor
+            .handle
+            .watch_with_options(dir.join("watched"), mask, options);
+        let (_, watcher) = executor.next_event(watcher);
+
+        // Out of the watched set
+        fs::rename(dir.join("watched/foo"), dir.join("unwatched/foo")).unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::MOVED_FROM));
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(
+            event,
+            WatcherEvent::Moved {
+                from_path: Some(dir.join("watched/foo")),
+                to_path: None,
+            }
+        );
+
+        // Into the watched set
+        fs::rename(dir.join("unwatched/bar"), dir.join("watched/bar")).unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::MOVED_TO));
+        let (event, _watcher) = executor.next_event(watcher);
+        assert_eq!(
+            event,
+            WatcherEvent::Moved {
+                from_path: None,
+                to_path: Some(dir.join("watched/bar")),
+            }
+        );
+    }
+
+    #[test]
+    fn cross_directory_rename_works() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("a")).unwrap();
+        fs::create_dir(dir.join("b")).unwrap();
+        fs::write(dir.join("a/foo"), b"").unwrap();
+        let mut executor = TestExecutor::new();
+
+        let options = WatchOptions {
+            track_renames: true,
+            ..Default::default()
+        };
+        let mask = WatchMask::MOVED_FROM | WatchMask::MOVED_TO;
+        let watcher_a = executor
+            .handle
+            .watch_with_options(dir.join("a"), mask, options.clone());
+        let (_, watcher_a) = executor.next_event(watcher_a);
+        let watcher_b = executor
+            .handle
+            .watch_with_options(dir.join("b"), mask, options);
+        let (_, watcher_b) = executor.next_event(watcher_b);
+
+        fs::rename(dir.join("a/foo"), dir.join("b/bar")).unwrap();
+        let moved = WatcherEvent::Moved {
+            from_path: Some(dir.join("a/foo")),
+            to_path: Some(dir.join("b/bar")),
+        };
+
+        let (event, watcher_a) = executor.next_event(watcher_a);
+        assert!(is_notified(&event, EventMask::MOVED_FROM));
+        let (event, _watcher_a) = executor.next_event(watcher_a);
+        assert_eq!(event, moved);
+
+        let (event, watcher_b) = executor.next_event(watcher_b);
+        assert!(is_notified(&event, EventMask::MOVED_TO));
+        let (event, _watcher_b) = executor.next_event(watcher_b);
+        assert_eq!(event, moved);
+    }
+
+    #[test]
+    fn observer_tracing_works() {
+        let dir = TempDir::new();
+        let observer = Arc::new(TestObserver::default());
+        let service = InotifyServiceBuilder::new()
+            .observer(Arc::clone(&observer))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher) = executor.next_event(watcher);
+        fs::write(dir.join("foo"), b"").unwrap();
+        let (_, watcher) = executor.next_event(watcher);
+        std::mem::drop(watcher);
+        executor.run_for(Duration::from_millis(10));
+
+        let syscalls = observer.syscalls.lock().unwrap();
+        assert_eq!(syscalls[0], "inotify_init1");
+        assert_eq!(syscalls[1], "inotify_add_watch");
+        assert!(syscalls.contains(&"read".to_owned()));
+        assert!(syscalls.contains(&"inotify_rm_watch".to_owned()));
+
+        let raw_events = observer.raw_events.lock().unwrap();
+        assert_eq!(raw_events[0].mask, EventMask::CREATE);
+        assert_eq!(raw_events[0].name, Some(PathBuf::from("foo")));
+    }
+
+    #[test]
+    fn discard_initial_events_works() {
+        let dir = TempDir::new();
+        let service = InotifyServiceBuilder::new()
+            .discard_initial_events(true)
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        // Queues an event in the new instance before its first polling
+        let path = dir.join("foo");
+        let create = move || fs::write(path, b"").unwrap();
+        AFTER_ADD_WATCH.with(|f| *f.borrow_mut() = Some(Box::new(create)));
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        // Events after the first polling are delivered as usual
+        fs::write(dir.join("bar"), b"").unwrap();
+        let (event, _watcher) = executor.next_event(watcher);
+        match event {
+            WatcherEvent::Notified(e) => assert_eq!(e.name, Some(PathBuf::from("bar"))),
+            _ => panic!("Unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn discard_initial_events_keeps_control_events() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("sub")).unwrap();
+        let service = InotifyServiceBuilder::new()
+            .discard_initial_events(true)
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        // Queues events in the new instance before its first polling
+        let path = dir.join("sub");
+        AFTER_ADD_WATCH.with(|f| {
+            *f.borrow_mut() = Some(Box::new(move || {
+                fs::write(path.join("foo"), b"").unwrap();
+                fs::remove_file(path.join("foo")).unwrap();
+                fs::remove_dir(path).unwrap();
+            }))
+        });
+        let mask = WatchMask::CREATE | WatchMask::DELETE_SELF;
+        let watcher = executor.handle.watch(dir.join("sub"), mask);
+        let events = executor.wait(watcher.collect()).unwrap();
+        assert_eq!(events.len(), 2, "{:?}", events);
+        assert_eq!(events[0], WatcherEvent::StartWatching);
+        assert!(is_notified(&events[1], EventMask::IGNORED));
+    }
+
+    #[test]
+    fn merge_masks_works() {
+        let dir = TempDir::new();
+        let service = InotifyServiceBuilder::new().merge_masks(true).finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        // Both watchers share a watch in the same instance
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.instance_count, 1);
+
+        // Each watcher receives only the events matching its own mask
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::remove_file(dir.join("foo")).unwrap();
+        fs::write(dir.join("bar"), b"").unwrap();
+        let (event, watcher0) = executor.next_event(watcher0);
+        assert!(is_notified(&event, EventMask::CREATE));
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert!(is_notified(&event, EventMask::DELETE));
+
+        // The shared watch survives as long as one of the watchers is alive
+        drop(watcher0);
+        executor.run_for(Duration::from_millis(10));
+        fs::remove_file(dir.join("bar")).unwrap();
+        let (event, _watcher1) = executor.next_event(watcher1);
+        assert!(is_notified(&event, EventMask::DELETE));
+    }
+
+    #[test]
+    fn watch_deduped_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch_deduped(dir.path(), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+        let watcher1 = executor.handle.watch_deduped(dir.path(), WatchMask::DELETE);
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.instance_count, 1);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::remove_file(dir.join("foo")).unwrap();
+        let (event, watcher0) = executor.next_event(watcher0);
+        assert!(is_notified(&event, EventMask::CREATE));
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert!(is_notified(&event, EventMask::DELETE));
+
+        // A non-shared watcher kicks out the shared ones
+        let watcher2 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, _watcher2) = executor.next_event(watcher2);
+        let (event, _watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+        let (event, _watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.instance_count, 2);
+    }
+
+    #[test]
+    fn placement_strategy_works() {
+        let dir = TempDir::new();
+        for name in &["a", "b", "c"] {
+            fs::create_dir(dir.join(name)).unwrap();
+        }
+        let instance_count = |strategy| {
+            let service = InotifyServiceBuilder::new()
+                .placement_strategy(strategy)
+                .finish();
+            let mut executor = TestExecutor::with_service(service);
+            let watchers = ["a", "b", "c"]
+                .iter()
+                .map(|name| {
+                    let watcher = executor.handle.watch(dir.join(name), WatchMask::CREATE);
+                    executor.next_event(watcher).1
+                })
+                .collect::<Vec<_>>();
+            let stats = executor.wait(executor.handle.stats()).unwrap();
+            std::mem::drop(watchers);
+            stats.instance_count
+        };
+        assert_eq!(instance_count(PlacementStrategy::Compact), 1);
+        assert_eq!(instance_count(PlacementStrategy::Spread(2)), 2);
+        assert_eq!(instance_count(PlacementStrategy::Dedicated), 3);
+    }
+
+    #[test]
+    fn start_works() {
+        let dir = TempDir::new();
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let handle = InotifyService::new().start(&executor.handle());
+
+        let watcher = handle.watch(dir.path(), WatchMask::CREATE);
+        let monitor = executor.spawn_monitor(watcher.into_future().map_err(|(e, _)| e));
+        let (event, _watcher) = executor.run_fiber(monitor).unwrap().unwrap();
+        assert_eq!(event, Some(WatcherEvent::StartWatching));
+        assert!(handle.is_alive());
+        assert!(handle.error().is_none());
+
+        // The service is terminated by an error (instead of panicking)
+        INJECTED_READ_ERROR.with(|e| e.set(Some(libc::EIO)));
+        fs::write(dir.join("foo"), b"").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.is_alive() {
+            assert!(Instant::now() < deadline, "Timeout");
+            executor.run_once().unwrap();
+        }
+        assert_eq!(handle.error().map(|e| *e.kind()), Some(ErrorKind::Other));
+    }
+
+    #[test]
+    fn duplicate_watcher_id_is_rejected() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+
+        let (watcher1, mut state) =
+            executor
+                .handle
+                .new_watcher(dir.path(), WatchMask::CREATE, WatchOptions::default());
+        state.id = watcher0.id();
+        let command = Command::RegisterWatcher {
+            watcher: Box::new(state),
+        };
+        executor.handle.command_tx.try_send(command).unwrap();
+
+        let e = executor.wait(watcher1.into_future()).err().unwrap().0;
+        assert_eq!(*e.kind(), ErrorKind::Other);
+
+        // The service and the existing watcher are not affected
+        fs::write(dir.join("foo"), b"").unwrap();
+        let (event, _watcher0) = executor.next_event(watcher0);
+        assert!(is_notified(&event, EventMask::CREATE));
+        assert!(executor.is_service_alive());
+    }
+
+    #[test]
+    fn max_watches_works() {
+        let dir = TempDir::new();
+        for name in &["foo", "bar", "baz"] {
+            fs::create_dir(dir.join(name)).unwrap();
+        }
+        let service = InotifyServiceBuilder::new().max_watches(2).finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let foo = executor.handle.watch(dir.join("foo"), WatchMask::CREATE);
+        let (event, foo) = executor.next_event(foo);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        let bar = executor.handle.watch(dir.join("bar"), WatchMask::CREATE);
+        let (event, bar) = executor.next_event(bar);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        // `foo` becomes more recently active than `bar`
+        fs::write(dir.join("foo/a"), b"").unwrap();
+        let (event, foo) = executor.next_event(foo);
+        assert!(matches!(event, WatcherEvent::Notified(_)));
+
+        let baz = executor.handle.watch(dir.join("baz"), WatchMask::CREATE);
+        let (event, _baz) = executor.next_event(baz);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        let (event, bar) = executor.next_event(bar);
+        assert_eq!(event, WatcherEvent::Evicted);
+        assert_eq!(executor.wait(bar.into_future()).ok().unwrap().0, None);
+
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.watchers.len(), 2);
+        fs::write(dir.join("foo/b"), b"").unwrap();
+        let (event, _) = executor.next_event(foo);
+        assert!(matches!(event, WatcherEvent::Notified(_)));
+
+        // No room at all
+        let service = InotifyServiceBuilder::new().max_watches(0).finish();
+        let mut executor = TestExecutor::with_service(service);
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        assert!(executor.wait(watcher.into_future()).is_err());
+    }
+
+    #[test]
+    fn dedup_identical_watches_works() {
+        let dir = TempDir::new();
+        let service = InotifyServiceBuilder::new()
+            .dedup_identical_watches(true)
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (event, watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        let watcher1 = executor.handle.watch(
+            dir.join("../").join(dir.path().file_name().unwrap()),
+            WatchMask::CREATE,
+        );
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.instance_count, 1);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        let (event, watcher0) = executor.next_event(watcher0);
+        assert!(is_notified(&event, EventMask::CREATE));
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert!(is_notified(&event, EventMask::CREATE));
+
+        // The watch is kept until the last watcher is dropped
+        std::mem::drop(watcher0);
+        executor.run_for(Duration::from_millis(10));
+        fs::write(dir.join("bar"), b"").unwrap();
+        let (event, _watcher1) = executor.next_event(watcher1);
+        assert!(is_notified(&event, EventMask::CREATE));
+
+        // A different mask is not deduplicated
+        let watcher2 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        let (event, _watcher2) = executor.next_event(watcher2);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.instance_count, 2);
+    }
+
+    #[test]
+    fn dedup_identical_watches_with_case_insensitive_paths() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("Data")).unwrap();
+        let service = InotifyServiceBuilder::new()
+            .dedup_identical_watches(true)
+            .case_insensitive_paths(true)
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher0 = executor.handle.watch(dir.join("Data"), WatchMask::CREATE);
+        let (event, watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        // Joins the existing watch without asking the (case-sensitive) filesystem
+        let watcher1 = executor.handle.watch(dir.join("data"), WatchMask::CREATE);
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.instance_count, 1);
+        assert_eq!(stats.watchers.len(), 2);
+
+        fs::write(dir.join("Data/foo"), b"").unwrap();
+        let (event, _watcher0) = executor.next_event(watcher0);
+        assert!(is_notified(&event, EventMask::CREATE));
+        let (event, _watcher1) = executor.next_event(watcher1);
+        assert!(is_notified(&event, EventMask::CREATE));
+    }
+
+    #[test]
+    fn watch_files_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let names = vec!["foo".into(), "baz".into()];
+        let watcher = executor
+            .handle
+            .watch_files(dir.path(), names, WatchMask::CREATE);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        fs::write(dir.join("bar"), b"").unwrap();
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::write(dir.join("qux"), b"").unwrap();
+        fs::write(dir.join("baz"), b"").unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        match event {
+            WatcherEvent::Notified(e) => assert_eq!(e.name, Some(PathBuf::from("foo"))),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        let (event, _watcher) = executor.next_event(watcher);
+        match event {
+            WatcherEvent::Notified(e) => assert_eq!(e.name, Some(PathBuf::from("baz"))),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn notify_subscribers_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let options = WatchOptions {
+            shared: true,
+            notify_subscribers: true,
+            ..Default::default()
+        };
+        let handle = executor.handle.clone();
+        let watcher0 = handle.watch_with_options(dir.path(), WatchMask::CREATE, options.clone());
+        let (_, watcher0) = executor.next_event(watcher0);
+        let watcher1 = handle.watch_with_options(dir.path(), WatchMask::DELETE, options.clone());
+        let (_, watcher1) = executor.next_event(watcher1);
+        let watcher2 = handle.watch_with_options(dir.path(), WatchMask::MODIFY, options);
+        let (_, watcher2) = executor.next_event(watcher2);
+
+        std::mem::drop(watcher2);
+        let (event, watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::SubscriberLeft { remaining: 2 });
+        let (event, watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::SubscriberLeft { remaining: 2 });
+
+        std::mem::drop(watcher1);
+        let (event, _watcher0) = executor.next_event(watcher0);
+        assert_eq!(event, WatcherEvent::SubscriberLeft { remaining: 1 });
+    }
+
+    #[test]
+    fn replay_buffer_works() {
+        let dir = TempDir::new();
+        let service = InotifyServiceBuilder::new()
+            .dedup_identical_watches(true)
+            .replay_buffer_size(2)
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, mut watcher0) = executor.next_event(watcher0);
+        for name in &["foo", "bar", "baz"] {
+            fs::write(dir.join(name), b"").unwrap();
+            let (event, w) = executor.next_event(watcher0);
+            assert!(is_notified(&event, EventMask::CREATE));
+            watcher0 = w;
+        }
+
+        // The last two events are replayed to the late subscriber
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (event, mut watcher1) = executor.next_event(watcher1);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        for name in &["bar", "baz", "qux"] {
+            if *name == "qux" {
+                // Live events follow the replayed ones
+                fs::write(dir.join(name), b"").unwrap();
+            }
+            let (event, w) = executor.next_event(watcher1);
+            match event {
+                WatcherEvent::Notified(e) => assert_eq!(e.name, Some(PathBuf::from(name))),
+                e => panic!("Unexpected event: {:?}", e),
+            }
+            watcher1 = w;
+        }
+        let (event, _watcher0) = executor.next_event(watcher0);
+        assert!(is_notified(&event, EventMask::CREATE));
+    }
+
+    #[test]
+    fn read_buffer_grows_on_overflow() {
+        let observer = Arc::new(TestObserver::default());
+        let mut service = InotifyServiceBuilder::new()
+            .observer(observer.clone())
+            .max_read_buffer_size(10000)
+            .finish();
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let observer_clone = service.observer.clone();
+        let monitor = executor.spawn_monitor(futures::lazy(|| InotifyState::new(observer_clone)));
+        let inotify = executor.run_fiber(monitor).unwrap().unwrap(); // Needs a fiber context
+        service.inotifies.push(inotify);
+        assert_eq!(service.inotifies[0].inotify.read_buffer_size(), 4096);
+
+        for _ in 0..3 {
+            service.handle_event(0, event(EventMask::Q_OVERFLOW, None, ""));
+        }
+        assert_eq!(service.inotifies[0].inotify.read_buffer_size(), 10000);
+        assert_eq!(
+            *observer.grown_read_buffers.lock().unwrap(),
+            [(0, 8192), (0, 10000)]
+        );
+    }
+
+    #[test]
+    fn distinct_inodes_share_single_instance() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let paths = (0..200)
+            .map(|i| dir.join(i.to_string()))
+            .collect::<Vec<_>>();
+        for path in &paths {
+            fs::create_dir(path).unwrap();
+        }
+        let watches = paths[..100]
+            .iter()
+            .map(|path| (path.clone(), WatchMask::CREATE))
+            .collect();
+        let mut watchers = executor.handle.watch_batch(watches);
+        for path in &paths[100..] {
+            watchers.push(executor.handle.watch(path, WatchMask::DELETE));
+        }
+        let watchers = watchers
+            .into_iter()
+            .map(|w| executor.next_event(w))
+            .collect::<Vec<_>>();
+        assert!(watchers
+            .iter()
+            .all(|(event, _)| *event == WatcherEvent::StartWatching));
+
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.watchers.len(), 200);
+        assert_eq!(stats.instance_count, 1);
+    }
+
+    #[test]
+    fn is_alive_works() {
+        let mut executor = TestExecutor::new();
+        let handle = executor.handle.clone();
+        executor.run_for(Duration::from_millis(10));
+        assert!(handle.is_alive());
+
+        handle.shutdown();
+        executor.wait_service().unwrap();
+        assert!(!handle.is_alive());
+
+        // Dropped without being polled
+        let service = InotifyService::new();
+        let handle = service.handle();
+        assert!(handle.is_alive());
+        std::mem::drop(service);
+        assert!(!handle.is_alive());
+    }
+
+    #[test]
+    fn unwatch_works() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("a")).unwrap();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch(dir.join("a"), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+        let watcher1 = executor
+            .handle
+            .watch(dir.join("a/../a/"), WatchMask::DELETE);
+        let (_, watcher1) = executor.next_event(watcher1);
+        let watcher2 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher2) = executor.next_event(watcher2);
+
+        let count = executor
+            .wait(executor.handle.unwatch(dir.join("a")))
+            .unwrap();
+        assert_eq!(count, 2);
+        let (_, watcher0) = executor.next_event(watcher0); // `RestartWatching`
+        assert!(executor.wait(watcher0.collect()).unwrap().is_empty());
+        assert!(executor.wait(watcher1.collect()).unwrap().is_empty());
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        let (event, _watcher2) = executor.next_event(watcher2);
+        assert!(is_notified(&event, EventMask::CREATE));
+    }
+
+    #[test]
+    fn unwatch_prefix_works() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::create_dir(dir.join("ab")).unwrap();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch(dir.join("a"), WatchMask::CREATE);
+        let (_, watcher0) = executor.next_event(watcher0);
+        let watcher1 = executor.handle.watch(dir.join("a/b"), WatchMask::CREATE);
+        let (_, watcher1) = executor.next_event(watcher1);
+        let watcher2 = executor.handle.watch(dir.join("ab"), WatchMask::CREATE);
+        let (_, watcher2) = executor.next_event(watcher2);
+
+        let count = executor
+            .wait(executor.handle.unwatch_prefix(dir.join("a")))
+            .unwrap();
+        assert_eq!(count, 2);
+        assert!(executor.wait(watcher0.collect()).unwrap().is_empty());
+        assert!(executor.wait(watcher1.collect()).unwrap().is_empty());
+
+        // `ab` is not under `a`
+        fs::write(dir.join("ab/foo"), b"").unwrap();
+        let (event, _watcher2) = executor.next_event(watcher2);
+        assert!(is_notified(&event, EventMask::CREATE));
+    }
+
+    #[test]
+    fn case_insensitive_unwatch_works() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("Data")).unwrap();
+
+        let mut executor = TestExecutor::new();
+        let watcher = executor.handle.watch(dir.join("Data"), WatchMask::CREATE);
+        let (_, _watcher) = executor.next_event(watcher);
+        let count = executor
+            .wait(executor.handle.unwatch(dir.join("data")))
+            .unwrap();
+        assert_eq!(count, 0);
+
+        let service = InotifyServiceBuilder::new()
+            .case_insensitive_paths(true)
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+        let watcher = executor.handle.watch(dir.join("Data"), WatchMask::CREATE);
+        let (_, watcher) = executor.next_event(watcher);
+        let count = executor
+            .wait(executor.handle.unwatch(dir.join("data")))
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(executor.wait(watcher.collect()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn watch_synced_works() {
+        let dir = TempDir::new();
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::write(dir.join("bar"), b"").unwrap();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor.handle.watch_synced(dir.path(), WatchMask::CREATE);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(
+            event,
+            WatcherEvent::Baseline(vec![dir.join("bar"), dir.join("foo")])
+        );
+
+        fs::write(dir.join("baz"), b"").unwrap();
+        let (event, _watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::CREATE));
+
+        let watcher = executor
+            .handle
+            .watch_synced(dir.join("foo"), WatchMask::MODIFY);
+        let (_, watcher) = executor.next_event(watcher);
+        let (event, _watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::Baseline(vec![dir.join("foo")]));
+    }
+
+    #[test]
+    fn watch_take_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let future = executor.handle.watch_take(dir.path(), WatchMask::CREATE, 3);
+        executor.run_for(Duration::from_millis(100)); // Registers the watcher
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::write(dir.join("bar"), b"").unwrap();
+        fs::write(dir.join("baz"), b"").unwrap();
+        let events = executor.wait(future).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], WatcherEvent::StartWatching);
+        assert!(is_notified(&events[1], EventMask::CREATE));
+        assert!(is_notified(&events[2], EventMask::CREATE));
+
+        // Terminated early
+        fs::write(dir.join("qux"), b"").unwrap();
+        let future = executor
+            .handle
+            .watch_take(dir.join("qux"), WatchMask::DELETE_SELF, 10);
+        executor.run_for(Duration::from_millis(100));
+        fs::remove_file(dir.join("qux")).unwrap();
+        let events = executor.wait(future).unwrap();
+        assert_eq!(events[0], WatcherEvent::StartWatching);
+        assert!(is_notified(&events[1], EventMask::DELETE_SELF));
+        assert!(events.len() < 10);
+    }
+
+    #[test]
+    fn follow_replacement_works() {
+        let dir = TempDir::new();
+        fs::write(dir.join("conf"), b"").unwrap();
+        let mut executor = TestExecutor::new();
+
+        let options = WatchOptions {
+            follow_replacement: true,
+            ..Default::default()
+        };
+        let path = dir.join("conf");
+        let watcher = executor
+            .handle
+            .watch_with_options(path, WatchMask::MODIFY, options);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        // Atomically replaced (the old inode is kept alive by the hard link)
+        fs::hard_link(dir.join("conf"), dir.join("conf.old")).unwrap();
+        fs::write(dir.join("conf.tmp"), b"").unwrap();
+        fs::rename(dir.join("conf.tmp"), dir.join("conf")).unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+
+        fs::write(dir.join("conf"), b"foo").unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::MODIFY));
+
+        // Removed and re-created
+        fs::remove_file(dir.join("conf")).unwrap();
+        fs::write(dir.join("conf"), b"").unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+
+        fs::write(dir.join("conf"), b"foo").unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::MODIFY));
+
+        // The internal watcher of the parent directory is hidden
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.watchers.len(), 1);
+
+        // The parent directory is removed
+        fs::remove_file(dir.join("conf")).unwrap();
+        fs::remove_file(dir.join("conf.old")).unwrap();
+        fs::remove_dir(dir.path()).unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::IGNORED));
+        assert!(executor.wait(watcher.collect()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rewatch_dangling_watcher_works() {
+        let dir = TempDir::new();
+        fs::write(dir.join("conf"), b"").unwrap();
+        fs::write(dir.join("other"), b"").unwrap();
+        let service = InotifyServiceBuilder::new()
+            .placement_strategy(PlacementStrategy::Spread(2))
+            .finish();
+        let mut executor = TestExecutor::with_service(service);
+
+        // The follower is placed in the second instance, and its parent watcher in the first one
+        let other = executor.handle.watch(dir.join("other"), WatchMask::MODIFY);
+        let (_, other) = executor.next_event(other);
+        let options = WatchOptions {
+            follow_replacement: true,
+            ..Default::default()
+        };
+        let watcher =
+            executor
+                .handle
+                .watch_with_options(dir.join("conf"), WatchMask::MODIFY, options);
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        // The follower loses its watch, and then its instance is released
+        fs::remove_file(dir.join("conf")).unwrap();
+        executor.run_for(Duration::from_millis(50));
+        std::mem::drop(other);
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.instance_count, 1);
+        assert_eq!(stats.watchers[0].inotify_index, None);
+
+        executor.wait(watcher.rewatch(dir.join("other"))).unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+
+        fs::write(dir.join("other"), b"foo").unwrap();
+        let (event, _watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::MODIFY));
+        assert!(executor.is_service_alive());
+    }
+
+    #[test]
+    fn events_queued_by_command_are_delivered_in_same_poll() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let path = dir.path().to_path_buf();
+        let future = futures::lazy(move || {
+            // `InotifyService` must be polled in a fiber
+            let mut service = InotifyService::new();
+            let mut watcher = service.handle().watch(&path, WatchMask::CREATE);
+
+            // Queues an event in the new instance while the registration is handled
+            let file = path.join("foo");
+            let create = move || fs::write(file, b"").unwrap();
+            AFTER_ADD_WATCH.with(|f| *f.borrow_mut() = Some(Box::new(create)));
+            assert!(track!(service.poll())?.is_not_ready());
+
+            // The event has been delivered without any further polling of the service
+            let mut events = Vec::new();
+            while let Async::Ready(Some(event)) = track!(watcher.poll())? {
+                events.push(event);
+            }
+            Ok::<_, Error>(events)
+        });
+        let monitor = executor.executor.spawn_monitor(future);
+        let events = executor.wait(monitor).unwrap();
+        assert_eq!(events.len(), 2, "{:?}", events);
+        assert_eq!(events[0], WatcherEvent::StartWatching);
+        assert!(is_notified(&events[1], EventMask::CREATE));
+    }
+
+    #[derive(Default)]
+    struct TestObserver {
+        instance_counts: Mutex<Vec<usize>>,
+        watcher_counts: Mutex<Vec<usize>>,
+        lagged: Mutex<Vec<(WatcherId, u64)>>,
+        raw_events: Mutex<Vec<InotifyEvent>>,
+        syscalls: Mutex<Vec<String>>,
+        removed_watches: Mutex<Vec<(WatcherId, PathBuf)>>,
+        stripped_masks: Mutex<Vec<(WatcherId, u32)>>,
+        created_instances: Mutex<Vec<(usize, InstanceReason)>>,
+        destroyed_instances: Mutex<Vec<usize>>,
+        grown_read_buffers: Mutex<Vec<(usize, usize)>>,
+        delivered_events: Mutex<Vec<(WatcherId, Duration)>>,
+        disconnected: Mutex<Vec<(WatcherId, PathBuf)>>,
+    }
+    impl Observer for Arc<TestObserver> {
+        fn instance_count_changed(&self, count: usize) {
+            self.instance_counts.lock().unwrap().push(count);
+        }
+        fn instance_created(&self, index: usize, reason: InstanceReason) {
+            self.created_instances.lock().unwrap().push((index, reason));
+        }
+        fn instance_destroyed(&self, index: usize) {
+            self.destroyed_instances.lock().unwrap().push(index);
+        }
+        fn watcher_count_changed(&self, count: usize) {
+            self.watcher_counts.lock().unwrap().push(count);
+        }
+        fn watcher_lagged(&self, id: WatcherId, dropped: u64) {
+            self.lagged.lock().unwrap().push((id, dropped));
+        }
+        fn watcher_disconnected(&self, id: WatcherId, path: &Path) {
+            self.disconnected
+                .lock()
+                .unwrap()
+                .push((id, path.to_path_buf()));
+        }
+        fn watch_removed(&self, id: WatcherId, path: &Path, _wd: i32) {
+            self.removed_watches
+                .lock()
+                .unwrap()
+                .push((id, path.to_path_buf()));
+        }
+        fn mask_stripped(&self, id: WatcherId, _path: &Path, bits: u32) {
+            self.stripped_masks.lock().unwrap().push((id, bits));
+        }
+        fn read_buffer_grown(&self, index: usize, size: usize) {
+            self.grown_read_buffers.lock().unwrap().push((index, size));
+        }
+        fn event_delivered(&self, id: WatcherId, queued: Duration) {
+            self.delivered_events.lock().unwrap().push((id, queued));
+        }
+        fn raw_event(&self, event: &InotifyEvent) {
+            self.raw_events.lock().unwrap().push(event.clone());
+        }
+        fn syscall(&self, name: &str, _args: fmt::Arguments, _result: &io::Result<i64>) {
+            self.syscalls.lock().unwrap().push(name.to_owned());
+        }
+    }
+
+    fn is_notified(event: &WatcherEvent, mask: EventMask) -> bool {
+        if let WatcherEvent::Notified(ref e) = *event {
+            e.mask.contains(mask)
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use fibers::io::poll::PollerHandle;
+use futures::{Async, Poll, Stream};
+use std::path::{Path, PathBuf};
+
+use internal_inotify::Inotify;
+use observer::SharedObserver;
+use {Error, EventMask, Result, WatchMask, WatcherEvent};
+
+/// A watcher which reads its own inotify instance without `InotifyService`.
+///
+/// This is a `Stream` that produces the same events as `Watcher`.
+/// Unlike `Watcher`, this does not depend on the polling of an `InotifyService`,
+/// thus it is convenient for programs that watch a single path.
+/// On the other hand, each `StandaloneWatcher` consumes an inotify instance
+/// (i.e., a file descriptor), so it is not suitable for watching many paths.
+///
+/// The inotify instance is created when the stream is polled for the first time,
+/// so this must be polled in a fiber (unless it is made by `new_with_poller`).
+#[derive(Debug)]
+pub struct StandaloneWatcher {
+    path: PathBuf,
+    mask: WatchMask,
+    inotify: Option<Inotify>,
+    poller: Option<PollerHandle>,
+    started: bool,
+    eos: bool,
+}
+impl StandaloneWatcher {
+    /// Makes a new `StandaloneWatcher` that watches `path` with the given mask.
+    pub fn new<P: AsRef<Path>>(path: P, mask: WatchMask) -> Self {
+        StandaloneWatcher {
+            path: path.as_ref().to_path_buf(),
+            mask,
+            inotify: None,
+            poller: None,
+            started: false,
+            eos: false,
+        }
+    }
+
+    /// Makes a new `StandaloneWatcher` that registers its inotify instance to `poller`.
+    ///
+    /// Unlike `new`, the resulting watcher can be polled outside of a fiber
+    /// (e.g., in a setup which runs a `fibers::io::poll::Poller` by itself).
+    pub fn new_with_poller<P: AsRef<Path>>(path: P, mask: WatchMask, poller: PollerHandle) -> Self {
+        StandaloneWatcher {
+            poller: Some(poller),
+            ..Self::new(path, mask)
+        }
+    }
+
+    /// Returns the path being watched by this watcher.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn start_watching(&mut self) -> Result<WatcherEvent> {
+        let poller = self.poller.clone();
+        let mut inotify = track!(Inotify::with_poller(SharedObserver::default(), poller))?;
+        let mut mask = self.mask;
+        mask.remove(WatchMask::MASK_ADD);
+        track!(inotify.add_watch(&self.path, mask.bits()))?;
+        self.inotify = Some(inotify);
+
+        let event = if self.started {
+            WatcherEvent::RestartWatching
+        } else {
+            WatcherEvent::StartWatching
+        };
+        self.started = true;
+        Ok(event)
+    }
+}
+impl Stream for StandaloneWatcher {
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.eos {
+            return Ok(Async::Ready(None));
+        }
+        if self.inotify.is_none() {
+            let event = track!(self.start_watching())?;
+            return Ok(Async::Ready(Some(event)));
+        }
+
+        let polled = track!(self.inotify.as_mut().expect("Never fails").poll())?;
+        match polled {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => {
+                // The file descriptor has become invalid, so we re-create the instance
+                let event = track!(self.start_watching())?;
+                Ok(Async::Ready(Some(event)))
+            }
+            Async::Ready(Some(mut event)) => {
+                event.watch_path = self.path.clone();
+                self.eos = event.mask.contains(EventMask::IGNORED);
+                Ok(Async::Ready(Some(WatcherEvent::Notified(event))))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fibers::io::poll::Poller;
+    use fibers::Spawn;
+    use futures::{Future, Stream};
+    use std::fs;
+    use std::time::Duration;
+
+    use super::*;
+    use test_util::{TempDir, TestExecutor};
+
+    #[test]
+    fn standalone_watcher_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher = StandaloneWatcher::new(dir.path(), WatchMask::CREATE);
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        let (event, watcher) = next_event(&mut executor, watcher);
+        if let WatcherEvent::Notified(e) = event {
+            assert_eq!(e.mask, EventMask::CREATE);
+            assert_eq!(e.path(), dir.join("foo"));
+        } else {
+            panic!("Unexpected event: {:?}", event);
+        }
+
+        fs::remove_dir_all(dir.path()).unwrap();
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert!(if let WatcherEvent::Notified(e) = event {
+            e.mask.contains(EventMask::IGNORED)
+        } else {
+            false
+        });
+        let monitor = executor.executor.spawn_monitor(watcher.collect());
+        assert!(executor.wait(monitor).unwrap().is_empty());
+    }
+
+    #[test]
+    fn new_with_poller_works() {
+        let dir = TempDir::new();
+        let mut poller = Poller::new().unwrap();
+
+        // Polled outside of a fiber
+        let mut watcher =
+            StandaloneWatcher::new_with_poller(dir.path(), WatchMask::CREATE, poller.handle());
+        let mut next_event = || loop {
+            poller.poll(Some(Duration::from_millis(1))).unwrap();
+            if let Async::Ready(event) = watcher.poll().unwrap() {
+                return event.expect("Unexpected end of stream");
+            }
+        };
+        assert_eq!(next_event(), WatcherEvent::StartWatching);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        match next_event() {
+            WatcherEvent::Notified(e) => assert_eq!(e.path(), dir.join("foo")),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn not_in_fiber_context() {
+        let dir = TempDir::new();
+        let mut watcher = StandaloneWatcher::new(dir.path(), WatchMask::CREATE);
+        let e = watcher.poll().err().unwrap();
+        assert!(e.to_string().contains("Not in a fiber context"));
+    }
+
+    fn next_event(
+        executor: &mut TestExecutor,
+        watcher: StandaloneWatcher,
+    ) -> (WatcherEvent, StandaloneWatcher) {
+        // `StandaloneWatcher` must be polled in a fiber
+        let future = watcher.into_future().map_err(|(e, _)| e);
+        let monitor = executor.executor.spawn_monitor(future);
+        let (event, watcher) = executor.wait(monitor).unwrap();
+        (event.expect("Unexpected end of stream"), watcher)
+    }
+}
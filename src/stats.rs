@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use {WatchMask, WatcherId};
+
+/// Statistics of an `InotifyService`.
+///
+/// This is retrieved by calling `InotifyServiceHandle::stats` method.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceStats {
+    /// The number of the inotify instances (i.e., file descriptors) managed by the service.
+    pub instance_count: usize,
+
+    /// The number of the events read from each inotify instance (indexed by
+    /// `WatcherStats::inotify_index`).
+    ///
+    /// This is useful to find the hot instances (see `PlacementStrategy`).
+    /// The count is reset when the instance is re-created
+    /// (i.e., after the file descriptor has become invalid).
+    pub instance_events: Vec<u64>,
+
+    /// The statistics of the registered watchers (in no particular order).
+    pub watchers: Vec<WatcherStats>,
+}
+
+/// The numbers of the events dropped since the last retrieval.
+///
+/// This is retrieved by calling `InotifyServiceHandle::take_drop_stats` method.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DropStats {
+    /// The number of the events dropped for all of the watchers,
+    /// including the ones which have been deregistered since the last retrieval.
+    pub dropped: u64,
+
+    /// The number of the events dropped for each registered watcher.
+    pub watchers: HashMap<WatcherId, u64>,
+}
+
+/// Statistics of a watcher.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatcherStats {
+    /// The identifier of the watcher.
+    pub id: WatcherId,
+
+    /// The path being watched by the watcher.
+    pub path: PathBuf,
+
+    /// The mask requested by the watcher.
+    pub mask: WatchMask,
+
+    /// The descriptor of the kernel watch of the watcher.
+    ///
+    /// Together with `inotify_index`, this identifies the kernel watch.
+    /// The watchers sharing a kernel watch have the same descriptor
+    /// (see `WatchOptions::shared`).
+    /// This is `None` if the watcher currently has no kernel watch.
+    pub wd: Option<i32>,
+
+    /// The index of the inotify instance to which the watch of the watcher belongs.
+    ///
+    /// This is useful to see how the watchers are distributed across the instances
+    /// (see `PlacementStrategy`).
+    /// This is `None` if the watcher currently has no kernel watch
+    /// (see `WatchOptions::follow_replacement`).
+    pub inotify_index: Option<usize>,
+
+    /// The cumulative number of the events dropped because the event channel of
+    /// the watcher was full (see `WatchOptions::channel_capacity`).
+    pub dropped: u64,
+
+    /// The number of the items buffered in the event channel of the watcher
+    /// at the time of the retrieval (see `Watcher::pending_len`).
+    pub pending_len: usize,
+}
@@ -0,0 +1,173 @@
+use fibers::sync::oneshot::Monitor;
+use fibers::{Executor, InPlaceExecutor, Spawn};
+use futures::{Async, Future, Poll, Stream};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use internal_inotify::WatchDecriptor;
+use {
+    Clock, Error, EventMask, InotifyEvent, InotifyService, InotifyServiceHandle, Watcher,
+    WatcherEvent,
+};
+
+static TEMP_DIR_SEQNO: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary directory which is removed when dropped.
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+impl TempDir {
+    pub fn new() -> Self {
+        let path = env::temp_dir().join(format!(
+            "fibers_inotify_test.{}.{}",
+            process::id(),
+            TEMP_DIR_SEQNO.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// An executor running an `InotifyService`.
+pub struct TestExecutor {
+    pub executor: InPlaceExecutor,
+    pub handle: InotifyServiceHandle,
+    pub service: Monitor<(), Error>,
+}
+impl TestExecutor {
+    pub fn new() -> Self {
+        Self::with_service(InotifyService::new())
+    }
+    pub fn with_service(service: InotifyService) -> Self {
+        let executor = InPlaceExecutor::new().unwrap();
+        let handle = service.handle();
+        let service = executor.spawn_monitor(service);
+        TestExecutor {
+            executor,
+            handle,
+            service,
+        }
+    }
+
+    /// Runs the executor until `future` is ready (panics if it takes too long).
+    pub fn wait<F: Future>(&mut self, mut future: F) -> Result<F::Item, F::Error> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Async::Ready(item) = future.poll()? {
+                return Ok(item);
+            }
+            assert!(Instant::now() < deadline, "Timeout");
+            self.executor.run_once().unwrap();
+        }
+    }
+
+    /// Runs the executor until the service terminates.
+    pub fn wait_service(&mut self) -> Result<(), Error> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Async::Ready(()) = self.service.poll().map_err(Error::from)? {
+                return Ok(());
+            }
+            assert!(Instant::now() < deadline, "Timeout");
+            self.executor.run_once().unwrap();
+        }
+    }
+
+    /// Runs the executor for a while.
+    pub fn run_for(&mut self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            self.executor.run_once().unwrap();
+        }
+    }
+
+    /// Takes the next event from `watcher`.
+    pub fn next_event(&mut self, watcher: Watcher) -> (WatcherEvent, Watcher) {
+        let (event, watcher) = self
+            .wait(watcher.into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        (event.expect("Unexpected end of stream"), watcher)
+    }
+
+    /// Returns `true` if the service has not terminated yet.
+    pub fn is_service_alive(&mut self) -> bool {
+        self.service
+            .poll()
+            .map(|a| a.is_not_ready())
+            .unwrap_or(false)
+    }
+}
+
+/// A clock which advances only when `FakeClock::advance` is called.
+///
+/// The timers never notify the current task, so the streams must be polled manually.
+#[derive(Debug, Clone, Default)]
+pub struct FakeClock(Arc<Mutex<Duration>>);
+impl FakeClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+    fn now(&self) -> Duration {
+        *self.0.lock().unwrap()
+    }
+}
+impl Clock for FakeClock {
+    type Timer = FakeTimer;
+    fn timer(&self, duration: Duration) -> Self::Timer {
+        FakeTimer {
+            clock: self.clone(),
+            deadline: self.now() + duration,
+        }
+    }
+}
+
+/// A timer made by `FakeClock`.
+#[derive(Debug)]
+pub struct FakeTimer {
+    clock: FakeClock,
+    deadline: Duration,
+}
+impl Future for FakeTimer {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.clock.now() >= self.deadline {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Makes an `InotifyEvent` for testing.
+pub fn event(mask: EventMask, name: Option<&str>, watch_path: &str) -> InotifyEvent {
+    InotifyEvent {
+        wd: WatchDecriptor(1),
+        mask,
+        cookie: 0,
+        name: name.map(PathBuf::from),
+        watch_path: PathBuf::from(watch_path),
+        inode: None,
+        #[cfg(feature = "raw-events")]
+        raw: None,
+    }
+}
@@ -0,0 +1,591 @@
+use futures::{Async, Poll, Stream};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use {
+    Error, EventMask, InotifyEvent, InotifyServiceHandle, WatchMask, Watcher, WatcherEvent,
+    WatcherId,
+};
+
+/// An entry of the tree watched by `TreeWatcher`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TreeEntry {
+    /// The path of the entry relative to the root of the tree.
+    pub path: PathBuf,
+
+    /// Whether the entry is a directory (symbolic links are not followed).
+    pub is_dir: bool,
+}
+
+/// An item produced by `TreeWatcher`.
+///
+/// The paths are relative to the root of the tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeEvent {
+    /// The snapshot of the whole tree.
+    ///
+    /// This is produced first, and again after the tree has been rescanned due to
+    /// an overflow of the event queue. The entries are sorted by their paths.
+    Initial(Vec<TreeEntry>),
+
+    /// An entry has been added.
+    Added(TreeEntry),
+
+    /// An entry has been removed (for a directory, the entries under it are removed too).
+    Removed(PathBuf),
+
+    /// The content of an entry has been modified.
+    Modified(PathBuf),
+
+    /// An entry has been renamed within the tree
+    /// (for a directory, the entries under it are moved too).
+    Moved {
+        /// The path of the entry before the rename.
+        from_path: PathBuf,
+
+        /// The path of the entry after the rename.
+        to_path: PathBuf,
+    },
+
+    /// The filesystem mounted on a directory of the tree has been unmounted.
+    ///
+    /// The entries under the directory are removed without being reported individually,
+    /// and the directory is no longer watched.
+    /// If the path is empty (i.e., the filesystem of the root has been unmounted),
+    /// the stream terminates after this.
+    Unmounted(PathBuf),
+}
+
+/// A watcher which reports the initial state of a directory tree followed by its changes.
+///
+/// This is a `Stream` that produces `TreeEvent::Initial` followed by the deltas.
+/// The tree is watched recursively, by a `Watcher` for each directory
+/// (made by `InotifyServiceHandle::watch_synced`, so that no change is missed between
+/// the scan of a directory and the start of its watch).
+/// Renames within the tree are correlated and reported as `TreeEvent::Moved`.
+/// A `MOVED_FROM` (or `MOVED_TO`) whose counterpart is not found among the events read
+/// at the same time is regarded as a removal (or an addition), i.e.,
+/// the entry was moved out of (or into) the tree.
+///
+/// If the event queue of the kernel overflows, some changes have been lost,
+/// so the tree is rescanned and a fresh `TreeEvent::Initial` is produced.
+///
+/// By default, the directories on other filesystems than the root (i.e., mount points)
+/// are reported as entries but not descended into (see `TreeWatcher::cross_mounts`).
+///
+/// If a filesystem in the tree is unmounted, every watch on it notifies `UNMOUNT`.
+/// They are coalesced into a single `TreeEvent::Unmounted` for the topmost directory
+/// of the filesystem, and the subtree is torn down at once.
+///
+/// This stream terminates when the root directory is removed (or unmounted).
+///
+/// This is created by calling `InotifyServiceHandle::watch_tree` method.
+#[derive(Debug)]
+pub struct TreeWatcher {
+    service: InotifyServiceHandle,
+    root: PathBuf,
+    root_dev: Option<u64>,
+    cross_mounts: bool,
+    dirs: HashMap<WatcherId, DirWatch>,
+    entries: BTreeMap<PathBuf, bool>,
+    scanning_dirs: HashSet<WatcherId>, // The directories whose baselines have not arrived yet
+    moved_from: HashMap<u32, PathBuf>,
+    moved_to: HashMap<u32, (PathBuf, bool)>,
+    unmounted: Vec<PathBuf>, // The topmost directories unmounted since the last report
+    is_initialized: bool,
+    events: VecDeque<TreeEvent>,
+    eos: bool,
+}
+impl TreeWatcher {
+    pub(crate) fn new(service: InotifyServiceHandle, root: PathBuf) -> Self {
+        let root_dev = fs::metadata(&root).ok().map(|m| m.dev());
+        let mut this = TreeWatcher {
+            service,
+            root,
+            root_dev,
+            cross_mounts: false,
+            dirs: HashMap::new(),
+            entries: BTreeMap::new(),
+            scanning_dirs: HashSet::new(),
+            moved_from: HashMap::new(),
+            moved_to: HashMap::new(),
+            unmounted: Vec::new(),
+            is_initialized: false,
+            events: VecDeque::new(),
+            eos: false,
+        };
+        this.watch_dir(PathBuf::new());
+        this
+    }
+
+    /// Sets whether the watcher descends into the directories on other filesystems
+    /// than the root.
+    ///
+    /// If `true`, the subtrees under mount points (found by the scans or created/moved into
+    /// the tree, e.g., container volumes) are watched as well.
+    /// Otherwise, a mount point is reported as a directory entry but its contents are not.
+    /// Whether a directory is a mount point is determined by comparing its device ID
+    /// (`st_dev`) with the one of the root.
+    ///
+    /// Note that inotify does not notify mounting a filesystem on an existing directory,
+    /// thus such a mount is reflected only when the tree is rescanned.
+    ///
+    /// This must be called before the watcher is polled. The default value is `false`.
+    pub fn cross_mounts(mut self, enabled: bool) -> Self {
+        self.cross_mounts = enabled;
+        self
+    }
+
+    fn is_mount_boundary(&self, dir: &Path) -> bool {
+        if self.cross_mounts {
+            return false;
+        }
+        let dev = fs::symlink_metadata(self.root.join(dir))
+            .ok()
+            .map(|m| m.dev());
+        dev.is_some() && dev != self.root_dev
+    }
+
+    fn watch_dir(&mut self, dir: PathBuf) {
+        if self.dirs.values().any(|d| d.path == dir) {
+            return;
+        }
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::ONLYDIR;
+        let watcher = self.service.watch_synced(self.root.join(&dir), mask);
+        self.scanning_dirs.insert(watcher.id());
+        self.dirs
+            .insert(watcher.id(), DirWatch { path: dir, watcher });
+    }
+
+    fn rescan(&mut self) {
+        self.dirs.clear();
+        self.entries.clear();
+        self.scanning_dirs.clear();
+        self.moved_from.clear();
+        self.moved_to.clear();
+        self.unmounted.clear();
+        self.is_initialized = false;
+        self.watch_dir(PathBuf::new());
+    }
+
+    fn handle_event(&mut self, watcher_id: WatcherId, event: WatcherEvent) {
+        let dir = match self.dirs.get(&watcher_id) {
+            None => return,
+            Some(d) => d.path.clone(),
+        };
+        match event {
+            WatcherEvent::Baseline(paths) => {
+                for path in paths {
+                    let is_dir = match fs::symlink_metadata(&path) {
+                        Err(_) => continue, // Removed again (will be notified)
+                        Ok(metadata) => metadata.is_dir(),
+                    };
+                    if let Some(name) = path.file_name() {
+                        self.add_entry(dir.join(name), is_dir);
+                    }
+                }
+                self.scanning_dirs.remove(&watcher_id);
+                self.check_initialized();
+            }
+            WatcherEvent::Notified(event) => self.handle_inotify_event(&dir, event),
+            _ => {}
+        }
+    }
+
+    fn handle_inotify_event(&mut self, dir: &Path, event: InotifyEvent) {
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            self.rescan();
+            return;
+        }
+        if event.mask.contains(EventMask::UNMOUNT) {
+            self.unmount(dir);
+            return;
+        }
+        let path = match event.name {
+            None => return, // The events of the directory itself are handled via its parent
+            Some(ref name) => dir.join(name),
+        };
+        let is_dir = event.mask.contains(EventMask::ISDIR);
+        if event.mask.contains(EventMask::CREATE) {
+            self.add_entry(path, is_dir);
+        } else if event.mask.contains(EventMask::DELETE) {
+            self.remove_entry(&path);
+        } else if event.mask.contains(EventMask::MODIFY) {
+            if self.is_initialized && self.entries.contains_key(&path) {
+                self.events.push_back(TreeEvent::Modified(path));
+            }
+        } else if event.mask.contains(EventMask::MOVED_FROM) {
+            // The halves of a rename may be read from different watchers in any order
+            if let Some((to_path, _)) = self.moved_to.remove(&event.cookie) {
+                self.move_entry(path, to_path);
+            } else {
+                self.moved_from.insert(event.cookie, path);
+            }
+        } else if event.mask.contains(EventMask::MOVED_TO) {
+            if let Some(from_path) = self.moved_from.remove(&event.cookie) {
+                self.move_entry(from_path, path);
+            } else {
+                self.moved_to.insert(event.cookie, (path, is_dir));
+            }
+        }
+    }
+
+    fn add_entry(&mut self, path: PathBuf, is_dir: bool) {
+        if self.entries.contains_key(&path) {
+            // Already reflected by the baseline (or the event)
+            return;
+        }
+        self.entries.insert(path.clone(), is_dir);
+        if is_dir && !self.is_mount_boundary(&path) {
+            self.watch_dir(path.clone());
+        }
+        if self.is_initialized {
+            self.events
+                .push_back(TreeEvent::Added(TreeEntry { path, is_dir }));
+        }
+    }
+
+    fn remove_entry(&mut self, path: &Path) {
+        if self.entries.remove(path).is_none() {
+            return;
+        }
+        self.entries.retain(|p, _| !p.starts_with(path));
+        let removed_dirs = self
+            .dirs
+            .iter()
+            .filter(|&(_, d)| d.path.starts_with(path))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for watcher_id in removed_dirs {
+            self.dirs.remove(&watcher_id);
+            self.scanning_dirs.remove(&watcher_id);
+        }
+        if self.is_initialized {
+            self.events
+                .push_back(TreeEvent::Removed(path.to_path_buf()));
+        }
+        self.check_initialized();
+    }
+
+    fn unmount(&mut self, dir: &Path) {
+        // The directories under `dir` are on the same filesystem (or on the ones mounted on it),
+        // so their notifications are coalesced into the topmost one
+        if !self.unmounted.iter().any(|p| dir.starts_with(p)) {
+            self.unmounted.retain(|p| !p.starts_with(dir));
+            self.unmounted.push(dir.to_path_buf());
+        }
+        self.entries
+            .retain(|p, _| p.as_path() == dir || !p.starts_with(dir));
+        let unmounted_dirs = self
+            .dirs
+            .iter()
+            .filter(|&(_, d)| d.path.starts_with(dir))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for watcher_id in unmounted_dirs {
+            self.dirs.remove(&watcher_id);
+            self.scanning_dirs.remove(&watcher_id);
+        }
+        self.check_initialized();
+    }
+
+    fn move_entry(&mut self, from_path: PathBuf, to_path: PathBuf) {
+        if !self.entries.contains_key(&from_path) {
+            let is_dir = self.root.join(&to_path).is_dir();
+            self.add_entry(to_path, is_dir);
+            return;
+        }
+
+        // The watches follow the inodes, so only the paths are updated
+        let rebase = |p: &Path| {
+            p.strip_prefix(&from_path)
+                .ok()
+                .map(|rest| to_path.join(rest))
+        };
+        let entries = ::std::mem::take(&mut self.entries);
+        self.entries = entries
+            .into_iter()
+            .map(|(p, is_dir)| (rebase(&p).unwrap_or(p), is_dir))
+            .collect();
+        for d in self.dirs.values_mut() {
+            if let Some(p) = rebase(&d.path) {
+                d.path = p;
+            }
+        }
+        if self.is_initialized {
+            self.events
+                .push_back(TreeEvent::Moved { from_path, to_path });
+        }
+    }
+
+    fn check_initialized(&mut self) {
+        if self.is_initialized || !self.scanning_dirs.is_empty() {
+            return;
+        }
+        self.is_initialized = true;
+        let entries = self
+            .entries
+            .iter()
+            .map(|(path, &is_dir)| TreeEntry {
+                path: path.clone(),
+                is_dir,
+            })
+            .collect();
+        self.events.push_back(TreeEvent::Initial(entries));
+    }
+
+    fn poll_dirs(&mut self) -> Result<bool, Error> {
+        let mut is_progressed = false;
+        let watcher_ids = self.dirs.keys().cloned().collect::<Vec<_>>();
+        for watcher_id in watcher_ids {
+            let polled = match self.dirs.get_mut(&watcher_id) {
+                None => continue, // Removed while handling the other events
+                Some(d) => d.watcher.poll(),
+            };
+            let is_root = self.dirs[&watcher_id].path.as_os_str().is_empty();
+            match polled {
+                Err(e) => {
+                    if is_root {
+                        return Err(track!(e));
+                    }
+                    // The directory has been removed before it is watched
+                    self.dirs.remove(&watcher_id);
+                    self.scanning_dirs.remove(&watcher_id);
+                    self.check_initialized();
+                }
+                Ok(Async::Ready(None)) => {
+                    self.dirs.remove(&watcher_id);
+                    self.scanning_dirs.remove(&watcher_id);
+                    if is_root {
+                        self.eos = true;
+                        return Ok(true);
+                    }
+                    self.check_initialized();
+                }
+                Ok(Async::Ready(Some(event))) => self.handle_event(watcher_id, event),
+                Ok(Async::NotReady) => continue,
+            }
+            is_progressed = true;
+        }
+        Ok(is_progressed)
+    }
+}
+impl Stream for TreeWatcher {
+    type Item = TreeEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Async::Ready(Some(event)));
+            }
+            if self.eos {
+                return Ok(Async::Ready(None));
+            }
+            if track!(self.poll_dirs())? {
+                continue;
+            }
+
+            // The rest of the renames are the ones across the boundary of the tree
+            let moved_from = self.moved_from.drain().map(|(_, p)| p).collect::<Vec<_>>();
+            for path in moved_from {
+                self.remove_entry(&path);
+            }
+            let moved_to = self.moved_to.drain().map(|(_, x)| x).collect::<Vec<_>>();
+            for (path, is_dir) in moved_to {
+                self.add_entry(path, is_dir);
+            }
+            let mut unmounted = ::std::mem::take(&mut self.unmounted);
+            unmounted.sort();
+            for dir in unmounted {
+                if dir.as_os_str().is_empty() {
+                    self.eos = true;
+                }
+                if self.is_initialized || self.eos {
+                    self.events.push_back(TreeEvent::Unmounted(dir));
+                }
+            }
+            if self.events.is_empty() && !self.eos {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DirWatch {
+    path: PathBuf, // Relative to the root
+    watcher: Watcher,
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Stream;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use test_util::{event, TempDir, TestExecutor};
+
+    #[test]
+    fn watch_tree_works() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("a")).unwrap();
+        fs::write(dir.join("a/x"), b"").unwrap();
+        fs::write(dir.join("b"), b"").unwrap();
+
+        let mut executor = TestExecutor::new();
+        let watcher = executor.handle.watch_tree(dir.path());
+        let (event, watcher) = next(&mut executor, watcher);
+        assert_eq!(
+            event,
+            TreeEvent::Initial(vec![
+                entry("a", true),
+                entry("a/x", false),
+                entry("b", false),
+            ])
+        );
+
+        fs::create_dir(dir.join("c")).unwrap();
+        let (event, watcher) = next(&mut executor, watcher);
+        assert_eq!(event, TreeEvent::Added(entry("c", true)));
+        executor.run_for(::std::time::Duration::from_millis(50));
+
+        fs::write(dir.join("c/y"), b"").unwrap();
+        let (event, watcher) = next(&mut executor, watcher);
+        assert_eq!(event, TreeEvent::Added(entry("c/y", false)));
+
+        fs::write(dir.join("a/x"), b"foo").unwrap();
+        let (event, watcher) = next(&mut executor, watcher);
+        assert_eq!(event, TreeEvent::Modified(PathBuf::from("a/x")));
+
+        fs::rename(dir.join("a"), dir.join("c/a")).unwrap();
+        let (event, watcher) = next(&mut executor, watcher);
+        assert_eq!(
+            event,
+            TreeEvent::Moved {
+                from_path: PathBuf::from("a"),
+                to_path: PathBuf::from("c/a"),
+            }
+        );
+
+        // The watch of the moved directory is still alive
+        fs::remove_file(dir.join("c/a/x")).unwrap();
+        let (event, watcher) = next(&mut executor, watcher);
+        assert_eq!(event, TreeEvent::Removed(PathBuf::from("c/a/x")));
+
+        fs::rename(dir.join("b"), dir.join("../b.moved_out")).unwrap();
+        let (event, _) = next(&mut executor, watcher);
+        assert_eq!(event, TreeEvent::Removed(PathBuf::from("b")));
+        let _ = fs::remove_file(dir.join("../b.moved_out"));
+    }
+
+    #[test]
+    fn mount_points_are_not_descended_by_default() {
+        let executor = TestExecutor::new();
+
+        // `/proc` and `/sys` are usually on different filesystems from `/`
+        let watcher = executor.handle.watch_tree("/");
+        let root_dev = watcher.root_dev;
+        let is_mount = |path: &str| {
+            let dev = fs::metadata(path).ok().map(|m| m.dev());
+            dev.is_some() && dev != root_dev
+        };
+        if !is_mount("/proc") {
+            return;
+        }
+        assert!(watcher.is_mount_boundary(Path::new("proc")));
+        assert!(!watcher.is_mount_boundary(Path::new("")));
+
+        let watcher = watcher.cross_mounts(true);
+        assert!(!watcher.is_mount_boundary(Path::new("proc")));
+    }
+
+    #[test]
+    fn overflow_triggers_rescan() {
+        let max_queued_events = fs::read_to_string("/proc/sys/fs/inotify/max_queued_events")
+            .ok()
+            .and_then(|s| s.trim().parse::<usize>().ok());
+        let max_queued_events = match max_queued_events {
+            Some(n) if n <= 65536 => n,
+            _ => return, // Too large to overflow in a test
+        };
+
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+        let watcher = executor.handle.watch_tree(dir.path());
+        let (event, watcher) = next(&mut executor, watcher);
+        assert_eq!(event, TreeEvent::Initial(Vec::new()));
+
+        // The service is not polled while the files are created
+        for i in 0..max_queued_events + 1 {
+            fs::write(dir.join(i.to_string()), b"").unwrap();
+        }
+        let mut watcher = watcher;
+        loop {
+            let (event, next_watcher) = next(&mut executor, watcher);
+            watcher = next_watcher;
+            if let TreeEvent::Initial(entries) = event {
+                assert_eq!(entries.len(), max_queued_events + 1);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn unmount_tears_down_subtree() {
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/x"), b"").unwrap();
+        fs::create_dir(dir.join("c")).unwrap();
+
+        let mut executor = TestExecutor::new();
+        let watcher = executor.handle.watch_tree(dir.path());
+        let (_, mut watcher) = next(&mut executor, watcher);
+        assert_eq!(watcher.dirs.len(), 4);
+
+        // Simulates that the filesystem mounted on `a` is unmounted.
+        // Every watch on it notifies `UNMOUNT` (in any order).
+        let unmount = |path: &str| event(EventMask::UNMOUNT, None, path);
+        watcher.handle_inotify_event(Path::new("a/b"), unmount("a/b"));
+        watcher.handle_inotify_event(Path::new("a"), unmount("a"));
+        let (event, mut watcher) = next(&mut executor, watcher);
+        assert_eq!(event, TreeEvent::Unmounted(PathBuf::from("a")));
+
+        let mut dirs = watcher.dirs.values().map(|d| &d.path).collect::<Vec<_>>();
+        dirs.sort();
+        assert_eq!(dirs, [Path::new(""), Path::new("c")]);
+        assert_eq!(
+            watcher.entries.keys().collect::<Vec<_>>(),
+            [Path::new("a"), Path::new("c")]
+        );
+
+        // The root
+        watcher.handle_inotify_event(Path::new(""), unmount(""));
+        let (event, watcher) = next(&mut executor, watcher);
+        assert_eq!(event, TreeEvent::Unmounted(PathBuf::new()));
+        let (event, _) = executor.wait(watcher.into_future()).ok().unwrap();
+        assert_eq!(event, None);
+    }
+
+    fn next(executor: &mut TestExecutor, watcher: TreeWatcher) -> (TreeEvent, TreeWatcher) {
+        let (event, watcher) = executor
+            .wait(watcher.into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        (event.expect("Unexpected end of stream"), watcher)
+    }
+
+    fn entry(path: &str, is_dir: bool) -> TreeEntry {
+        TreeEntry {
+            path: PathBuf::from(path),
+            is_dir,
+        }
+    }
+}
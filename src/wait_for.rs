@@ -0,0 +1,125 @@
+use futures::{Async, Future, Poll, Stream};
+use std::fmt;
+
+use {Error, ErrorKind, InotifyEvent, Watcher, WatcherEvent};
+
+/// A future which waits for the first event satisfying a predicate.
+///
+/// The underlying watcher is deregistered as soon as the event is found
+/// (or when this future is dropped).
+/// If the watcher terminates before such an event is notified (e.g., the watched path is
+/// removed and `EventMask::IGNORED` is notified), this future fails.
+///
+/// This is created by calling `InotifyServiceHandle::wait_for` method.
+pub struct WaitFor<F> {
+    watcher: Option<Watcher>,
+    predicate: F,
+}
+impl<F> WaitFor<F>
+where
+    F: FnMut(&InotifyEvent) -> bool,
+{
+    pub(crate) fn new(watcher: Watcher, predicate: F) -> Self {
+        WaitFor {
+            watcher: Some(watcher),
+            predicate,
+        }
+    }
+}
+impl<F> Future for WaitFor<F>
+where
+    F: FnMut(&InotifyEvent) -> bool,
+{
+    type Item = InotifyEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let polled = {
+                let watcher = self
+                    .watcher
+                    .as_mut()
+                    .expect("Cannot poll a completed WaitFor");
+                track!(watcher.poll())?
+            };
+            let found = match polled {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => {
+                    let path = self.watcher.take().map(|w| w.path().to_path_buf());
+                    track_panic!(
+                        ErrorKind::Other,
+                        "The watcher terminated before the expected event was notified: path={:?}",
+                        path
+                    );
+                }
+                Async::Ready(Some(WatcherEvent::Notified(event))) => {
+                    Some(event).filter(|e| (self.predicate)(e))
+                }
+                Async::Ready(Some(WatcherEvent::Batch(events))) => {
+                    events.into_iter().find(|e| (self.predicate)(e))
+                }
+                Async::Ready(Some(_)) => None,
+            };
+            if let Some(event) = found {
+                // Deregisters the watcher
+                self.watcher = None;
+                return Ok(Async::Ready(event));
+            }
+        }
+    }
+}
+impl<F> fmt::Debug for WaitFor<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WaitFor")
+            .field("watcher", &self.watcher)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::*;
+    use test_util::{TempDir, TestExecutor};
+    use {EventMask, WatchMask};
+
+    #[test]
+    fn wait_for_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let future = executor
+            .handle
+            .wait_for(dir.path(), WatchMask::CREATE, |e| {
+                e.name == Some("done.flag".into())
+            });
+        executor.run_for(Duration::from_millis(10));
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::write(dir.join("done.flag"), b"").unwrap();
+
+        let event = executor.wait(future).unwrap();
+        assert_eq!(event.mask, EventMask::CREATE);
+        assert_eq!(event.path(), dir.join("done.flag"));
+
+        // The watcher has been deregistered
+        let stats = executor.handle.stats();
+        assert!(executor.wait(stats).unwrap().watchers.is_empty());
+    }
+
+    #[test]
+    fn termination_before_match_fails() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("sub")).unwrap();
+        let mut executor = TestExecutor::new();
+
+        let future = executor
+            .handle
+            .wait_for(dir.join("sub"), WatchMask::CREATE, |_| false);
+        executor.run_for(Duration::from_millis(10));
+        fs::remove_dir(dir.join("sub")).unwrap();
+
+        let error = executor.wait(future).err().unwrap();
+        assert_eq!(*error.kind(), ErrorKind::Other);
+    }
+}
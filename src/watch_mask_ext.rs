@@ -0,0 +1,46 @@
+use WatchMask;
+
+/// An extension of `WatchMask`.
+///
+/// Because `WatchMask` is defined in the [inotify] crate, the helpers are provided by this trait.
+///
+/// [inotify]: https://crates.io/crates/inotify
+pub trait WatchMaskExt: Sized {
+    /// Returns the mask that covers any modification of the watched file/directory or its entries.
+    ///
+    /// That is, `CREATE`, `DELETE`, `MODIFY`, `MOVED_FROM`, `MOVED_TO`, `ATTRIB`,
+    /// `CLOSE_WRITE`, `DELETE_SELF` and `MOVE_SELF`.
+    ///
+    /// Unlike `WatchMask::all()`, this contains neither read-only events (e.g., `ACCESS`,
+    /// `OPEN`) which are usually too noisy, nor the flags which change the behavior of
+    /// the watch (e.g., `ONESHOT`, `MASK_ADD`).
+    fn all_changes() -> Self;
+}
+impl WatchMaskExt for WatchMask {
+    fn all_changes() -> Self {
+        WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::ATTRIB
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::DELETE_SELF
+            | WatchMask::MOVE_SELF
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use inotify_sys;
+
+    use super::*;
+
+    #[test]
+    fn all_changes_works() {
+        let mask = WatchMask::all_changes();
+        assert!(WatchMask::ALL_EVENTS.contains(mask));
+        assert!(!mask.intersects(WatchMask::ACCESS | WatchMask::OPEN | WatchMask::CLOSE_NOWRITE));
+        assert_eq!(mask.bits() & !inotify_sys::IN_ALL_EVENTS, 0);
+    }
+}
@@ -0,0 +1,187 @@
+/// Options for `InotifyServiceHandle::watch_with_options`.
+///
+/// To be robust against additions of new fields, please construct this with
+/// `WatchOptions { ..Default::default() }` syntax.
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    /// If `true`, the watcher fails instead of modifying an existing watch
+    /// when the inode has already been watched in the inotify instance.
+    ///
+    /// This corresponds to `IN_MASK_CREATE` flag which is available since Linux 4.18.
+    /// If the inode has already been watched, the watcher will produce an error which has
+    /// the kind `ErrorKind::AlreadyExists` (instead of kicking out the existing watcher).
+    /// On older kernels, the watcher will produce an error which has
+    /// the kind `ErrorKind::InvalidInput`.
+    /// The availability can be checked in advance by `kernel_capabilities` function.
+    ///
+    /// The default value is `false`.
+    pub mask_create: bool,
+
+    /// If `true`, the service attaches `InotifyEvent::inode` to each event.
+    ///
+    /// Because inotify does not report inode numbers, the service calls `lstat(2)` on
+    /// the path of each event when it is delivered.
+    /// Note that the file/directory may have been replaced or removed at that time.
+    ///
+    /// The default value is `false`.
+    pub stat_inode: bool,
+
+    /// The capacity of the channel used to deliver events to the watcher.
+    ///
+    /// If the number of the events queued in the channel reaches the capacity
+    /// (i.e., the consumer of the watcher is lagging), inotify events are handled according to
+    /// `overflow_policy` until the consumer catches up.
+    /// Control events such as `WatcherEvent::StartWatching`, errors and
+    /// the events having the mask `EventMask::IGNORED` are never dropped.
+    ///
+    /// The number of the dropped events can be observed via `Observer::watcher_lagged`
+    /// and `InotifyServiceHandle::stats`.
+    ///
+    /// By default, the channel is unbounded.
+    pub channel_capacity: Option<usize>,
+
+    /// The policy applied when the event channel of the watcher is full.
+    ///
+    /// This has no effect unless `channel_capacity` is specified.
+    ///
+    /// The default value is `OverflowPolicy::DropNewest`.
+    pub overflow_policy: OverflowPolicy,
+
+    /// If `true`, the watcher produces `WatcherEvent::Moved` when a rename observed by
+    /// the watcher is completed.
+    ///
+    /// The service pairs up `MOVED_FROM` and `MOVED_TO` events by their cookies across
+    /// all of the watchers of the service.
+    /// Thus a rename between directories watched by different watchers can be detected,
+    /// as long as both directories are watched by the service.
+    /// The `MOVED_FROM` and `MOVED_TO` events themselves are delivered as usual.
+    ///
+    /// The default value is `false`.
+    pub track_renames: bool,
+
+    /// If `true`, the watcher shares the kernel watch with the other shared watchers of
+    /// the same inode.
+    ///
+    /// The kernel watch covers the union of the masks of the sharing watchers
+    /// (via `IN_MASK_ADD`), and the events are fanned out to each of them according to
+    /// its own mask.
+    /// Because inotify identifies watches by inodes, the watchers of different paths which
+    /// refer to the same file/directory (e.g., via symbolic links) are also deduplicated.
+    ///
+    /// This is equivalent to `InotifyServiceBuilder::merge_masks` but applies only to
+    /// the watchers that enable this option.
+    /// Non-shared watchers of the same inode are kicked out as usual
+    /// (see `InotifyServiceHandle::watch`).
+    ///
+    /// The default value is `false`.
+    pub shared: bool,
+
+    /// If `true`, the watcher keeps watching the path when the file/directory is replaced
+    /// by a different one (e.g., atomically replaced by renaming a temporary file over it).
+    ///
+    /// The service additionally watches the parent directory of the path,
+    /// and when an entry is created at (or moved to) the path,
+    /// it migrates the watch to the new inode and produces `WatcherEvent::RestartWatching`.
+    /// While the path does not exist, the watcher waits for it to appear again instead of
+    /// terminating, thus `EventMask::IGNORED` is produced only if the parent directory
+    /// is removed.
+    ///
+    /// Unlike `WatcherExt::follow_path`, this does not miss the replacements which the old inode
+    /// survives (e.g., the old inode is still opened or has other hard links).
+    /// Note that this consumes an additional kernel watch for the parent directory.
+    ///
+    /// The default value is `false`.
+    pub follow_replacement: bool,
+
+    /// If `true`, the inotify events for the watcher are delivered in batches
+    /// (i.e., `WatcherEvent::Batch`) instead of individual `WatcherEvent::Notified`s.
+    ///
+    /// The events read by a single polling of the service are grouped into one batch,
+    /// which reduces the channel operations and the wakeups of the consumer under heavy load.
+    /// Control events (e.g., `WatcherEvent::RestartWatching`) are never reordered with the events.
+    ///
+    /// A batch counts as a single item of `channel_capacity`,
+    /// and if it is dropped, all of its events are counted as dropped.
+    ///
+    /// Note that the adaptors which inspect the individual events
+    /// (e.g., `WatcherExt::by_path`) do not handle batches.
+    ///
+    /// The default value is `false`.
+    pub batch_events: bool,
+
+    /// If `true`, the watcher produces `WatcherEvent::SubscriberLeft` when another watcher
+    /// sharing its kernel watch stops watching (e.g., is dropped).
+    ///
+    /// This is meaningful only if the kernel watch can be shared
+    /// (see `shared`, `InotifyServiceBuilder::merge_masks` and
+    /// `InotifyServiceBuilder::dedup_identical_watches`).
+    /// When the event reports that this watcher is the only remaining one,
+    /// dropping it will remove the kernel watch (which is reported by
+    /// `Observer::watch_removed`).
+    ///
+    /// The default value is `false`.
+    pub notify_subscribers: bool,
+
+    /// Policy applied when the service finds that the consumer of the watcher has gone.
+    ///
+    /// This happens when the `Watcher` is dropped while the service still has events to
+    /// deliver to it (i.e., before the service processes the deregistration).
+    ///
+    /// The default value is `DisconnectPolicy::Cleanup`.
+    pub disconnect_policy: DisconnectPolicy,
+}
+
+/// Policy applied when the event channel of a watcher is full.
+///
+/// See `WatchOptions::channel_capacity`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OverflowPolicy {
+    /// Drops the new events.
+    ///
+    /// The consumer will receive the events which occurred before it started lagging.
+    ///
+    /// This is the default policy.
+    #[default]
+    DropNewest,
+
+    /// Drops the oldest queued events to make room for the new ones.
+    ///
+    /// The consumer will receive the latest events.
+    /// This is suitable for consumers which are interested in the latest state
+    /// (e.g., user interfaces).
+    DropOldest,
+
+    /// Never drops events, and stops reading the inotify instance of the watcher instead.
+    ///
+    /// While the channel is full, the events of the other watchers in the same instance are
+    /// also delayed, and the events queued in the kernel may overflow
+    /// (i.e., `EventMask::Q_OVERFLOW`).
+    /// Consider `PlacementStrategy::Dedicated` to isolate such watchers.
+    Block,
+}
+
+/// Policy applied when the consumer of a watcher has gone.
+///
+/// See `WatchOptions::disconnect_policy`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisconnectPolicy {
+    /// Deregisters the watcher and discards the undelivered events silently.
+    ///
+    /// This is the default policy.
+    #[default]
+    Cleanup,
+
+    /// Deregisters the watcher, and reports the disconnection via
+    /// `Observer::watcher_disconnected` (once per watcher).
+    ///
+    /// The service keeps running.
+    Report,
+
+    /// Same as `Report`, and then terminates the service with an error
+    /// which has the kind `ErrorKind::Other`.
+    ///
+    /// This is suitable for watchers whose events must not be lost (e.g., ones feeding
+    /// an audit log), where the disconnection of the consumer indicates a bug.
+    /// Note that the other watchers of the service also terminate.
+    Fatal,
+}
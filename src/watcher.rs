@@ -1,8 +1,18 @@
-use fibers::sync::mpsc;
-use futures::{Async, Poll, Stream};
+use fibers::sync::oneshot;
+use fibers::Spawn;
+use futures::{Async, Future, Poll, Stream};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc as std_mpsc;
 
-use {Error, EventMask, InotifyEvent, InotifyServiceHandle, Result};
+use event_channel::EventReceiver;
+use {
+    DecodedEvent, Error, EventMask, InodeId, InotifyEvent, InotifyServiceHandle, Reply, Result,
+    WatchMask, WatchOptions,
+};
 
+/// Identifier of a watcher which is unique in an `InotifyService`.
 pub type WatcherId = usize;
 
 /// [Inotify] event watcher.
@@ -15,6 +25,8 @@ pub type WatcherId = usize;
 /// - The watcher receives an inotify event which has the mask `EventMask::IGNORED`.
 ///
 /// To stop watching, you can drop the `Watcher` instance.
+/// Dropping only sends a deregistration request to the service,
+/// thus it is safe to drop a `Watcher` on any thread (even outside of a fiber context).
 ///
 /// [inotify]: https://en.wikipedia.org/wiki/Inotify
 /// [`Stream`]: https://docs.rs/futures/0.1/futures/stream/trait.Stream.html
@@ -22,37 +34,228 @@ pub type WatcherId = usize;
 pub struct Watcher {
     id: WatcherId,
     service: InotifyServiceHandle,
-    event_rx: mpsc::Receiver<Result<WatcherEvent>>,
+    path: PathBuf,
+    mask: WatchMask,
+    options: WatchOptions,
+    event_rx: EventReceiver,
+    pending_error: Option<Error>, // See `Watcher::poll_drain`
     eos: bool,
+    is_detached: bool,
 }
 impl Watcher {
     pub(crate) fn new(
         id: WatcherId,
         service: InotifyServiceHandle,
-        event_rx: mpsc::Receiver<Result<WatcherEvent>>,
+        path: PathBuf,
+        mask: WatchMask,
+        options: WatchOptions,
+        event_rx: EventReceiver,
     ) -> Self {
         Watcher {
             id,
             service,
+            path,
+            mask,
+            options,
             event_rx,
+            pending_error: None,
             eos: false,
+            is_detached: false,
         }
     }
+
+    /// Disables the deregistration on drop, and returns the identifier of this watcher.
+    pub(crate) fn detach(mut self) -> WatcherId {
+        self.is_detached = true;
+        self.id
+    }
+
+    /// Returns the identifier of this watcher.
+    pub fn id(&self) -> WatcherId {
+        self.id
+    }
+
+    /// Returns the path passed when this watcher was created.
+    ///
+    /// Note that this is not affected by `Watcher::rewatch`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Switches the path watched by this watcher to `path`.
+    ///
+    /// The service adds the watch for the new path before removing the old one,
+    /// so there is no gap during which neither path is watched.
+    /// After switching, this watcher produces `WatcherEvent::RestartWatching`,
+    /// and the subsequent events have the new path as `InotifyEvent::watch_path`.
+    ///
+    /// If the new path cannot be watched, the resulting future fails and
+    /// the watcher keeps watching the old path.
+    pub fn rewatch<P: AsRef<Path>>(&self, path: P) -> Reply<()> {
+        self.service.rewatch(self.id, path.as_ref().to_path_buf())
+    }
+
+    /// Redirects the subsequent events of this watcher to a new `Watcher`.
+    ///
+    /// This is useful to hand over the events to a new consumer
+    /// (e.g., when reloading a downstream pipeline) without re-adding the kernel watch.
+    ///
+    /// The events delivered before the service processes the redirection remain in
+    /// this watcher, and this watcher terminates after producing them.
+    /// The events delivered after that are produced by the resulting watcher.
+    /// Thus no events are lost or duplicated during the switch.
+    ///
+    /// After calling this, dropping this watcher no longer stops watching;
+    /// the resulting watcher takes over the registration.
+    /// If this watcher is not registered to the service (e.g., it has already terminated),
+    /// the resulting watcher produces an error which has the kind `ErrorKind::InvalidInput`.
+    pub fn redirect(&mut self) -> Watcher {
+        self.is_detached = true;
+        self.service
+            .redirect(self.id, &self.path, self.mask, self.options.clone())
+    }
+
+    /// Retrieves the mask currently applied to this watcher.
+    ///
+    /// The resulting future fails with an error which has the kind `ErrorKind::InvalidInput`
+    /// if the watcher is not registered to the service
+    /// (e.g., the watch could not be added, or has been removed by the kernel).
+    pub fn current_mask(&self) -> Reply<WatchMask> {
+        self.service.current_mask(self.id)
+    }
+
+    /// Spawns a fiber on `spawner` that forwards the items of this watcher to
+    /// a `std::sync::mpsc` channel, and returns the receiver of the channel.
+    ///
+    /// This is a bridge for consumers running outside of fibers (e.g., on a plain thread).
+    ///
+    /// The fiber terminates when this watcher terminates, or when the receiver is dropped.
+    /// In the latter case, the watcher is dropped together and thus the kernel watch is
+    /// released right away, without waiting for the next item.
+    pub fn into_std_receiver<S: Spawn>(self, spawner: &S) -> StdReceiver {
+        let (tx, rx) = std_mpsc::channel();
+        let (drop_tx, drop_rx) = oneshot::channel();
+        let forward = self
+            .then(Ok::<_, ()>)
+            .for_each(move |item| tx.send(item).map_err(|_| ()));
+        let receiver_dropped = drop_rx.then(|_| Ok(()));
+        spawner.spawn(forward.select(receiver_dropped).then(|_| Ok(())));
+        StdReceiver {
+            inner: rx,
+            _drop_tx: drop_tx,
+        }
+    }
+
+    /// Returns the number of the items (events and errors) currently buffered in this watcher.
+    ///
+    /// This is useful to detect that the consumer is falling behind
+    /// before the event channel overflows (see `WatchOptions::channel_capacity`).
+    pub fn pending_len(&self) -> usize {
+        self.event_rx.len()
+    }
+
+    /// Makes the service buffer up to `limit` items for this watcher losslessly
+    /// until the returned guard is dropped.
+    ///
+    /// While the guard is alive, the service keeps the items of this watcher in order
+    /// instead of delivering them, so they are never handled by the overflow policy of
+    /// the event channel. When the guard is dropped, the buffered items are delivered at once.
+    /// This is useful for consumers which process events in bursts and do synchronous work
+    /// in between.
+    ///
+    /// The inotify events exceeding `limit` are handled by the overflow policy as usual
+    /// (for `OverflowPolicy::Block`, the service stops reading the events of the instance),
+    /// which protects against runaway memory usage.
+    /// Control events (e.g., `WatcherEvent::RestartWatching`) and errors are never dropped.
+    ///
+    /// Multiple guards can be alive at the same time. In that case, the largest `limit`
+    /// is applied and the items are buffered until all the guards are dropped.
+    pub fn hold(&self, limit: usize) -> HoldGuard {
+        let token = NEXT_HOLD_TOKEN.fetch_add(1, Ordering::SeqCst);
+        self.service.hold(self.id, token, limit);
+        HoldGuard {
+            service: self.service.clone(),
+            watcher_id: self.id,
+            token,
+        }
+    }
+
+    /// Takes all the events currently buffered in this watcher without waiting.
+    ///
+    /// This is useful to discard stale events before starting the main loop.
+    ///
+    /// Like `Stream::poll`, this method must be called inside the executor context
+    /// (e.g., in a `poll` method of a future running on a fiber),
+    /// so that the current task is notified when a new event arrives.
+    ///
+    /// If an error occurs after some events have been taken, this stops there and
+    /// returns the taken events. The error is returned by the next call of this method
+    /// (or `Stream::poll`).
+    pub fn poll_drain(&mut self) -> Result<Vec<WatcherEvent>> {
+        let mut events = Vec::new();
+        loop {
+            match self.poll() {
+                Ok(Async::Ready(Some(event))) => events.push(event),
+                Ok(_) => break,
+                Err(e) => {
+                    if events.is_empty() {
+                        return Err(track!(e));
+                    }
+                    self.pending_error = Some(e);
+                    break;
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Makes a stream that produces the events paired with the identifier of this watcher.
+    ///
+    /// This is useful to tell which watcher produced an event after merging
+    /// multiple watchers (e.g., by `Stream::select`).
+    pub fn tagged(self) -> Tagged {
+        Tagged(self)
+    }
+
+    /// Sets whether the inotify instance of this watcher is read with high priority.
+    ///
+    /// In each polling of `InotifyService`, the instances which have high priority watchers
+    /// are read prior to the others.
+    /// This is useful to reduce the latency of latency-sensitive watchers under heavy load.
+    ///
+    /// Note that the priority is applied by instance, thus the other watchers which share the
+    /// instance with this watcher are also affected.
+    ///
+    /// The default value is `false`.
+    pub fn set_high_priority(&self, enabled: bool) -> Result<()> {
+        track!(self.service.set_high_priority(self.id, enabled))
+    }
 }
 impl Stream for Watcher {
     type Item = WatcherEvent;
     type Error = Error;
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(e) = self.pending_error.take() {
+            return Err(track!(e));
+        }
         if self.eos {
             return Ok(Async::Ready(None));
         }
-        match self.event_rx.poll().expect("Never fails") {
+        match self.event_rx.poll_timed().expect("Never fails") {
             Async::NotReady => Ok(Async::NotReady),
             Async::Ready(None) => Ok(Async::Ready(None)),
-            Async::Ready(Some(result)) => {
+            Async::Ready(Some((result, queued_at))) => {
                 let event = track!(result)?;
-                if let WatcherEvent::Notified(ref e) = event {
-                    self.eos = e.mask.contains(EventMask::IGNORED);
+                let is_ignored = |e: &InotifyEvent| e.mask.contains(EventMask::IGNORED);
+                if let WatcherEvent::Notified(_) | WatcherEvent::Batch(_) = event {
+                    let queued = queued_at.elapsed();
+                    self.service.observer.event_delivered(self.id, queued);
+                }
+                match event {
+                    WatcherEvent::Notified(ref e) => self.eos = is_ignored(e),
+                    WatcherEvent::Batch(ref events) => self.eos = events.iter().any(is_ignored),
+                    WatcherEvent::Evicted => self.eos = true,
+                    _ => {}
                 }
                 Ok(Async::Ready(Some(event)))
             }
@@ -61,26 +264,370 @@ impl Stream for Watcher {
 }
 impl Drop for Watcher {
     fn drop(&mut self) {
-        self.service.deregister_watcher(self.id);
+        if !self.is_detached {
+            self.service.deregister_watcher(self.id);
+        }
+    }
+}
+
+/// A stream which produces the events of a watcher paired with its identifier.
+///
+/// This is created by calling `Watcher::tagged` method.
+#[derive(Debug)]
+pub struct Tagged(Watcher);
+impl Stream for Tagged {
+    type Item = (WatcherId, WatcherEvent);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let id = self.0.id;
+        Ok(track!(self.0.poll())?.map(|event| event.map(|event| (id, event))))
+    }
+}
+
+static NEXT_HOLD_TOKEN: AtomicUsize = AtomicUsize::new(0);
+
+/// A guard which makes the service buffer the events of a `Watcher` losslessly
+/// while the consumer is busy.
+///
+/// The hold is released when this is dropped.
+///
+/// This is created by calling `Watcher::hold` method.
+#[derive(Debug)]
+pub struct HoldGuard {
+    service: InotifyServiceHandle,
+    watcher_id: WatcherId,
+    token: usize, // Identifies this guard among the ones of the watcher
+}
+impl Drop for HoldGuard {
+    fn drop(&mut self) {
+        self.service.release(self.watcher_id, self.token);
+    }
+}
+
+/// The receiver of the items forwarded from a watcher.
+///
+/// This dereferences to `std::sync::mpsc::Receiver`.
+/// Dropping this stops the forwarding fiber, which releases the watch.
+///
+/// This is created by calling `Watcher::into_std_receiver` method.
+#[derive(Debug)]
+pub struct StdReceiver {
+    inner: std_mpsc::Receiver<Result<WatcherEvent>>,
+    _drop_tx: oneshot::Sender<()>, // Wakes up the forwarding fiber when dropped
+}
+impl Deref for StdReceiver {
+    type Target = std_mpsc::Receiver<Result<WatcherEvent>>;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
     }
 }
 
 /// Event produced by `Watcher`.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum WatcherEvent {
     /// The watcher starts watching.
     ///
     /// It means that the specified path and mask are added to an inotify instance successfully.
     StartWatching,
 
+    /// The path to be watched does not exist yet.
+    ///
+    /// This is produced only by `EventualWatcher` before `WatcherEvent::StartWatching`.
+    Pending,
+
+    /// The entries of the watched directory at the time the watcher started watching.
+    ///
+    /// This is produced only by the watchers made by `InotifyServiceHandle::watch_synced`,
+    /// right after `WatcherEvent::StartWatching`.
+    /// If the watched path is not a directory, this contains only the path itself.
+    Baseline(Vec<PathBuf>),
+
     /// The watcher restarts watching.
     ///
     /// If the inode being watched by this watcher conflicts with a newer watcher's one
     /// in an inotify instance, it will be kicked out and re-added to another inotify instance.
     /// If it happens, this event will be produced.
+    /// This event is also produced when the inotify instance of this watcher becomes invalid
+    /// and the service re-creates it.
     /// Note that in such case some inotify events may be lost and the watcher may start watching an inode different from before (althought the path is the same).
+    ///
+    /// When kicked out, the events queued before that are delivered prior to this event,
+    /// so that the order of the events is preserved as much as possible.
     RestartWatching,
 
     /// Inotify event.
     Notified(InotifyEvent),
+
+    /// Inotify events delivered at once.
+    ///
+    /// This is produced instead of `WatcherEvent::Notified` if `WatchOptions::batch_events`
+    /// is enabled. The events are in the order they occurred, and the batch is never empty.
+    Batch(Vec<InotifyEvent>),
+
+    /// The watched directory became empty.
+    ///
+    /// This is produced only by `EmptyDirWatcher` as the final event.
+    DirectoryEmptied,
+
+    /// No events arrived within the duration specified by `WatcherExt::idle_timeout`.
+    Idle,
+
+    /// The inode at a path has been replaced with another one.
+    ///
+    /// This is produced only by `WatcherExt::track_inodes`, prior to the event which
+    /// revealed the replacement.
+    InodeReplaced {
+        /// The path of the file/directory.
+        path: PathBuf,
+
+        /// The inode seen last time.
+        old: InodeId,
+
+        /// The current inode.
+        new: InodeId,
+    },
+
+    /// A file/directory has been renamed.
+    ///
+    /// This is produced after the latter of the `MOVED_FROM` and `MOVED_TO` events
+    /// that make up the rename, if `WatchOptions::track_renames` is enabled.
+    ///
+    /// If the other half is not observed by the service within
+    /// `InotifyServiceBuilder::rename_expiry` (i.e., the file/directory has been moved
+    /// into or out of the directories watched by the service),
+    /// this is produced when the half expires, and the unobserved side is `None`.
+    Moved {
+        /// The path of the file/directory before the rename.
+        ///
+        /// `None` if the source directory is not watched.
+        from_path: Option<PathBuf>,
+
+        /// The path of the file/directory after the rename.
+        ///
+        /// `None` if the destination directory is not watched.
+        to_path: Option<PathBuf>,
+    },
+
+    /// The watcher has been evicted to make room for a new watch.
+    ///
+    /// This is produced as the final event when the number of the kernel watches reaches
+    /// `InotifyServiceBuilder::max_watches` and this watcher is the least recently active one.
+    Evicted,
+
+    /// Another watcher sharing the kernel watch of this watcher has stopped watching.
+    ///
+    /// This is produced only if `WatchOptions::notify_subscribers` is enabled.
+    SubscriberLeft {
+        /// The number of the watchers still sharing the kernel watch (including this one).
+        ///
+        /// `1` means that the kernel watch will be removed when this watcher is dropped.
+        remaining: usize,
+    },
+
+    /// A file/directory has been changed in any way (i.e., `MODIFY`, `ATTRIB` or `CLOSE_WRITE`).
+    ///
+    /// This is produced only by `WatcherExt::touched`.
+    Touched {
+        /// The path of the file/directory.
+        path: PathBuf,
+    },
+}
+impl WatcherEvent {
+    /// Decodes the event into a structured report.
+    ///
+    /// Returns `None` if the event is not `WatcherEvent::Notified`.
+    pub fn decode(&self) -> Option<DecodedEvent> {
+        if let WatcherEvent::Notified(ref e) = *self {
+            Some(e.decode())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use event_channel;
+    use test_util::{event, TempDir, TestExecutor};
+    use {ErrorKind, OverflowPolicy};
+
+    #[test]
+    fn redirect_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, mut old) = executor.next_event(watcher);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        executor.run_for(Duration::from_millis(50)); // Delivers the event to `old`
+        let new = old.redirect();
+        fs::write(dir.join("bar"), b"").unwrap();
+
+        let events = executor.wait(old.collect()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(is_notified(&events[0], EventMask::CREATE));
+
+        let (event, new) = executor.next_event(new);
+        if let WatcherEvent::Notified(ref e) = event {
+            assert_eq!(e.name, Some(PathBuf::from("bar")));
+        } else {
+            panic!("Unexpected event: {:?}", event);
+        }
+
+        // Dropping `new` stops watching
+        let id = new.id();
+        std::mem::drop(new);
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert!(stats.watchers.iter().all(|w| w.id != id));
+    }
+
+    #[test]
+    fn drop_outside_fiber_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, watcher) = executor.next_event(watcher);
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert_eq!(stats.watchers.len(), 1);
+
+        thread::spawn(move || std::mem::drop(watcher))
+            .join()
+            .unwrap();
+        let stats = executor.wait(executor.handle.stats()).unwrap();
+        assert!(stats.watchers.is_empty());
+        assert!(executor.is_service_alive());
+    }
+
+    #[test]
+    fn poll_drain_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let (_, mut watcher) = executor.next_event(watcher);
+
+        fs::write(dir.join("foo"), b"").unwrap();
+        fs::write(dir.join("bar"), b"").unwrap();
+        executor.run_for(Duration::from_millis(10));
+
+        let events = watcher.poll_drain().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| is_notified(e, EventMask::CREATE)));
+        assert!(watcher.poll_drain().unwrap().is_empty());
+    }
+
+    #[test]
+    fn poll_drain_keeps_events_taken_before_error() {
+        let executor = TestExecutor::new();
+        let (event_tx, event_rx) = event_channel::channel(None, OverflowPolicy::default());
+        let mut watcher = Watcher::new(
+            0,
+            executor.handle.clone(),
+            PathBuf::from("/tmp"),
+            WatchMask::CREATE,
+            WatchOptions::default(),
+            event_rx,
+        );
+        let notified = || WatcherEvent::Notified(event(EventMask::CREATE, Some("foo"), "/tmp"));
+        event_tx.send(Ok(WatcherEvent::StartWatching)).unwrap();
+        event_tx.send(Ok(notified())).unwrap();
+        event_tx.send(Err(ErrorKind::Other.into())).unwrap();
+        event_tx.send(Ok(notified())).unwrap();
+
+        let events = watcher.poll_drain().unwrap();
+        assert_eq!(events, [WatcherEvent::StartWatching, notified()]);
+        assert!(watcher.poll_drain().is_err());
+        assert_eq!(watcher.poll_drain().unwrap(), [notified()]);
+        watcher.detach();
+    }
+
+    #[test]
+    fn rewatch_works() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("a")).unwrap();
+        fs::create_dir(dir.join("b")).unwrap();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor.handle.watch(dir.join("a"), WatchMask::CREATE);
+        let (_, watcher) = executor.next_event(watcher);
+
+        // Fails
+        let e = executor
+            .wait(watcher.rewatch(dir.join("nonexistent")))
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        fs::write(dir.join("a/foo"), b"").unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert!(is_notified(&event, EventMask::CREATE));
+
+        // Succeeds
+        executor.wait(watcher.rewatch(dir.join("b"))).unwrap();
+        let (event, watcher) = executor.next_event(watcher);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+
+        fs::write(dir.join("a/bar"), b"").unwrap();
+        fs::write(dir.join("b/baz"), b"").unwrap();
+        let (event, _watcher) = executor.next_event(watcher);
+        if let WatcherEvent::Notified(e) = event {
+            assert_eq!(e.path(), dir.join("b/baz"));
+        } else {
+            panic!("Unexpected event: {:?}", event);
+        }
+    }
+
+    #[test]
+    fn tagged_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let watcher0 = executor.handle.watch(dir.path(), WatchMask::CREATE);
+        let watcher1 = executor.handle.watch(dir.path(), WatchMask::DELETE);
+        assert_ne!(watcher0.id(), watcher1.id());
+        let ids = [watcher0.id(), watcher1.id()];
+
+        let merged = watcher0.tagged().select(watcher1.tagged());
+        let (item, merged) = executor
+            .wait(merged.into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        let (id, event) = item.unwrap();
+        assert!(ids.contains(&id));
+        assert_eq!(event, WatcherEvent::StartWatching);
+        let (item, _) = executor
+            .wait(merged.into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert!(ids.contains(&item.unwrap().0));
+    }
+
+    #[test]
+    fn current_mask_works() {
+        let dir = TempDir::new();
+        let mut executor = TestExecutor::new();
+
+        let mask = WatchMask::CREATE | WatchMask::DELETE;
+        let watcher = executor.handle.watch(dir.path(), mask);
+        let (_, watcher) = executor.next_event(watcher);
+        assert_eq!(executor.wait(watcher.current_mask()).unwrap(), mask);
+
+        let watcher = executor.handle.watch(dir.join("nonexistent"), mask);
+        let e = executor.wait(watcher.current_mask()).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+    }
+
+    fn is_notified(event: &WatcherEvent, mask: EventMask) -> bool {
+        if let WatcherEvent::Notified(ref e) = *event {
+            e.mask.contains(mask)
+        } else {
+            false
+        }
+    }
 }
@@ -0,0 +1,1062 @@
+use fibers::sync::mpsc;
+use futures::{Async, Future, Poll, Stream};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use {
+    Clock, Error, EventMask, FibersClock, InodeId, InotifyEvent, InotifyServiceHandle, IntoFsOps,
+    WatchMask, WatchOptions, Watcher, WatcherEvent,
+};
+
+const TOUCH_MASK: EventMask = EventMask::MODIFY
+    .union(EventMask::ATTRIB)
+    .union(EventMask::CLOSE_WRITE);
+const UNTOUCH_MASK: EventMask = EventMask::DELETE
+    .union(EventMask::DELETE_SELF)
+    .union(EventMask::MOVED_FROM);
+
+/// An extension of streams which produce `WatcherEvent`s (e.g., `Watcher`).
+pub trait WatcherExt: Stream<Item = WatcherEvent, Error = Error> + Sized {
+    /// Makes a stream that rewrites the paths of the events to be relative to `root`.
+    ///
+    /// `InotifyEvent::watch_path` of each event is replaced with the one relative to `root`,
+    /// thus `InotifyEvent::path` returns the relative path of the file/directory.
+    /// If the event refers to `root` itself (e.g., `EventMask::DELETE_SELF` on `root`),
+    /// the resulting path will be empty.
+    ///
+    /// The events for paths outside of `root` are left untouched.
+    fn relative_to<P: AsRef<Path>>(self, root: P) -> RelativeTo<Self> {
+        RelativeTo {
+            inner: self,
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Makes a stream that drops the events whose paths do not satisfy `predicate`.
+    ///
+    /// `predicate` is applied to `InotifyEvent::path` of each inotify event
+    /// (including the ones in `WatcherEvent::Batch`, which is dropped if it becomes empty).
+    /// The events without names (i.e., the ones referring to the watched path itself)
+    /// and the events other than inotify ones (e.g., `WatcherEvent::StartWatching`)
+    /// are passed through.
+    ///
+    /// This can be combined with `relative_to` to match relative paths.
+    fn filter_path<F>(self, predicate: F) -> FilterPath<Self, F>
+    where
+        F: FnMut(&Path) -> bool,
+    {
+        FilterPath {
+            inner: self,
+            predicate,
+        }
+    }
+
+    /// Makes a stream that demultiplexes the events into per-path sub-streams.
+    ///
+    /// When an event for a new path (i.e., `InotifyEvent::path`) arrives,
+    /// the resulting stream produces a pair of the path and a sub-stream
+    /// which receives the events for the path.
+    /// Events other than `WatcherEvent::Notified` are discarded.
+    ///
+    /// A sub-stream terminates after receiving an event which indicates that the path has gone
+    /// (i.e., `DELETE`, `DELETE_SELF`, `MOVED_FROM` or `IGNORED`), or when the resulting stream
+    /// is dropped or terminates.
+    /// If an event for the same path arrives after that, a new sub-stream will be produced.
+    /// The same applies to sub-streams dropped by the consumer.
+    ///
+    /// Note that the sub-streams receive events only while the resulting stream is polled.
+    fn by_path(self) -> ByPath<Self> {
+        ByPath {
+            inner: self,
+            sub_streams: HashMap::new(),
+        }
+    }
+
+    /// Splits the events into labeled sub-streams by mask.
+    ///
+    /// Each sub-stream in the resulting map receives the inotify events intersecting the mask
+    /// associated with its label. An event may be delivered to multiple sub-streams.
+    /// This allows routing the events of a single kernel watch to multiple consumers
+    /// (e.g., `CREATE` events to one and `DELETE` events to another).
+    ///
+    /// The resulting `SplitByMask` stream produces the rest of the items, i.e.,
+    /// the events matching no label and the events other than inotify ones
+    /// (e.g., `WatcherEvent::StartWatching`). They can be handled as the default route or
+    /// simply be discarded.
+    ///
+    /// Note that the sub-streams receive events only while `SplitByMask` is polled,
+    /// and they terminate when `SplitByMask` terminates or is dropped.
+    fn split_by_mask<L>(
+        self,
+        masks: HashMap<L, EventMask>,
+    ) -> (SplitByMask<Self>, HashMap<L, MaskEvents>)
+    where
+        L: Eq + Hash,
+    {
+        let mut routes = Vec::new();
+        let mut sub_streams = HashMap::new();
+        for (label, mask) in masks {
+            let (tx, rx) = mpsc::channel();
+            routes.push((mask, tx));
+            sub_streams.insert(label, MaskEvents(rx));
+        }
+        let split = SplitByMask {
+            inner: self,
+            routes,
+        };
+        (split, sub_streams)
+    }
+
+    /// Makes a stream that produces `WatcherEvent::Idle` if no event arrives within `duration`.
+    ///
+    /// The timer is reset each time an event arrives (or `WatcherEvent::Idle` is produced),
+    /// thus `WatcherEvent::Idle` is produced periodically while the stream is idle.
+    /// This is useful to detect stuck watches in a watchdog.
+    ///
+    /// Note that the timer works only if the resulting stream is polled in a fiber.
+    fn idle_timeout(self, duration: Duration) -> IdleTimeout<Self> {
+        self.idle_timeout_with_clock(duration, FibersClock)
+    }
+
+    /// Same as `idle_timeout` except that the timers are made by `clock`.
+    fn idle_timeout_with_clock<C: Clock>(
+        self,
+        duration: Duration,
+        clock: C,
+    ) -> IdleTimeout<Self, C> {
+        let timeout = clock.timer(duration);
+        IdleTimeout {
+            inner: self,
+            duration,
+            clock,
+            timeout,
+        }
+    }
+
+    /// Makes a stream that interleaves `HeartbeatItem::Heartbeat` with the events.
+    ///
+    /// A heartbeat is produced each time `interval` has elapsed without events,
+    /// thus the downstream can distinguish "alive but nothing happening" from "dead"
+    /// (e.g., when the events are forwarded over a network connection).
+    /// Unlike `idle_timeout`, the heartbeats are distinguished from the events by the type.
+    ///
+    /// Note that the timer works only if the resulting stream is polled in a fiber.
+    fn with_heartbeat(self, interval: Duration) -> WithHeartbeat<Self> {
+        self.with_heartbeat_with_clock(interval, FibersClock)
+    }
+
+    /// Same as `with_heartbeat` except that the timers are made by `clock`.
+    fn with_heartbeat_with_clock<C: Clock>(
+        self,
+        interval: Duration,
+        clock: C,
+    ) -> WithHeartbeat<Self, C> {
+        let timeout = clock.timer(interval);
+        WithHeartbeat {
+            inner: self,
+            interval,
+            clock,
+            timeout,
+        }
+    }
+
+    /// Makes a stream that detects the replacements of the inodes at the paths of the events.
+    ///
+    /// For each `WatcherEvent::Notified`, the resulting stream calls `lstat(2)` on
+    /// `InotifyEvent::path` and sets `InotifyEvent::inode` (unless it has already been set,
+    /// e.g., by `WatchOptions::stat_inode`).
+    /// If the inode differs from the one seen last time for the same path
+    /// (e.g., the file has been atomically replaced), `WatcherEvent::InodeReplaced` is
+    /// produced prior to the event.
+    ///
+    /// The remembered inode of a path is forgotten when the path no longer exists.
+    /// Other events (including `WatcherEvent::Batch`) are passed through as they are.
+    fn track_inodes(self) -> TrackInodes<Self> {
+        TrackInodes {
+            inner: self,
+            inodes: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    /// Makes a stream that collapses the change-family events into `WatcherEvent::Touched`.
+    ///
+    /// Each `WatcherEvent::Notified` whose mask contains `MODIFY`, `ATTRIB` or `CLOSE_WRITE`
+    /// is converted into `WatcherEvent::Touched`, and the subsequent ones for the same path are
+    /// discarded until `window` has elapsed since the last produced one.
+    /// The other events (e.g., `CREATE`, `DELETE` or renames) are passed through as they are,
+    /// and a removal (or a rename) of a path resets its window.
+    ///
+    /// Note that the timer works only if the resulting stream is polled in a fiber.
+    fn touched(self, window: Duration) -> Touched<Self> {
+        self.touched_with_clock(window, FibersClock)
+    }
+
+    /// Same as `touched` except that the timers are made by `clock`.
+    fn touched_with_clock<C: Clock>(self, window: Duration, clock: C) -> Touched<Self, C> {
+        Touched {
+            inner: self,
+            window,
+            clock,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Makes a stream that keeps watching the path across inode replacements.
+    ///
+    /// If the watched inode is removed (e.g., an editor saves a file by renaming
+    /// a temporary file over it), the watch is dropped by the kernel and
+    /// `EventMask::IGNORED` event is produced.
+    /// In that case, if the path (i.e., `InotifyEvent::watch_path` of the event) still exists,
+    /// the resulting stream re-watches it via `service` with `mask`,
+    /// and produces `WatcherEvent::RestartWatching` once the new watch has been added.
+    /// Otherwise, the stream terminates after the `IGNORED` event as usual.
+    ///
+    /// Note that this should be applied before the adaptors which rewrite the paths
+    /// (e.g., `relative_to`), and `EventMask::IGNORED` in `WatcherEvent::Batch` is not handled.
+    fn follow_path(self, service: InotifyServiceHandle, mask: WatchMask) -> FollowPath<Self> {
+        self.follow_path_with_options(service, mask, WatchOptions::default())
+    }
+
+    /// Same as `follow_path` except that the new watches are added with `options`.
+    fn follow_path_with_options(
+        self,
+        service: InotifyServiceHandle,
+        mask: WatchMask,
+        options: WatchOptions,
+    ) -> FollowPath<Self> {
+        FollowPath {
+            inner: Some(self),
+            rewatched: None,
+            service,
+            mask,
+            options,
+            is_rewatched: false,
+        }
+    }
+
+    /// Makes a stream that converts the events into filesystem operations (see `FsOp`).
+    ///
+    /// The pairs of `MOVED_FROM` and `MOVED_TO` events are correlated by their cookies and
+    /// produced as `FsOp::Rename`. A rename whose counterpart is outside of the watched
+    /// files is produced as `FsOp::Remove` or `FsOp::Create` instead.
+    /// Consecutive `MODIFY` and `CLOSE_WRITE` events for the same file are coalesced into
+    /// a single `FsOp::Modify`.
+    ///
+    /// The control events (e.g., `WatcherEvent::StartWatching`) and the events which
+    /// do not change the filesystem (e.g., `EventMask::OPEN`) are discarded.
+    fn into_fs_ops(self) -> IntoFsOps<Self> {
+        IntoFsOps::new(self)
+    }
+}
+impl<S> WatcherExt for S where S: Stream<Item = WatcherEvent, Error = Error> {}
+
+/// A stream which rewrites the paths of the events to be relative to a root directory.
+///
+/// This is created by calling `WatcherExt::relative_to` method.
+#[derive(Debug)]
+pub struct RelativeTo<S> {
+    inner: S,
+    root: PathBuf,
+}
+impl<S> Stream for RelativeTo<S>
+where
+    S: Stream<Item = WatcherEvent, Error = Error>,
+{
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut polled = track!(self.inner.poll())?;
+        match polled {
+            Async::Ready(Some(WatcherEvent::Notified(ref mut event))) => {
+                make_relative(event, &self.root)
+            }
+            Async::Ready(Some(WatcherEvent::Batch(ref mut events))) => {
+                for event in events {
+                    make_relative(event, &self.root);
+                }
+            }
+            _ => {}
+        }
+        Ok(polled)
+    }
+}
+
+fn make_relative(event: &mut InotifyEvent, root: &Path) {
+    let relative = event
+        .watch_path
+        .strip_prefix(root)
+        .ok()
+        .map(Path::to_path_buf);
+    if let Some(relative) = relative {
+        event.watch_path = relative;
+    }
+}
+
+/// A stream which drops the events whose paths do not satisfy a predicate.
+///
+/// This is created by calling `WatcherExt::filter_path` method.
+pub struct FilterPath<S, F> {
+    inner: S,
+    predicate: F,
+}
+impl<S, F> FilterPath<S, F>
+where
+    F: FnMut(&Path) -> bool,
+{
+    fn is_passed(&mut self, event: &InotifyEvent) -> bool {
+        event.name.is_none() || (self.predicate)(&event.path())
+    }
+}
+impl<S, F> Stream for FilterPath<S, F>
+where
+    S: Stream<Item = WatcherEvent, Error = Error>,
+    F: FnMut(&Path) -> bool,
+{
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match track!(self.inner.poll())? {
+                Async::Ready(Some(WatcherEvent::Notified(event))) => {
+                    if self.is_passed(&event) {
+                        return Ok(Async::Ready(Some(WatcherEvent::Notified(event))));
+                    }
+                }
+                Async::Ready(Some(WatcherEvent::Batch(mut events))) => {
+                    events.retain(|e| self.is_passed(e));
+                    if !events.is_empty() {
+                        return Ok(Async::Ready(Some(WatcherEvent::Batch(events))));
+                    }
+                }
+                polled => return Ok(polled),
+            }
+        }
+    }
+}
+impl<S: fmt::Debug, F> fmt::Debug for FilterPath<S, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FilterPath {{ inner: {:?}, predicate: _ }}", self.inner)
+    }
+}
+
+/// A stream which demultiplexes the events into per-path sub-streams.
+///
+/// This is created by calling `WatcherExt::by_path` method.
+#[derive(Debug)]
+pub struct ByPath<S> {
+    inner: S,
+    sub_streams: HashMap<PathBuf, mpsc::Sender<InotifyEvent>>,
+}
+impl<S> Stream for ByPath<S>
+where
+    S: Stream<Item = WatcherEvent, Error = Error>,
+{
+    type Item = (PathBuf, PathEvents);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let event = match track!(self.inner.poll())? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::Ready(Some(WatcherEvent::Notified(event))) => event,
+                Async::Ready(Some(_)) => continue,
+            };
+
+            let path = event.path();
+            let is_last = event.mask.intersects(
+                EventMask::DELETE
+                    | EventMask::DELETE_SELF
+                    | EventMask::MOVED_FROM
+                    | EventMask::IGNORED,
+            );
+            let event = if let Some(tx) = self.sub_streams.get(&path) {
+                match tx.send(event) {
+                    Ok(()) => None,
+                    Err(e) => Some(e.0),
+                }
+            } else {
+                Some(event)
+            };
+            if let Some(event) = event {
+                // Removes the entries of the sub-streams dropped by the consumer
+                self.sub_streams.retain(|_, tx| !tx.is_disconnected());
+
+                let (tx, rx) = mpsc::channel();
+                let _ = tx.send(event);
+                if !is_last {
+                    self.sub_streams.insert(path.clone(), tx);
+                }
+                return Ok(Async::Ready(Some((path, PathEvents(rx)))));
+            } else if is_last {
+                self.sub_streams.remove(&path);
+            }
+        }
+    }
+}
+
+/// A stream which routes the events to the sub-streams by mask.
+///
+/// This is created by calling `WatcherExt::split_by_mask` method,
+/// and produces the events routed to no sub-stream.
+#[derive(Debug)]
+pub struct SplitByMask<S> {
+    inner: S,
+    routes: Vec<(EventMask, mpsc::Sender<InotifyEvent>)>,
+}
+impl<S> SplitByMask<S> {
+    fn route(&self, event: InotifyEvent) -> Option<InotifyEvent> {
+        let mut is_routed = false;
+        for &(mask, ref tx) in &self.routes {
+            if event.mask.intersects(mask) {
+                // The sub-streams dropped by the consumer are ignored
+                let _ = tx.send(event.clone());
+                is_routed = true;
+            }
+        }
+        if is_routed {
+            None
+        } else {
+            Some(event)
+        }
+    }
+}
+impl<S> Stream for SplitByMask<S>
+where
+    S: Stream<Item = WatcherEvent, Error = Error>,
+{
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match track!(self.inner.poll())? {
+                Async::Ready(Some(WatcherEvent::Notified(event))) => {
+                    if let Some(event) = self.route(event) {
+                        return Ok(Async::Ready(Some(WatcherEvent::Notified(event))));
+                    }
+                }
+                Async::Ready(Some(WatcherEvent::Batch(events))) => {
+                    let events = events
+                        .into_iter()
+                        .filter_map(|e| self.route(e))
+                        .collect::<Vec<_>>();
+                    if !events.is_empty() {
+                        return Ok(Async::Ready(Some(WatcherEvent::Batch(events))));
+                    }
+                }
+                polled => return Ok(polled),
+            }
+        }
+    }
+}
+
+/// A stream which re-watches the path when the watched inode is replaced.
+///
+/// This is created by calling `WatcherExt::follow_path` method.
+#[derive(Debug)]
+pub struct FollowPath<S> {
+    inner: Option<S>,
+    rewatched: Option<Watcher>,
+    service: InotifyServiceHandle,
+    mask: WatchMask,
+    options: WatchOptions,
+    is_rewatched: bool,
+}
+impl<S> Stream for FollowPath<S>
+where
+    S: Stream<Item = WatcherEvent, Error = Error>,
+{
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let polled = if let Some(ref mut watcher) = self.rewatched {
+            track!(watcher.poll())?
+        } else if let Some(ref mut inner) = self.inner {
+            track!(inner.poll())?
+        } else {
+            Async::Ready(None)
+        };
+        match polled {
+            Async::Ready(Some(WatcherEvent::StartWatching)) if self.is_rewatched => {
+                self.is_rewatched = false;
+                Ok(Async::Ready(Some(WatcherEvent::RestartWatching)))
+            }
+            Async::Ready(Some(WatcherEvent::Notified(ref e)))
+                if e.mask.contains(EventMask::IGNORED) && fs::metadata(&e.watch_path).is_ok() =>
+            {
+                // The old stream (or watcher) has been terminated by the `IGNORED` event
+                self.inner = None;
+                self.rewatched = Some(self.service.watch_with_options(
+                    &e.watch_path,
+                    self.mask,
+                    self.options.clone(),
+                ));
+                self.is_rewatched = true;
+                Ok(polled)
+            }
+            _ => Ok(polled),
+        }
+    }
+}
+
+/// A stream which produces `WatcherEvent::Idle` if no event arrives within a duration.
+///
+/// This is created by calling `WatcherExt::idle_timeout` method.
+#[derive(Debug)]
+pub struct IdleTimeout<S, C: Clock = FibersClock> {
+    inner: S,
+    duration: Duration,
+    clock: C,
+    timeout: C::Timer,
+}
+impl<S, C> Stream for IdleTimeout<S, C>
+where
+    S: Stream<Item = WatcherEvent, Error = Error>,
+    C: Clock,
+{
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let polled = track!(self.inner.poll())?;
+        if polled.is_ready() {
+            self.timeout = self.clock.timer(self.duration);
+            return Ok(polled);
+        }
+        if track!(self.timeout.poll())?.is_ready() {
+            self.timeout = self.clock.timer(self.duration);
+            return Ok(Async::Ready(Some(WatcherEvent::Idle)));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// A stream which detects the replacements of the inodes at the paths of the events.
+///
+/// This is created by calling `WatcherExt::track_inodes` method.
+#[derive(Debug)]
+pub struct TrackInodes<S> {
+    inner: S,
+    inodes: HashMap<PathBuf, InodeId>,
+    pending: Option<WatcherEvent>,
+}
+impl<S> Stream for TrackInodes<S>
+where
+    S: Stream<Item = WatcherEvent, Error = Error>,
+{
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(event) = self.pending.take() {
+            return Ok(Async::Ready(Some(event)));
+        }
+        let mut event = match track!(self.inner.poll())? {
+            Async::Ready(Some(WatcherEvent::Notified(event))) => event,
+            polled => return Ok(polled),
+        };
+
+        let path = event.path();
+        if event.inode.is_none() {
+            event.inode = InodeId::from_path(&path).ok();
+        }
+        let old = if let Some(inode) = event.inode {
+            self.inodes.insert(path.clone(), inode)
+        } else {
+            self.inodes.remove(&path);
+            None
+        };
+        match (old, event.inode) {
+            (Some(old), Some(new)) if old != new => {
+                self.pending = Some(WatcherEvent::Notified(event));
+                let replaced = WatcherEvent::InodeReplaced { path, old, new };
+                Ok(Async::Ready(Some(replaced)))
+            }
+            _ => Ok(Async::Ready(Some(WatcherEvent::Notified(event)))),
+        }
+    }
+}
+
+/// A stream which collapses the change-family events into `WatcherEvent::Touched`.
+///
+/// This is created by calling `WatcherExt::touched` method.
+#[derive(Debug)]
+pub struct Touched<S, C: Clock = FibersClock> {
+    inner: S,
+    window: Duration,
+    clock: C,
+    windows: HashMap<PathBuf, C::Timer>,
+}
+impl<S, C> Stream for Touched<S, C>
+where
+    S: Stream<Item = WatcherEvent, Error = Error>,
+    C: Clock,
+{
+    type Item = WatcherEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let event = match track!(self.inner.poll())? {
+                Async::Ready(Some(WatcherEvent::Notified(event))) => event,
+                polled => return Ok(polled),
+            };
+            let path = event.path();
+            if !event.mask.intersects(TOUCH_MASK) {
+                if event.mask.intersects(UNTOUCH_MASK) {
+                    self.windows.remove(&path);
+                }
+                return Ok(Async::Ready(Some(WatcherEvent::Notified(event))));
+            }
+
+            let mut expired = Vec::new();
+            for (path, timer) in &mut self.windows {
+                if track!(timer.poll())?.is_ready() {
+                    expired.push(path.clone());
+                }
+            }
+            for path in expired {
+                self.windows.remove(&path);
+            }
+            if !self.windows.contains_key(&path) {
+                let timer = self.clock.timer(self.window);
+                self.windows.insert(path.clone(), timer);
+                return Ok(Async::Ready(Some(WatcherEvent::Touched { path })));
+            }
+        }
+    }
+}
+
+/// An item produced by `WithHeartbeat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeartbeatItem {
+    /// An event produced by the underlying stream.
+    Event(WatcherEvent),
+
+    /// No events arrived within the interval.
+    Heartbeat,
+}
+
+/// A stream which produces heartbeats while no events arrive.
+///
+/// This is created by calling `WatcherExt::with_heartbeat` method.
+#[derive(Debug)]
+pub struct WithHeartbeat<S, C: Clock = FibersClock> {
+    inner: S,
+    interval: Duration,
+    clock: C,
+    timeout: C::Timer,
+}
+impl<S, C> Stream for WithHeartbeat<S, C>
+where
+    S: Stream<Item = WatcherEvent, Error = Error>,
+    C: Clock,
+{
+    type Item = HeartbeatItem;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Async::Ready(item) = track!(self.inner.poll())? {
+            self.timeout = self.clock.timer(self.interval);
+            return Ok(Async::Ready(item.map(HeartbeatItem::Event)));
+        }
+        if track!(self.timeout.poll())?.is_ready() {
+            self.timeout = self.clock.timer(self.interval);
+            return Ok(Async::Ready(Some(HeartbeatItem::Heartbeat)));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// A sub-stream of `ByPath` that produces the events for a specific path.
+#[derive(Debug)]
+pub struct PathEvents(mpsc::Receiver<InotifyEvent>);
+impl Stream for PathEvents {
+    type Item = InotifyEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        Ok(self.0.poll().expect("Never fails"))
+    }
+}
+
+/// A sub-stream of `SplitByMask` that produces the events matching a mask.
+#[derive(Debug)]
+pub struct MaskEvents(mpsc::Receiver<InotifyEvent>);
+impl Stream for MaskEvents {
+    type Item = InotifyEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        Ok(self.0.poll().expect("Never fails"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{stream, Future};
+    use std::path::{Path, PathBuf};
+
+    use std::fs;
+
+    use super::*;
+    use test_util::{event, FakeClock, TempDir, TestExecutor};
+    use {EventMask, WatchMask};
+
+    #[test]
+    fn follow_path_works() {
+        let dir = TempDir::new();
+        let path = dir.join("foo");
+        fs::write(&path, b"").unwrap();
+
+        let mut executor = TestExecutor::new();
+        // Any stream of `WatcherEvent`s can be followed
+        let watcher = executor
+            .handle
+            .watch(&path, WatchMask::MODIFY)
+            .filter_path(|_| true)
+            .follow_path(executor.handle.clone(), WatchMask::MODIFY);
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert_eq!(event, WatcherEvent::StartWatching);
+
+        // Replaces the file atomically
+        fs::write(dir.join("foo.tmp"), b"").unwrap();
+        fs::rename(dir.join("foo.tmp"), &path).unwrap();
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert!(is_notified(&event, EventMask::IGNORED));
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert_eq!(event, WatcherEvent::RestartWatching);
+
+        fs::write(&path, b"bar").unwrap();
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert!(is_notified(&event, EventMask::MODIFY));
+
+        // Removes the file
+        fs::remove_file(&path).unwrap();
+        let (event, watcher) = next_event(&mut executor, watcher);
+        assert!(is_notified(&event, EventMask::IGNORED));
+        let events = executor.wait(watcher.collect()).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn relative_to_works() {
+        let events = vec![
+            WatcherEvent::StartWatching,
+            WatcherEvent::Notified(event(EventMask::CREATE, Some("main.rs"), "/project/src")),
+            WatcherEvent::Notified(event(EventMask::DELETE_SELF, None, "/project")),
+            WatcherEvent::Notified(event(EventMask::CREATE, Some("foo"), "/tmp")),
+        ];
+        let events = stream::iter_ok(events)
+            .relative_to("/project")
+            .collect()
+            .wait()
+            .unwrap();
+        let paths = events
+            .iter()
+            .filter_map(|e| {
+                if let WatcherEvent::Notified(ref e) = *e {
+                    Some(e.path())
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                Path::new("src/main.rs"),
+                Path::new(""),
+                Path::new("/tmp/foo")
+            ]
+        );
+    }
+
+    #[test]
+    fn filter_path_works() {
+        let events = vec![
+            WatcherEvent::StartWatching,
+            WatcherEvent::Notified(event(EventMask::CREATE, Some("main.rs"), "/project/src")),
+            WatcherEvent::Notified(event(EventMask::CREATE, Some("main.o"), "/project/src")),
+            WatcherEvent::Notified(event(EventMask::DELETE_SELF, None, "/project/src")),
+            WatcherEvent::Batch(vec![event(
+                EventMask::CREATE,
+                Some("lib.o"),
+                "/project/src",
+            )]),
+        ];
+        let events = stream::iter_ok(events)
+            .relative_to("/project")
+            .filter_path(|path| path.extension().is_some_and(|ext| ext == "rs"))
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                WatcherEvent::StartWatching,
+                WatcherEvent::Notified(event(EventMask::CREATE, Some("main.rs"), "src")),
+                WatcherEvent::Notified(event(EventMask::DELETE_SELF, None, "src")),
+            ]
+        );
+    }
+
+    #[test]
+    fn idle_timeout_works() {
+        let mut executor = TestExecutor::new();
+        let events = stream::iter_ok(vec![WatcherEvent::StartWatching])
+            .chain(stream::poll_fn(|| Ok(Async::NotReady)))
+            .idle_timeout(Duration::from_millis(10));
+
+        let (event, events) = executor.wait(events.into_future()).ok().unwrap();
+        assert_eq!(event, Some(WatcherEvent::StartWatching));
+        let (event, events) = executor.wait(events.into_future()).ok().unwrap();
+        assert_eq!(event, Some(WatcherEvent::Idle));
+        let (event, _) = executor.wait(events.into_future()).ok().unwrap();
+        assert_eq!(event, Some(WatcherEvent::Idle));
+    }
+
+    #[test]
+    fn idle_timeout_with_fake_clock_works() {
+        let clock = FakeClock::new();
+        let (tx, rx) = mpsc::channel();
+        let mut events = rx
+            .map_err(|()| unreachable!())
+            .idle_timeout_with_clock(Duration::from_secs(10), clock.clone());
+        assert_eq!(events.poll().unwrap(), Async::NotReady);
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(events.poll().unwrap(), Async::NotReady);
+
+        // An event resets the timer
+        tx.send(WatcherEvent::StartWatching).unwrap();
+        let polled = events.poll().unwrap();
+        assert_eq!(polled, Async::Ready(Some(WatcherEvent::StartWatching)));
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(events.poll().unwrap(), Async::NotReady);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            events.poll().unwrap(),
+            Async::Ready(Some(WatcherEvent::Idle))
+        );
+        assert_eq!(events.poll().unwrap(), Async::NotReady);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(
+            events.poll().unwrap(),
+            Async::Ready(Some(WatcherEvent::Idle))
+        );
+    }
+
+    #[test]
+    fn with_heartbeat_with_fake_clock_works() {
+        let clock = FakeClock::new();
+        let mut events = stream::poll_fn(|| Ok(Async::NotReady))
+            .with_heartbeat_with_clock(Duration::from_secs(1), clock.clone());
+        assert_eq!(events.poll().unwrap(), Async::NotReady);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            events.poll().unwrap(),
+            Async::Ready(Some(HeartbeatItem::Heartbeat))
+        );
+        assert_eq!(events.poll().unwrap(), Async::NotReady);
+    }
+
+    #[test]
+    fn touched_with_fake_clock_works() {
+        let clock = FakeClock::new();
+        let (tx, rx) = mpsc::channel();
+        let mut events = rx
+            .map_err(|_| unreachable!())
+            .touched_with_clock(Duration::from_secs(1), clock.clone());
+        let touched = |name: &str| {
+            Async::Ready(Some(WatcherEvent::Touched {
+                path: Path::new("/tmp").join(name),
+            }))
+        };
+        let send = |mask, name| {
+            let e = event(mask, Some(name), "/tmp");
+            tx.send(WatcherEvent::Notified(e)).unwrap();
+        };
+
+        send(EventMask::MODIFY, "foo");
+        send(EventMask::ATTRIB, "foo");
+        send(EventMask::CLOSE_WRITE, "bar");
+        send(EventMask::CLOSE_WRITE, "foo");
+        assert_eq!(events.poll().unwrap(), touched("foo"));
+        assert_eq!(events.poll().unwrap(), touched("bar"));
+        assert_eq!(events.poll().unwrap(), Async::NotReady);
+
+        // The window has elapsed
+        clock.advance(Duration::from_secs(1));
+        send(EventMask::MODIFY, "foo");
+        assert_eq!(events.poll().unwrap(), touched("foo"));
+
+        // Non-change events are passed through, and a removal resets the window
+        send(EventMask::DELETE, "foo");
+        send(EventMask::CREATE, "foo");
+        send(EventMask::MODIFY, "foo");
+        for mask in [EventMask::DELETE, EventMask::CREATE] {
+            match events.poll().unwrap() {
+                Async::Ready(Some(WatcherEvent::Notified(e))) => assert_eq!(e.mask, mask),
+                polled => panic!("Unexpected event: {:?}", polled),
+            }
+        }
+        assert_eq!(events.poll().unwrap(), touched("foo"));
+    }
+
+    #[test]
+    fn with_heartbeat_works() {
+        let mut executor = TestExecutor::new();
+        let events = stream::iter_ok(vec![WatcherEvent::StartWatching])
+            .chain(stream::poll_fn(|| Ok(Async::NotReady)))
+            .with_heartbeat(Duration::from_millis(10));
+
+        let (item, events) = executor.wait(events.into_future()).ok().unwrap();
+        assert_eq!(
+            item,
+            Some(HeartbeatItem::Event(WatcherEvent::StartWatching))
+        );
+        let (item, events) = executor.wait(events.into_future()).ok().unwrap();
+        assert_eq!(item, Some(HeartbeatItem::Heartbeat));
+        let (item, _) = executor.wait(events.into_future()).ok().unwrap();
+        assert_eq!(item, Some(HeartbeatItem::Heartbeat));
+
+        // Terminates with the underlying stream
+        let events = stream::iter_ok::<_, Error>(vec![])
+            .with_heartbeat(Duration::from_millis(10))
+            .collect();
+        assert!(executor.wait(events).unwrap().is_empty());
+    }
+
+    #[test]
+    fn track_inodes_works() {
+        let dir = TempDir::new();
+        fs::write(dir.join("foo"), b"").unwrap();
+        let mut executor = TestExecutor::new();
+
+        let watcher = executor
+            .handle
+            .watch(dir.path(), WatchMask::MODIFY | WatchMask::MOVED_TO)
+            .track_inodes();
+        let (event, watcher) = executor.wait(watcher.into_future()).ok().unwrap();
+        assert_eq!(event, Some(WatcherEvent::StartWatching));
+
+        fs::write(dir.join("foo"), b"foo").unwrap();
+        let (event, watcher) = executor.wait(watcher.into_future()).ok().unwrap();
+        let old = match event {
+            Some(WatcherEvent::Notified(e)) => e.inode.unwrap(),
+            _ => panic!("Unexpected event: {:?}", event),
+        };
+
+        // Atomically replaced
+        fs::write(dir.join("foo.tmp"), b"").unwrap();
+        fs::rename(dir.join("foo.tmp"), dir.join("foo")).unwrap();
+        let (event, watcher) = executor.wait(watcher.into_future()).ok().unwrap();
+        let new = InodeId::from_path(dir.join("foo")).unwrap();
+        let path = dir.join("foo");
+        assert_eq!(event, Some(WatcherEvent::InodeReplaced { path, old, new }));
+        let (event, _) = executor.wait(watcher.into_future()).ok().unwrap();
+        match event {
+            Some(WatcherEvent::Notified(e)) => {
+                assert_eq!(e.mask, EventMask::MOVED_TO);
+                assert_eq!(e.inode, Some(new));
+            }
+            _ => panic!("Unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn by_path_works() {
+        let events = vec![
+            WatcherEvent::StartWatching,
+            WatcherEvent::Notified(event(EventMask::CREATE, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::CREATE, Some("bar"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::MODIFY, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::DELETE, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::CREATE, Some("foo"), "/tmp")),
+        ];
+        let sub_streams = stream::iter_ok(events).by_path().collect().wait().unwrap();
+        let sub_streams = sub_streams
+            .into_iter()
+            .map(|(path, events)| {
+                let masks = events.map(|e| e.mask).collect().wait().unwrap();
+                (path, masks)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sub_streams,
+            [
+                (
+                    PathBuf::from("/tmp/foo"),
+                    vec![EventMask::CREATE, EventMask::MODIFY, EventMask::DELETE]
+                ),
+                (PathBuf::from("/tmp/bar"), vec![EventMask::CREATE]),
+                (PathBuf::from("/tmp/foo"), vec![EventMask::CREATE]),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_by_mask_works() {
+        let events = vec![
+            WatcherEvent::StartWatching,
+            WatcherEvent::Notified(event(EventMask::CREATE, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::MODIFY, Some("foo"), "/tmp")),
+            WatcherEvent::Notified(event(EventMask::DELETE, Some("foo"), "/tmp")),
+            WatcherEvent::Batch(vec![
+                event(EventMask::CREATE, Some("bar"), "/tmp"),
+                event(EventMask::ATTRIB, Some("bar"), "/tmp"),
+            ]),
+        ];
+        let mut masks = HashMap::new();
+        masks.insert("created", EventMask::CREATE);
+        masks.insert("changed", EventMask::CREATE | EventMask::DELETE);
+        let (split, mut sub_streams) = stream::iter_ok(events).split_by_mask(masks);
+
+        let rest = split.collect().wait().unwrap();
+        assert_eq!(
+            rest,
+            [
+                WatcherEvent::StartWatching,
+                WatcherEvent::Notified(event(EventMask::MODIFY, Some("foo"), "/tmp")),
+                WatcherEvent::Batch(vec![event(EventMask::ATTRIB, Some("bar"), "/tmp")]),
+            ]
+        );
+
+        let mut collect = |label| {
+            let events = sub_streams.remove(label).unwrap();
+            events.map(|e| e.path()).collect().wait().unwrap()
+        };
+        assert_eq!(
+            collect("created"),
+            [PathBuf::from("/tmp/foo"), PathBuf::from("/tmp/bar")]
+        );
+        assert_eq!(
+            collect("changed"),
+            [
+                PathBuf::from("/tmp/foo"),
+                PathBuf::from("/tmp/foo"),
+                PathBuf::from("/tmp/bar")
+            ]
+        );
+    }
+
+    fn next_event<S>(executor: &mut TestExecutor, stream: S) -> (WatcherEvent, S)
+    where
+        S: Stream<Item = WatcherEvent, Error = Error>,
+    {
+        let (event, stream) = executor
+            .wait(stream.into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        (event.expect("Unexpected end of stream"), stream)
+    }
+
+    fn is_notified(event: &WatcherEvent, mask: EventMask) -> bool {
+        if let WatcherEvent::Notified(ref e) = *event {
+            e.mask.contains(mask)
+        } else {
+            false
+        }
+    }
+}